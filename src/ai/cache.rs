@@ -0,0 +1,149 @@
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use sha2::{Digest, Sha256};
+
+use super::{AiRequest, AiResult, Speaker, TokenUsage};
+
+/// Hash of everything a reply depends on: model, parameters, system prompt and the turns sent.
+pub type CacheKey = [u8; 32];
+
+/// Replies to recently asked requests, so asking the same thing again in the same context doesn't
+/// cost another call. Entries expire after the TTL; once the cache is full the oldest entry makes
+/// room for the new one.
+#[derive(Clone)]
+pub struct ResponseCache {
+    entries: Arc<DashMap<CacheKey, (AiResult, Instant)>>,
+    ttl: Duration,
+    capacity: usize,
+}
+
+impl ResponseCache {
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self { entries: Arc::new(DashMap::new()), ttl, capacity }
+    }
+
+    /// Whitespace in the prompt is collapsed, so a stray space or newline still hits the cache.
+    pub fn key(request: &AiRequest) -> CacheKey {
+        let mut hasher = Sha256::new();
+        // Every part is length-prefixed so neighbouring fields can't run into each other
+        let mut field = |bytes: &[u8]| {
+            hasher.update((bytes.len() as u64).to_be_bytes());
+            hasher.update(bytes);
+        };
+
+        field(request.model.as_bytes());
+        field(serde_json::to_string(&request.params).unwrap_or_default().as_bytes());
+        field(request.system.as_deref().unwrap_or_default().as_bytes());
+        let last = request.turns.len().saturating_sub(1);
+        for (i, turn) in request.turns.iter().enumerate() {
+            field(match turn.speaker {
+                Speaker::User => b"user",
+                Speaker::Model => b"model",
+            });
+            if i == last {
+                field(turn.text.split_whitespace().collect::<Vec<_>>().join(" ").as_bytes());
+            } else {
+                field(turn.text.as_bytes());
+            }
+        }
+        for image in &request.images {
+            field(image.as_bytes());
+        }
+
+        hasher.finalize().into()
+    }
+
+    /// The stored reply, marked as cached. It reports no tokens charged since no call was made.
+    pub fn get(&self, key: &CacheKey) -> Option<AiResult> {
+        let entry = self.entries.get(key)?;
+        let (result, stored_at) = entry.value();
+        if stored_at.elapsed() >= self.ttl {
+            drop(entry);
+            self.entries.remove(key);
+            return None;
+        }
+
+        Some(AiResult {
+            usage: result.usage.map(|usage| TokenUsage { total_tokens: 0, ..usage }),
+            cached: true,
+            ..result.clone()
+        })
+    }
+
+    pub fn insert(&self, key: CacheKey, result: AiResult) {
+        if result.text.is_empty() || self.capacity == 0 {
+            return;
+        }
+
+        if self.entries.len() >= self.capacity {
+            self.entries.retain(|_, (_, stored_at)| stored_at.elapsed() < self.ttl);
+        }
+        if self.entries.len() >= self.capacity {
+            let oldest = self
+                .entries
+                .iter()
+                .min_by_key(|entry| entry.value().1)
+                .map(|entry| *entry.key());
+            if let Some(oldest) = oldest {
+                self.entries.remove(&oldest);
+            }
+        }
+
+        self.entries.insert(key, (result, Instant::now()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{ai::Turn, models::ai::GenerationParams};
+
+    fn request(prompt: &str) -> AiRequest {
+        AiRequest {
+            model: "gemini-2.0-flash".to_string(),
+            system: None,
+            turns: vec![Turn::user("Hi"), Turn::model("Hello!"), Turn::user(prompt)],
+            params: GenerationParams::default(),
+            images: Vec::new(),
+        }
+    }
+
+    fn reply(text: &str) -> AiResult {
+        AiResult {
+            text: text.to_string(),
+            usage: Some(TokenUsage { response_tokens: 4, total_tokens: 12 }),
+            ..AiResult::default()
+        }
+    }
+
+    #[test]
+    fn same_request_hits_and_reports_no_tokens_charged() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 10);
+        cache.insert(ResponseCache::key(&request("What is Rust?")), reply("A language."));
+
+        let hit = cache.get(&ResponseCache::key(&request("  What is\n Rust? "))).unwrap();
+        assert_eq!(hit.text, "A language.");
+        assert!(hit.cached);
+        assert_eq!(hit.usage, Some(TokenUsage { response_tokens: 4, total_tokens: 0 }));
+
+        let mut other_model = request("What is Rust?");
+        other_model.model = "gemini-1.5-pro".to_string();
+        assert!(cache.get(&ResponseCache::key(&other_model)).is_none());
+        assert!(cache.get(&ResponseCache::key(&request("What is Go?"))).is_none());
+    }
+
+    #[test]
+    fn full_cache_drops_its_oldest_entry() {
+        let cache = ResponseCache::new(Duration::from_secs(60), 2);
+        for prompt in ["one", "two", "three"] {
+            cache.insert(ResponseCache::key(&request(prompt)), reply(prompt));
+        }
+
+        assert!(cache.get(&ResponseCache::key(&request("one"))).is_none());
+        assert!(cache.get(&ResponseCache::key(&request("three"))).is_some());
+    }
+}
@@ -0,0 +1,146 @@
+use futures::{StreamExt, future::BoxFuture};
+use gemini_rust::{Error, Gemini, GenerationConfig, GenerationResponse, Message};
+use secrecy::{ExposeSecret, SecretString};
+
+use super::{AiProvider, AiRequest, AiResult, AiStream, ModelInfo, ProviderError, Speaker, TokenUsage};
+use crate::{errors::api_errors::AiErrorWrapper, models::ai::GenerationParams};
+
+/// Google's Gemini API (`AI_PROVIDER=gemini`, keyed by `GEMINI_API_KEY`).
+pub struct GeminiProvider {
+    api_key: SecretString,
+}
+
+impl GeminiProvider {
+    pub fn new(api_key: SecretString) -> Self {
+        Self { api_key }
+    }
+
+    /// The client for the requested model and the turns in Gemini's shape.
+    fn prepare(&self, request: &AiRequest) -> (Gemini, Vec<Message>) {
+        let client = Gemini::with_model(
+            self.api_key.expose_secret().to_string(),
+            format!("models/{}", request.model),
+        );
+
+        let turns = request
+            .turns
+            .iter()
+            .map(|turn| match turn.speaker {
+                Speaker::User => Message::user(turn.text.as_str()),
+                Speaker::Model => Message::model(turn.text.as_str()),
+            })
+            .collect();
+
+        (client, turns)
+    }
+}
+
+impl AiProvider for GeminiProvider {
+    fn name(&self) -> &'static str {
+        "gemini"
+    }
+
+    // gemini-rust only builds text parts, so images can't be sent to any of them yet
+    fn model_info(&self, model: &str) -> Option<ModelInfo> {
+        model.starts_with("gemini-").then_some(ModelInfo {
+            streaming: true,
+            vision: false,
+        })
+    }
+
+    fn generate<'a>(&'a self, request: &'a AiRequest) -> BoxFuture<'a, Result<AiResult, ProviderError>> {
+        Box::pin(async move {
+            let (client, turns) = self.prepare(request);
+            let mut builder = client.generate_content().with_messages(turns);
+            if let Some(config) = generation_config(request.params) {
+                builder = builder.with_generation_config(config);
+            }
+            if let Some(system) = &request.system {
+                builder = builder.with_system_instruction(system);
+            }
+
+            let response = builder.execute().await.map_err(provider_error)?;
+            Ok(to_result(&response))
+        })
+    }
+
+    fn generate_stream<'a>(&'a self, request: &'a AiRequest) -> BoxFuture<'a, Result<AiStream, ProviderError>> {
+        Box::pin(async move {
+            let (client, turns) = self.prepare(request);
+            let mut builder = client.generate_content().with_messages(turns);
+            if let Some(config) = generation_config(request.params) {
+                builder = builder.with_generation_config(config);
+            }
+            if let Some(system) = &request.system {
+                builder = builder.with_system_instruction(system);
+            }
+
+            let stream = builder.execute_stream().await.map_err(provider_error)?;
+            let stream = stream.map(|chunk| chunk.map(|chunk| to_result(&chunk)).map_err(provider_error));
+            Ok(Box::pin(stream) as AiStream)
+        })
+    }
+}
+
+// Built field by field: the crate's own setters fill the unset fields with its defaults,
+// including a 1024 token cap
+fn generation_config(params: GenerationParams) -> Option<GenerationConfig> {
+    if params == GenerationParams::default() {
+        return None;
+    }
+
+    Some(GenerationConfig {
+        temperature: params.temperature,
+        top_p: params.top_p,
+        top_k: None,
+        max_output_tokens: params.max_output_tokens,
+        candidate_count: None,
+        stop_sequences: None,
+        response_mime_type: None,
+        response_schema: None,
+    })
+}
+
+fn to_result(response: &GenerationResponse) -> AiResult {
+    AiResult {
+        text: response.text(),
+        usage: response.usage_metadata.as_ref().map(|usage| TokenUsage {
+            response_tokens: i64::from(usage.candidates_token_count),
+            total_tokens: i64::from(usage.total_token_count),
+        }),
+        finish_reason: response.candidates.first().and_then(|c| c.finish_reason.clone()),
+        cached: false,
+    }
+}
+
+fn provider_error(e: Error) -> ProviderError {
+    let transient = match &e {
+        Error::ApiError { status_code, .. } => matches!(status_code, 429 | 500 | 503),
+        Error::HttpError(_) => true,
+        _ => false,
+    };
+
+    ProviderError {
+        error: AiErrorWrapper::from(e),
+        transient,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generation_config_only_carries_what_was_set() {
+        assert!(generation_config(GenerationParams::default()).is_none());
+
+        let config = generation_config(GenerationParams {
+            max_output_tokens: Some(64),
+            ..Default::default()
+        })
+        .unwrap();
+        assert_eq!(config.max_output_tokens, Some(64));
+        assert_eq!(config.temperature, None);
+        assert_eq!(config.top_k, None);
+    }
+}
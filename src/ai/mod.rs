@@ -0,0 +1,101 @@
+pub mod cache;
+pub mod gemini;
+
+use std::pin::Pin;
+
+use futures::{Stream, future::BoxFuture};
+
+use crate::{errors::api_errors::AiErrorWrapper, models::ai::GenerationParams};
+
+/// Who a turn of the conversation came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Speaker {
+    User,
+    Model,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Turn {
+    pub speaker: Speaker,
+    pub text: String,
+}
+
+impl Turn {
+    pub fn user(text: impl Into<String>) -> Self {
+        Self { speaker: Speaker::User, text: text.into() }
+    }
+
+    pub fn model(text: impl Into<String>) -> Self {
+        Self { speaker: Speaker::Model, text: text.into() }
+    }
+}
+
+/// Everything a provider is asked one reply with.
+#[derive(Clone, Debug)]
+pub struct AiRequest {
+    pub model: String,
+    pub system: Option<String>,
+    pub turns: Vec<Turn>,
+    pub params: GenerationParams,
+    /// Images sent with the last user turn, as `data:` or `https:` URLs; only ever set for models
+    /// whose `ModelInfo` has `vision`
+    pub images: Vec<String>,
+}
+
+/// What a model served by a provider can be asked to do.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ModelInfo {
+    /// Replies can be streamed in chunks
+    pub streaming: bool,
+    /// Prompts may carry images
+    pub vision: bool,
+}
+
+/// A whole reply, or one chunk of a streamed one.
+#[derive(Clone, Debug, Default)]
+pub struct AiResult {
+    pub text: String,
+    /// Only some chunks of a stream carry it, usually the last
+    pub usage: Option<TokenUsage>,
+    /// Why the model stopped, as the provider words it (e.g. `STOP`, `MAX_TOKENS`)
+    pub finish_reason: Option<String>,
+    /// Served from the response cache instead of asking the provider
+    pub cached: bool,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TokenUsage {
+    pub response_tokens: i64,
+    /// Prompt and reply together
+    pub total_tokens: i64,
+}
+
+/// A failed call. Transient ones (rate limits, outages, network trouble) are worth retrying.
+#[derive(Debug)]
+pub struct ProviderError {
+    pub error: AiErrorWrapper,
+    pub transient: bool,
+}
+
+impl From<ProviderError> for AiErrorWrapper {
+    fn from(e: ProviderError) -> Self {
+        e.error
+    }
+}
+
+pub type AiStream = Pin<Box<dyn Stream<Item = Result<AiResult, ProviderError>> + Send>>;
+
+/// A model API replies can be generated with. Deadlines and retries are applied by the caller,
+/// so implementations make exactly one attempt per call.
+pub trait AiProvider: Send + Sync {
+    /// The `AI_PROVIDER` name this provider is selected by.
+    fn name(&self) -> &'static str;
+
+    /// What `model` supports, or `None` when this provider doesn't serve it.
+    fn model_info(&self, model: &str) -> Option<ModelInfo>;
+
+    fn generate<'a>(&'a self, request: &'a AiRequest) -> BoxFuture<'a, Result<AiResult, ProviderError>>;
+
+    /// Opens a reply that arrives in chunks; errors after the stream is open come through it.
+    fn generate_stream<'a>(&'a self, request: &'a AiRequest) -> BoxFuture<'a, Result<AiStream, ProviderError>>;
+}
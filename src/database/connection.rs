@@ -1,110 +1,69 @@
+use std::{collections::HashMap, str::FromStr, time::Duration};
+
 use axum::Json;
 use chrono::Utc;
 use serde::Serialize;
-use sqlx::{Executor, Pool, Sqlite, sqlite};
+use sqlx::{Acquire, Pool, QueryBuilder, Sqlite, SqliteConnection, SqliteExecutor, sqlite::{self, SqlitePoolOptions}};
+
+use super::migrations::run_migrations;
 
-use crate::{models::{
-    auth::TokenClaims,
+use crate::models::{
+    ai::{ConvMessage, Conversation, GenerationParams, ReplyMeta, Tag},
+    app::Settings,
+    auth::{SessionDevice, TokenClaims},
     user::{OnSuccessRegister, UserDB},
-}, utils::validation::{ValidationDetail, ValidationError}};
+};
 
 pub async fn add_user(
     name: &str,
     password: &str,
     email: &str,
-    conn: &Pool<Sqlite>,
+    conn: impl SqliteExecutor<'_>,
 ) -> Result<Json<OnSuccessRegister>, sqlx::Error> {
-    let r: Vec<UserDB> = sqlx::query_as("SELECT * FROM users")
-        .fetch_all(conn)
-        .await?;
-    println!("all users: {:?}", r);
-
-    let _res = sqlx::query("INSERT INTO users (name, password, email) VALUES (?, ?, ?)")
-        .bind(name)
-        .bind(password)
-        .bind(email)
-        .execute(conn)
-        .await?;
-
-    let user: UserDB = sqlx::query_as("SELECT * FROM users WHERE name = ?")
-        .bind(name)
-        .fetch_one(conn)
-        .await?;
+    let user: UserDB = sqlx::query_as(
+        "INSERT INTO users (name, password, email, created_at, updated_at) VALUES (?1, ?2, ?3, ?4, ?4) RETURNING *",
+    )
+    .bind(name)
+    .bind(password)
+    .bind(email.to_lowercase())
+    .bind(Utc::now().timestamp())
+    .fetch_one(conn)
+    .await?;
 
     let success = OnSuccessRegister {
         message: "User created succesfully".to_owned(),
         user_id: user.id,
+        verification_token: None,
     };
 
     Ok(Json(success))
 }
 
+/// Opens the shared SQLite pool and brings the schema up to date.
+///
+/// With WAL, readers never block on the writer, so extra pooled connections help concurrent
+/// reads; SQLite still allows only one writer at a time, and writers queue on the busy timeout.
+/// An in-memory database lives per connection, so it needs `DB_MAX_CONNECTIONS=1`.
 #[allow(unused)]
-pub async fn connect_to_database() -> Pool<Sqlite> {
-    let options = sqlite::SqliteConnectOptions::new()
-        .filename("app.db")
-        .create_if_missing(true);
-
-    let connection = sqlx::SqlitePool::connect_with(options).await.unwrap();
+pub async fn connect_to_database(settings: &Settings) -> Pool<Sqlite> {
+    // Set on the options so every pooled connection gets them, not just the first one
+    let options = sqlite::SqliteConnectOptions::from_str(&settings.database_url)
+        .unwrap_or_else(|e| panic!("Invalid DATABASE_URL {:?}: {}", settings.database_url, e))
+        .create_if_missing(true)
+        .foreign_keys(true)
+        .journal_mode(sqlite::SqliteJournalMode::Wal);
 
-    // let _ = sqlx::query("PRAGMA foreign_keys = ON").execute(&connection).await;
-
-    connection
-        .execute(
-            "CREATE TABLE IF NOT EXISTS users (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            email TEXT UNIQUE NOT NULL,
-            name TEXT NOT NULL,
-            password TEXT NOT NULL
-        )",
-        )
+    let connection = SqlitePoolOptions::new()
+        .max_connections(settings.db_max_connections)
+        .acquire_timeout(Duration::from_secs(settings.db_acquire_timeout_secs))
+        .idle_timeout(Duration::from_secs(settings.db_idle_timeout_secs))
+        .connect_with(options)
         .await
-        .expect("Failed to create users table");
+        .unwrap();
 
-    connection
-        .execute(
-            "CREATE TABLE IF NOT EXISTS tokens (
-            id INTEGER PRIMARY KEY AUTOINCREMENT,
-            token TEXT UNIQUE NOT NULL,
-            user_id INTEGER NOT NULL,
-            email TEXT NOT NULL,
-            name TEXT NOT NULL,
-            exp INTEGER NOT NULL,
-            used BOOL NOT NULL,
-            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
-        )",
-        )
+    run_migrations(&connection)
         .await
-        .expect("Failed to create tokens table");
-
-    connection
-        .execute(
-            "CREATE TABLE IF NOT EXISTS conversations (
-    id INTEGER PRIMARY KEY AUTOINCREMENT,
-    user_id INTEGER NOT NULL,
-    title TEXT,
-    created_at INTEGER NOT NULL,
-    updated_at INTEGER NOT NULL,
-    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
-)",
-        )
-        .await
-        .expect("Failed to create conversations table");
-
-    connection
-        .execute(
-            "CREATE TABLE IF NOT EXISTS messages (
-    id INTEGER PRIMARY KEY AUTOINCREMENT,
-    conversation_id INTEGER NOT NULL,
-    role TEXT NOT NULL CHECK (role IN ('user', 'assistant', 'system')),
-    content TEXT NOT NULL,
-    timestamp INTEGER NOT NULL,
-    token_count INTEGER,
-    FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
-)",
-        )
-        .await
-        .expect("Failed to create messages table");
+        .expect("Failed to run database migrations");
 
     connection
 }
@@ -117,55 +76,359 @@ pub struct OnSuccessTokenAdd {
 pub async fn add_token(
     token_claims: &TokenClaims,
     token: &str,
-    conn: &Pool<Sqlite>,
+    family_id: &str,
+    device: &SessionDevice,
+    conn: impl SqliteExecutor<'_>,
 ) -> Result<Json<OnSuccessTokenAdd>, sqlx::Error> {
     let r: Result<sqlite::SqliteQueryResult, sqlx::Error> =
-        sqlx::query("INSERT INTO tokens (token, user_id, email, name, exp, used) VALUES (?1, ?2, ?3, ?4, ?5, ?6)")
-            .bind(&token)
-            .bind(&token_claims.user_id)
+        sqlx::query("INSERT INTO tokens (token, user_id, email, name, exp, used, family_id, sid, ip, user_agent, created_at, jti)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)")
+            .bind(token)
+            .bind(token_claims.user_id)
             .bind(&token_claims.email)
             .bind(&token_claims.name)
-            .bind(&token_claims.exp)
-            .bind(&token_claims.used)
+            .bind(token_claims.exp)
+            .bind(token_claims.used)
+            .bind(family_id)
+            .bind(&token_claims.sid)
+            .bind(&device.ip)
+            .bind(&device.user_agent)
+            .bind(Utc::now().timestamp())
+            .bind(&token_claims.jti)
             .execute(conn)
             .await;
-    if let Err(e) = r {
-        return Err(e);
-    }
+    r?;
     Ok(Json(OnSuccessTokenAdd {
         refresh_token: token.to_string(),
     }))
 }
 
+/// Stores a message and returns the row with its server-assigned id and timestamp.
+/// The conversation's `updated_at` moves along with it, so active chats sort first. Given a
+/// transaction, both writes join it.
 pub async fn insert_chat_message_to_db(
     role: &str,
     conversation_id: i64,
     msg: &str,
-    exec: &Pool<Sqlite>,
-) -> Result<(), String> {
-    let insert = sqlx::query(
-        "INSERT INTO messages (conversation_id, role, content, timestamp, token_count)
-VALUES (?1, ?2, ?3, ?4, 4)",
+    token_count: i64,
+    meta: &ReplyMeta<'_>,
+    exec: impl Acquire<'_, Database = Sqlite>,
+) -> Result<ConvMessage, sqlx::Error> {
+    let now = Utc::now().timestamp();
+    let mut tx = exec.begin().await?;
+
+    let message = sqlx::query_as(
+        "INSERT INTO messages
+    (conversation_id, role, content, timestamp, token_count, model, stopped, latency_ms, finish_reason, cached)
+VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10) RETURNING *",
     )
-    .bind(&conversation_id)
+    .bind(conversation_id)
     .bind(role)
     .bind(msg)
-    .bind(Utc::now().timestamp())
-    .execute(exec)
-    .await;
-
-    if let Err(e) = insert {
-        let stringified = serde_json::to_string(&ValidationError {
-            error: "Database query failed".to_string(),
-            details: vec![ValidationDetail {
-                field: "database".to_string(),
-                messages: vec![format!("adding {} message to database failed: {}", role, e)],
-            }],
-        })
-        .unwrap_or_else(|_| "{\"error\": \"Internal server error\"}".to_string());
-
-        return Err(stringified);
+    .bind(now)
+    .bind(token_count)
+    .bind(meta.model)
+    .bind(meta.stopped)
+    .bind(meta.latency_ms)
+    .bind(meta.finish_reason)
+    .bind(meta.cached)
+    .fetch_one(&mut *tx)
+    .await?;
+
+    sqlx::query("UPDATE conversations SET updated_at = ?1 WHERE id = ?2")
+        .bind(now)
+        .bind(conversation_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(message)
+}
+
+/// Returns the latest `limit` messages of a conversation, oldest first.
+pub async fn get_conversation_history(
+    conversation_id: i64,
+    limit: i64,
+    exec: &Pool<Sqlite>,
+) -> Result<Vec<ConvMessage>, sqlx::Error> {
+    sqlx::query_as(
+        "SELECT * FROM (
+    SELECT * FROM messages WHERE conversation_id = ?1 ORDER BY timestamp DESC, id DESC LIMIT ?2
+) ORDER BY timestamp ASC, id ASC",
+    )
+    .bind(conversation_id)
+    .bind(limit)
+    .fetch_all(exec)
+    .await
+}
+
+/// Counts the user messages of a conversation that sort before `before` (timestamp, id),
+/// or all of them when no bound is given.
+pub async fn count_user_turns_before(
+    conversation_id: i64,
+    before: Option<(i64, i64)>,
+    exec: &Pool<Sqlite>,
+) -> Result<i64, sqlx::Error> {
+    let (timestamp, id) = before.unwrap_or((i64::MAX, i64::MAX));
+
+    sqlx::query_scalar(
+        "SELECT COUNT(*) FROM messages WHERE conversation_id = ?1 AND role = 'user'
+AND (timestamp < ?2 OR (timestamp = ?2 AND id < ?3))",
+    )
+    .bind(conversation_id)
+    .bind(timestamp)
+    .bind(id)
+    .fetch_one(exec)
+    .await
+}
+
+/// The generation defaults saved for a conversation, all unset when none were saved.
+pub async fn get_conversation_settings(
+    conversation_id: i64,
+    exec: impl SqliteExecutor<'_>,
+) -> Result<GenerationParams, sqlx::Error> {
+    let params: Option<GenerationParams> = sqlx::query_as(
+        "SELECT temperature, top_p, max_output_tokens FROM conversation_settings WHERE conversation_id = ?1",
+    )
+    .bind(conversation_id)
+    .fetch_optional(exec)
+    .await?;
+
+    Ok(params.unwrap_or_default())
+}
+
+/// The conversation's own system prompt, if it has one.
+pub async fn get_system_prompt(
+    conversation_id: i64,
+    exec: impl SqliteExecutor<'_>,
+) -> Result<Option<String>, sqlx::Error> {
+    let prompt: Option<Option<String>> =
+        sqlx::query_scalar("SELECT system_prompt FROM conversations WHERE id = ?1")
+            .bind(conversation_id)
+            .fetch_optional(exec)
+            .await?;
+
+    Ok(prompt.flatten())
+}
+
+/// The user's own monthly token budget, `None` when they follow the configured default.
+pub async fn get_token_budget(
+    user_id: i64,
+    exec: impl SqliteExecutor<'_>,
+) -> Result<Option<i64>, sqlx::Error> {
+    let budget: Option<Option<i64>> =
+        sqlx::query_scalar("SELECT monthly_token_budget FROM users WHERE id = ?1")
+            .bind(user_id)
+            .fetch_optional(exec)
+            .await?;
+
+    Ok(budget.flatten())
+}
+
+/// The user's own cap on open conversations, `None` when they follow the configured default.
+pub async fn get_conversation_limit(
+    user_id: i64,
+    exec: impl SqliteExecutor<'_>,
+) -> Result<Option<i64>, sqlx::Error> {
+    let limit: Option<Option<i64>> =
+        sqlx::query_scalar("SELECT max_conversations FROM users WHERE id = ?1")
+            .bind(user_id)
+            .fetch_optional(exec)
+            .await?;
+
+    Ok(limit.flatten())
+}
+
+/// Tokens the user has been charged for since `since` (unix seconds).
+pub async fn tokens_spent_since(
+    user_id: i64,
+    since: i64,
+    exec: impl SqliteExecutor<'_>,
+) -> Result<i64, sqlx::Error> {
+    sqlx::query_scalar(
+        "SELECT COALESCE(SUM(tokens), 0) FROM token_usage WHERE user_id = ?1 AND recorded_at >= ?2",
+    )
+    .bind(user_id)
+    .bind(since)
+    .fetch_one(exec)
+    .await
+}
+
+/// Charges a model call to the user's budget.
+pub async fn record_token_usage(
+    user_id: i64,
+    tokens: i64,
+    exec: impl SqliteExecutor<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("INSERT INTO token_usage (user_id, tokens, recorded_at) VALUES (?1, ?2, ?3)")
+        .bind(user_id)
+        .bind(tokens)
+        .bind(Utc::now().timestamp())
+        .execute(exec)
+        .await?;
+
+    Ok(())
+}
+
+/// What a request carrying an `Idempotency-Key` should do.
+#[derive(Debug, PartialEq)]
+pub enum IdempotencyClaim {
+    /// First use of the key: handle the request, then `complete_idempotency_key` or release it
+    New,
+    /// Already handled; send back the stored status and JSON body
+    Replay { status: u16, body: String },
+    /// The first request with this key hasn't finished yet
+    InProgress,
+    /// The key was first used for a different endpoint or request body
+    Mismatch,
+}
+
+/// Claims the user's key for `endpoint` and the request hashed to `request_hash`. Keys older than
+/// `ttl_secs` are purged first, everyone's, so they can be reused once they expire and don't pile
+/// up. Takes a connection so a caller can claim inside the transaction that does the work.
+pub async fn claim_idempotency_key(
+    user_id: i64,
+    key: &str,
+    endpoint: &str,
+    request_hash: &str,
+    ttl_secs: i64,
+    conn: &mut SqliteConnection,
+) -> Result<IdempotencyClaim, sqlx::Error> {
+    let now = Utc::now().timestamp();
+    sqlx::query("DELETE FROM idempotency_keys WHERE created_at <= ?1")
+        .bind(now - ttl_secs)
+        .execute(&mut *conn)
+        .await?;
+
+    // The primary key lets exactly one of several concurrent requests claim the key
+    let claimed = sqlx::query(
+        "INSERT INTO idempotency_keys (user_id, key, endpoint, request_hash, created_at)
+        VALUES (?1, ?2, ?3, ?4, ?5)
+        ON CONFLICT (user_id, key) DO NOTHING",
+    )
+    .bind(user_id)
+    .bind(key)
+    .bind(endpoint)
+    .bind(request_hash)
+    .bind(now)
+    .execute(&mut *conn)
+    .await?;
+    if claimed.rows_affected() == 1 {
+        return Ok(IdempotencyClaim::New);
+    }
+
+    // Keys stored before request hashes were kept only have their endpoint compared
+    let stored: Option<(bool, Option<u16>, Option<String>)> = sqlx::query_as(
+        "SELECT endpoint != ?3 OR COALESCE(request_hash != ?4, FALSE), status, body
+        FROM idempotency_keys WHERE user_id = ?1 AND key = ?2",
+    )
+    .bind(user_id)
+    .bind(key)
+    .bind(endpoint)
+    .bind(request_hash)
+    .fetch_optional(&mut *conn)
+    .await?;
+
+    Ok(match stored {
+        // Released between the insert and the read; the client's next retry claims it
+        None => IdempotencyClaim::InProgress,
+        Some((true, _, _)) => IdempotencyClaim::Mismatch,
+        Some((_, Some(status), Some(body))) => IdempotencyClaim::Replay { status, body },
+        Some(_) => IdempotencyClaim::InProgress,
+    })
+}
+
+/// Stores the response a claimed key replays.
+pub async fn complete_idempotency_key(
+    user_id: i64,
+    key: &str,
+    status: u16,
+    body: &str,
+    exec: impl SqliteExecutor<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("UPDATE idempotency_keys SET status = ?3, body = ?4 WHERE user_id = ?1 AND key = ?2")
+        .bind(user_id)
+        .bind(key)
+        .bind(status)
+        .bind(body)
+        .execute(exec)
+        .await?;
+
+    Ok(())
+}
+
+/// Gives a claimed key back after the request failed, so a retry runs it again.
+pub async fn release_idempotency_key(
+    user_id: i64,
+    key: &str,
+    exec: impl SqliteExecutor<'_>,
+) -> Result<(), sqlx::Error> {
+    sqlx::query("DELETE FROM idempotency_keys WHERE user_id = ?1 AND key = ?2 AND status IS NULL")
+        .bind(user_id)
+        .bind(key)
+        .execute(exec)
+        .await?;
+
+    Ok(())
+}
+
+/// Fills in each conversation's tags, sorted by name, with one query for the whole batch.
+pub async fn load_tags(
+    conversations: &mut [Conversation],
+    exec: impl SqliteExecutor<'_>,
+) -> Result<(), sqlx::Error> {
+    if conversations.is_empty() {
+        return Ok(());
+    }
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "SELECT ct.conversation_id, t.id, t.name, t.created_at FROM conversation_tags ct
+        JOIN tags t ON t.id = ct.tag_id WHERE ct.conversation_id IN (",
+    );
+    let mut ids = query.separated(", ");
+    for conversation in conversations.iter() {
+        ids.push_bind(conversation.id);
+    }
+    query.push(") ORDER BY t.name");
+
+    let rows: Vec<(i64, i64, String, i64)> = query.build_query_as().fetch_all(exec).await?;
+    let mut tags = group_tags(rows);
+    for conversation in conversations.iter_mut() {
+        conversation.tags = tags.remove(&conversation.id).unwrap_or_default();
     }
 
     Ok(())
 }
+
+pub async fn load_conversation_tags(
+    conversation: &mut Conversation,
+    exec: impl SqliteExecutor<'_>,
+) -> Result<(), sqlx::Error> {
+    load_tags(std::slice::from_mut(conversation), exec).await
+}
+
+/// Every tagged conversation of the user with its tags, for when conversations are streamed
+/// rather than loaded in batches.
+pub async fn tags_by_conversation(
+    user_id: i64,
+    exec: impl SqliteExecutor<'_>,
+) -> Result<HashMap<i64, Vec<Tag>>, sqlx::Error> {
+    let rows: Vec<(i64, i64, String, i64)> = sqlx::query_as(
+        "SELECT ct.conversation_id, t.id, t.name, t.created_at FROM conversation_tags ct
+        JOIN tags t ON t.id = ct.tag_id WHERE t.user_id = ?1 ORDER BY t.name",
+    )
+    .bind(user_id)
+    .fetch_all(exec)
+    .await?;
+
+    Ok(group_tags(rows))
+}
+
+fn group_tags(rows: Vec<(i64, i64, String, i64)>) -> HashMap<i64, Vec<Tag>> {
+    let mut tags: HashMap<i64, Vec<Tag>> = HashMap::new();
+    for (conversation_id, id, name, created_at) in rows {
+        tags.entry(conversation_id).or_default().push(Tag { id, name, created_at });
+    }
+    tags
+}
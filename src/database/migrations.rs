@@ -0,0 +1,423 @@
+use chrono::Utc;
+use futures::future::BoxFuture;
+use sqlx::{Pool, Sqlite, SqliteConnection};
+
+/// One schema step. Steps run in `version` order inside their own transaction and are recorded
+/// in `_migrations`, so each one is applied exactly once per database.
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub statements: &'static [&'static str],
+    /// Runs in the step's transaction before its statements; an error stops the step with
+    /// nothing applied, for data the statements would trip over
+    pub pre_check: Option<PreCheck>,
+}
+
+pub type PreCheck = for<'c> fn(&'c mut SqliteConnection) -> BoxFuture<'c, Result<(), sqlx::Error>>;
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "initial_schema",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS users (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            email TEXT UNIQUE NOT NULL,
+            name TEXT NOT NULL,
+            password TEXT NOT NULL
+        )",
+            "CREATE TABLE IF NOT EXISTS tokens (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            token TEXT UNIQUE NOT NULL,
+            user_id INTEGER NOT NULL,
+            email TEXT NOT NULL,
+            name TEXT NOT NULL,
+            exp INTEGER NOT NULL,
+            used BOOL NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )",
+            "CREATE TABLE IF NOT EXISTS conversations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    user_id INTEGER NOT NULL,
+    title TEXT,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+)",
+            "CREATE TABLE IF NOT EXISTS messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    conversation_id INTEGER NOT NULL,
+    role TEXT NOT NULL CHECK (role IN ('user', 'assistant', 'system')),
+    content TEXT NOT NULL,
+    timestamp INTEGER NOT NULL,
+    token_count INTEGER,
+    FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
+)",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 2,
+        name: "token_families",
+        statements: &["ALTER TABLE tokens ADD COLUMN family_id TEXT NOT NULL DEFAULT ''"],
+        pre_check: None,
+    },
+    Migration {
+        version: 3,
+        name: "token_sessions",
+        statements: &[
+            "ALTER TABLE tokens ADD COLUMN sid TEXT NOT NULL DEFAULT ''",
+            "CREATE TABLE IF NOT EXISTS revoked_sessions (
+            sid TEXT PRIMARY KEY,
+            revoked_at INTEGER NOT NULL
+        )",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 4,
+        name: "conversation_archive",
+        statements: &["ALTER TABLE conversations ADD COLUMN archived_at INTEGER"],
+        pre_check: None,
+    },
+    Migration {
+        version: 5,
+        name: "user_timestamps",
+        statements: &[
+            "ALTER TABLE users ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0",
+            "ALTER TABLE users ADD COLUMN updated_at INTEGER NOT NULL DEFAULT 0",
+            // Accounts that predate the columns are dated to the migration itself
+            "UPDATE users SET created_at = strftime('%s', 'now'), updated_at = strftime('%s', 'now') WHERE created_at = 0",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 6,
+        name: "email_nocase",
+        statements: &[
+            "UPDATE users SET email = LOWER(email)",
+            "CREATE UNIQUE INDEX IF NOT EXISTS users_email_nocase ON users (email COLLATE NOCASE)",
+        ],
+        pre_check: Some(check_email_case_conflicts),
+    },
+    Migration {
+        version: 7,
+        name: "email_verification",
+        statements: &[
+            "ALTER TABLE users ADD COLUMN email_verified BOOLEAN NOT NULL DEFAULT FALSE",
+            // Accounts created before verification existed stay able to log in
+            "UPDATE users SET email_verified = TRUE",
+            "CREATE TABLE IF NOT EXISTS email_verifications (
+            token_hash TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 8,
+        name: "password_resets",
+        statements: &["CREATE TABLE IF NOT EXISTS password_resets (
+            token_hash TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            expires_at INTEGER NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )"],
+        pre_check: None,
+    },
+    Migration {
+        version: 9,
+        name: "conversation_version",
+        statements: &["ALTER TABLE conversations ADD COLUMN version INTEGER NOT NULL DEFAULT 1"],
+        pre_check: None,
+    },
+    Migration {
+        version: 10,
+        name: "message_model",
+        // Which model wrote an assistant reply; NULL for user rows and replies from before the column
+        statements: &["ALTER TABLE messages ADD COLUMN model TEXT"],
+        pre_check: None,
+    },
+    Migration {
+        version: 11,
+        name: "conversation_settings",
+        statements: &["CREATE TABLE IF NOT EXISTS conversation_settings (
+            conversation_id INTEGER PRIMARY KEY,
+            temperature REAL,
+            top_p REAL,
+            max_output_tokens INTEGER,
+            FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE
+        )"],
+        pre_check: None,
+    },
+    Migration {
+        version: 12,
+        name: "conversation_system_prompt",
+        statements: &["ALTER TABLE conversations ADD COLUMN system_prompt TEXT"],
+        pre_check: None,
+    },
+    Migration {
+        version: 13,
+        name: "message_stopped",
+        // Set on replies the user cut short; their content is the partial text
+        statements: &["ALTER TABLE messages ADD COLUMN stopped BOOLEAN NOT NULL DEFAULT FALSE"],
+        pre_check: None,
+    },
+    Migration {
+        version: 14,
+        name: "token_budgets",
+        statements: &[
+            // NULL follows MONTHLY_TOKEN_BUDGET
+            "ALTER TABLE users ADD COLUMN monthly_token_budget INTEGER",
+            "CREATE TABLE IF NOT EXISTS token_usage (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            tokens INTEGER NOT NULL,
+            recorded_at INTEGER NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )",
+            "CREATE INDEX IF NOT EXISTS token_usage_user_time ON token_usage (user_id, recorded_at)",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 15,
+        name: "user_roles",
+        statements: &[
+            "ALTER TABLE users ADD COLUMN role TEXT NOT NULL DEFAULT 'user' CHECK (role IN ('user', 'admin'))",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 16,
+        name: "user_disabled",
+        // Set when an account is suspended; its tokens stop working and it can't log in
+        statements: &["ALTER TABLE users ADD COLUMN disabled_at INTEGER"],
+        pre_check: None,
+    },
+    Migration {
+        version: 17,
+        name: "two_factor",
+        statements: &[
+            // Encrypted; set by /2fa/enable and only enforced once /2fa/verify flips the flag
+            "ALTER TABLE users ADD COLUMN totp_secret TEXT",
+            "ALTER TABLE users ADD COLUMN totp_enabled BOOLEAN NOT NULL DEFAULT FALSE",
+            // Last accepted time step, so a code can't be replayed within its window
+            "ALTER TABLE users ADD COLUMN totp_last_step INTEGER",
+            "CREATE TABLE IF NOT EXISTS backup_codes (
+            code_hash TEXT PRIMARY KEY,
+            user_id INTEGER NOT NULL,
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 18,
+        name: "token_devices",
+        statements: &[
+            // Where the login or refresh that minted the token came from
+            "ALTER TABLE tokens ADD COLUMN ip TEXT",
+            "ALTER TABLE tokens ADD COLUMN user_agent TEXT",
+            "ALTER TABLE tokens ADD COLUMN created_at INTEGER NOT NULL DEFAULT 0",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 19,
+        name: "message_metadata",
+        statements: &[
+            // How an assistant reply came about; NULL on user messages and older replies
+            "ALTER TABLE messages ADD COLUMN latency_ms INTEGER",
+            "ALTER TABLE messages ADD COLUMN finish_reason TEXT",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 20,
+        name: "idempotency_keys",
+        statements: &["CREATE TABLE IF NOT EXISTS idempotency_keys (
+            user_id INTEGER NOT NULL,
+            key TEXT NOT NULL,
+            -- What the key was first used for, so it can't be replayed against something else
+            endpoint TEXT NOT NULL,
+            -- Both NULL while the first request is still being handled
+            status INTEGER,
+            body TEXT,
+            created_at INTEGER NOT NULL,
+            PRIMARY KEY (user_id, key),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )"],
+        pre_check: None,
+    },
+    Migration {
+        version: 21,
+        name: "conversation_order",
+        statements: &[
+            "ALTER TABLE conversations ADD COLUMN pinned BOOLEAN NOT NULL DEFAULT FALSE",
+            // Manual position from drag-to-reorder; NULL falls back to recency
+            "ALTER TABLE conversations ADD COLUMN sort_order INTEGER",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 22,
+        name: "tags",
+        statements: &[
+            "CREATE TABLE IF NOT EXISTS tags (
+            id INTEGER PRIMARY KEY AUTOINCREMENT,
+            user_id INTEGER NOT NULL,
+            name TEXT NOT NULL COLLATE NOCASE,
+            created_at INTEGER NOT NULL,
+            UNIQUE (user_id, name),
+            FOREIGN KEY (user_id) REFERENCES users(id) ON DELETE CASCADE
+        )",
+            "CREATE TABLE IF NOT EXISTS conversation_tags (
+            conversation_id INTEGER NOT NULL,
+            tag_id INTEGER NOT NULL,
+            PRIMARY KEY (conversation_id, tag_id),
+            FOREIGN KEY (conversation_id) REFERENCES conversations(id) ON DELETE CASCADE,
+            FOREIGN KEY (tag_id) REFERENCES tags(id) ON DELETE CASCADE
+        )",
+            "CREATE INDEX IF NOT EXISTS conversation_tags_tag ON conversation_tags (tag_id)",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 23,
+        name: "message_cached",
+        statements: &["ALTER TABLE messages ADD COLUMN cached BOOLEAN NOT NULL DEFAULT FALSE"],
+        pre_check: None,
+    },
+    Migration {
+        version: 24,
+        name: "conversation_limits",
+        // NULL follows MAX_CONVERSATIONS
+        statements: &["ALTER TABLE users ADD COLUMN max_conversations INTEGER"],
+        pre_check: None,
+    },
+    Migration {
+        version: 25,
+        name: "token_jti",
+        // Rows from before this have no jti and can't be refreshed; their users log in again
+        statements: &[
+            "ALTER TABLE tokens ADD COLUMN jti TEXT",
+            "CREATE INDEX IF NOT EXISTS tokens_jti ON tokens (jti)",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 26,
+        name: "idempotency_request_hash",
+        statements: &[
+            // SHA-256 of what the first request asked for; NULL on keys stored before this
+            "ALTER TABLE idempotency_keys ADD COLUMN request_hash TEXT",
+            "CREATE INDEX IF NOT EXISTS idempotency_keys_created_at ON idempotency_keys (created_at)",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 27,
+        name: "totp_lockout",
+        statements: &[
+            "ALTER TABLE users ADD COLUMN totp_failed_attempts INTEGER NOT NULL DEFAULT 0",
+            // Second-factor checks are refused until then
+            "ALTER TABLE users ADD COLUMN totp_locked_until INTEGER",
+        ],
+        pre_check: None,
+    },
+    Migration {
+        version: 28,
+        name: "refresh_token_sha256",
+        // Refresh tokens are now stored as SHA-256; rows still holding an argon2 hash can't be
+        // matched, so their users log in again
+        statements: &["DELETE FROM tokens WHERE token LIKE '$argon2%'"],
+        pre_check: None,
+    },
+];
+
+/// Applies every migration newer than the database's recorded version.
+pub async fn run_migrations(pool: &Pool<Sqlite>) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        "CREATE TABLE IF NOT EXISTS _migrations (
+            version INTEGER PRIMARY KEY,
+            name TEXT NOT NULL,
+            applied_at INTEGER NOT NULL
+        )",
+    )
+    .execute(pool)
+    .await?;
+
+    let current: i64 = sqlx::query_scalar("SELECT COALESCE(MAX(version), 0) FROM _migrations")
+        .fetch_one(pool)
+        .await?;
+
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        let mut tx = pool.begin().await?;
+
+        if let Some(pre_check) = migration.pre_check {
+            pre_check(&mut tx).await?;
+        }
+
+        for statement in migration.statements {
+            if let Err(e) = sqlx::query(statement).execute(&mut *tx).await {
+                // Databases from before the runner may already carry columns added by ad-hoc ALTERs
+                if !is_duplicate_column(&e) {
+                    return Err(e);
+                }
+            }
+        }
+
+        sqlx::query("INSERT INTO _migrations (version, name, applied_at) VALUES (?1, ?2, ?3)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .bind(Utc::now().timestamp())
+            .execute(&mut *tx)
+            .await?;
+
+        tx.commit().await?;
+        tracing::info!(version = migration.version, name = migration.name, "applied migration");
+    }
+
+    Ok(())
+}
+
+/// Lowercasing emails fails on the unique index when two accounts differ only in case. Those have
+/// to be merged or renamed by hand, so name them instead of surfacing a bare constraint error, e.g.
+/// `UPDATE users SET email = 'old.alice@example.com' WHERE id = 7`, then restart.
+fn check_email_case_conflicts(conn: &mut SqliteConnection) -> BoxFuture<'_, Result<(), sqlx::Error>> {
+    Box::pin(async move {
+        let conflicts: Vec<(i64, String)> = sqlx::query_as(
+            "SELECT id, email FROM users WHERE LOWER(email) IN
+                (SELECT LOWER(email) FROM users GROUP BY LOWER(email) HAVING COUNT(*) > 1)
+            ORDER BY LOWER(email), id",
+        )
+        .fetch_all(conn)
+        .await?;
+
+        if conflicts.is_empty() {
+            return Ok(());
+        }
+
+        let rows = conflicts
+            .iter()
+            .map(|(id, email)| format!("{} (id {})", email, id))
+            .collect::<Vec<_>>()
+            .join(", ");
+
+        Err(sqlx::Error::Configuration(
+            format!(
+                "migration email_nocase: these users share an email address ignoring case: {}. \
+                Change or delete all but one of each group, then restart",
+                rows
+            )
+            .into(),
+        ))
+    })
+}
+
+fn is_duplicate_column(e: &sqlx::Error) -> bool {
+    matches!(e, sqlx::Error::Database(db) if db.message().contains("duplicate column name"))
+}
@@ -1,12 +1,17 @@
-use axum::{http::StatusCode, response::IntoResponse, Json};
+use axum::{Json, http::StatusCode, response::IntoResponse};
 use serde::{Deserialize, Serialize};
+use validator::ValidationErrors;
 
+use crate::utils::validation::{ValidationDetail, ValidationError, format_validation_errors};
+
+/// A failed model call, whichever provider made it, rendered as `{"error": {"code", "message"}}`
+/// with the HTTP status taken from `code`.
 #[derive(Serialize, Deserialize, Debug)]
-pub struct GeminiApiErrorWrapper {
-    pub error: GeminiApiError,
+pub struct AiErrorWrapper {
+    pub error: AiError,
 }
 
-impl IntoResponse for GeminiApiErrorWrapper {
+impl IntoResponse for AiErrorWrapper {
     fn into_response(self) -> axum::response::Response {
         let status =
             StatusCode::from_u16(self.error.code).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
@@ -15,11 +20,67 @@ impl IntoResponse for GeminiApiErrorWrapper {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-pub struct GeminiApiError {
+pub struct AiError {
     pub code: u16,
     pub message: String,
+    /// The provider that failed, absent when the error came from our side of the call (a deadline)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub provider: Option<String>,
 }
 
+impl AiErrorWrapper {
+    pub fn new(status: StatusCode, message: impl Into<String>) -> Self {
+        Self {
+            error: AiError {
+                code: status.as_u16(),
+                message: message.into(),
+                provider: None,
+            },
+        }
+    }
+
+    pub fn with_provider(mut self, provider: impl Into<String>) -> Self {
+        self.error.provider = Some(provider.into());
+        self
+    }
+}
+
+impl From<gemini_rust::Error> for AiErrorWrapper {
+    fn from(e: gemini_rust::Error) -> Self {
+        let wrapper = match e {
+            gemini_rust::Error::HttpError(e) if e.is_timeout() => Self::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "Gemini did not respond in time",
+            ),
+            gemini_rust::Error::HttpError(e) => Self::new(
+                StatusCode::BAD_GATEWAY,
+                format!("Failed to reach Gemini: {}", e),
+            ),
+            gemini_rust::Error::JsonError(e) => Self::new(
+                StatusCode::BAD_GATEWAY,
+                format!("Gemini returned an unreadable response: {}", e),
+            ),
+            // The API relays its own `{"error": {...}}` body; keep it when it parses
+            gemini_rust::Error::ApiError { message, .. } => message
+                .find('{')
+                .and_then(|start| serde_json::from_str(&message[start..]).ok())
+                .unwrap_or_else(|| Self::new(StatusCode::BAD_GATEWAY, message)),
+            gemini_rust::Error::RequestError(message) => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Failed to build Gemini request: {}", message),
+            ),
+            gemini_rust::Error::MissingApiKey => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                "Gemini API key is missing",
+            ),
+            gemini_rust::Error::FunctionCallError(message) => Self::new(
+                StatusCode::INTERNAL_SERVER_ERROR,
+                format!("Gemini function call failed: {}", message),
+            ),
+        };
+        wrapper.with_provider("gemini")
+    }
+}
 
 #[derive(Serialize)]
 pub struct DatabaseError {
@@ -30,4 +91,234 @@ impl IntoResponse for DatabaseError {
     fn into_response(self) -> axum::response::Response {
         (StatusCode::INTERNAL_SERVER_ERROR, Json(self)).into_response()
     }
-}
\ No newline at end of file
+}
+
+#[derive(Debug)]
+pub enum AuthError {
+    MissingHeader,
+    MalformedHeader,
+    TokenExpired,
+    InvalidSignature,
+    InvalidToken,
+    SessionRevoked,
+    /// The token's account was deleted or disabled after it was issued
+    AccountInactive,
+    /// Authenticated, but the account's role doesn't cover the route
+    Forbidden,
+    Unavailable,
+}
+
+impl AuthError {
+    fn code(&self) -> &'static str {
+        match self {
+            AuthError::MissingHeader => "missing_authorization_header",
+            AuthError::MalformedHeader => "malformed_authorization_header",
+            AuthError::TokenExpired => "token_expired",
+            AuthError::InvalidSignature => "invalid_signature",
+            AuthError::InvalidToken => "invalid_token",
+            AuthError::SessionRevoked => "session_revoked",
+            AuthError::AccountInactive => "account_inactive",
+            AuthError::Forbidden => "insufficient_role",
+            AuthError::Unavailable => "auth_unavailable",
+        }
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            AuthError::Forbidden => StatusCode::FORBIDDEN,
+            AuthError::Unavailable => StatusCode::INTERNAL_SERVER_ERROR,
+            _ => StatusCode::UNAUTHORIZED,
+        }
+    }
+}
+
+impl From<jsonwebtoken::errors::Error> for AuthError {
+    fn from(e: jsonwebtoken::errors::Error) -> Self {
+        match e.kind() {
+            jsonwebtoken::errors::ErrorKind::ExpiredSignature => AuthError::TokenExpired,
+            jsonwebtoken::errors::ErrorKind::InvalidSignature => AuthError::InvalidSignature,
+            _ => AuthError::InvalidToken,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct AuthErrorBody {
+    error: &'static str,
+}
+
+impl IntoResponse for AuthError {
+    fn into_response(self) -> axum::response::Response {
+        (self.status(), Json(AuthErrorBody { error: self.code() })).into_response()
+    }
+}
+
+/// Error type handlers return; every variant renders with the JSON shape of the error it wraps.
+///
+/// Statuses follow one policy across handlers:
+/// - `401`: no usable token (missing, malformed, expired, revoked, or its account is gone or
+///   disabled), decided by the auth middleware before any handler runs
+/// - `403`: a valid token whose role doesn't cover the route
+/// - `404`: a conversation, message or tag that is missing *or belongs to someone else*; the two
+///   aren't told apart so ids can't be probed
+/// - `400`: the request itself is invalid, whoever sends it
+#[derive(Debug)]
+pub enum AppError {
+    /// Client input problem, always a `400`
+    Validation(ValidationError),
+    /// Same body as `Validation` under a different status
+    Status(StatusCode, ValidationError),
+    Ai(AiErrorWrapper),
+    Auth(AuthError),
+}
+
+impl AppError {
+    /// Builds a `ValidationError`-shaped error with a single detail.
+    pub fn new(
+        status: StatusCode,
+        error: impl Into<String>,
+        field: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        AppError::Status(
+            status,
+            ValidationError {
+                error: error.into(),
+                details: vec![ValidationDetail {
+                    field: field.into(),
+                    messages: vec![message.into()],
+                }],
+            },
+        )
+    }
+
+    pub fn internal(
+        error: impl Into<String>,
+        field: impl Into<String>,
+        message: impl Into<String>,
+    ) -> Self {
+        Self::new(StatusCode::INTERNAL_SERVER_ERROR, error, field, message)
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            AppError::Validation(e) => e.into_response(),
+            AppError::Status(status, e) => (status, e).into_response(),
+            AppError::Ai(e) => e.into_response(),
+            AppError::Auth(e) => e.into_response(),
+        }
+    }
+}
+
+impl From<ValidationError> for AppError {
+    fn from(e: ValidationError) -> Self {
+        AppError::Validation(e)
+    }
+}
+
+impl From<(StatusCode, ValidationError)> for AppError {
+    fn from((status, e): (StatusCode, ValidationError)) -> Self {
+        AppError::Status(status, e)
+    }
+}
+
+impl From<ValidationErrors> for AppError {
+    fn from(e: ValidationErrors) -> Self {
+        AppError::Validation(format_validation_errors(e))
+    }
+}
+
+impl From<AiErrorWrapper> for AppError {
+    fn from(e: AiErrorWrapper) -> Self {
+        AppError::Ai(e)
+    }
+}
+
+impl From<AuthError> for AppError {
+    fn from(e: AuthError) -> Self {
+        AppError::Auth(e)
+    }
+}
+
+impl From<sqlx::Error> for AppError {
+    fn from(e: sqlx::Error) -> Self {
+        match e {
+            // Only `fetch_one` produces this, when the row the handler asked for isn't there
+            sqlx::Error::RowNotFound => AppError::new(
+                StatusCode::NOT_FOUND,
+                "Not found",
+                "id",
+                "No matching record for the current user.",
+            ),
+            e => AppError::internal(
+                "Database error",
+                "database",
+                format!("Database query failed: {}", e),
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mapped(e: gemini_rust::Error) -> (u16, String) {
+        let wrapper = AiErrorWrapper::from(e);
+        assert_eq!(wrapper.error.provider.as_deref(), Some("gemini"));
+        (wrapper.error.code, wrapper.error.message)
+    }
+
+    #[test]
+    fn api_error_keeps_the_relayed_body() {
+        let e = gemini_rust::Error::ApiError {
+            status_code: 429,
+            message: r#"{"error": {"code": 429, "message": "Resource exhausted", "status": "RESOURCE_EXHAUSTED"}}"#
+                .to_string(),
+        };
+
+        assert_eq!(mapped(e), (429, "Resource exhausted".to_string()));
+    }
+
+    #[test]
+    fn api_error_without_json_becomes_a_bad_gateway() {
+        let e = gemini_rust::Error::ApiError {
+            status_code: 500,
+            message: "upstream connect error".to_string(),
+        };
+
+        assert_eq!(mapped(e), (502, "upstream connect error".to_string()));
+    }
+
+    #[test]
+    fn unreadable_response_becomes_a_bad_gateway() {
+        let json_error = serde_json::from_str::<AiError>("not json").unwrap_err();
+
+        let (code, message) = mapped(gemini_rust::Error::JsonError(json_error));
+        assert_eq!(code, 502);
+        assert!(message.starts_with("Gemini returned an unreadable response"));
+    }
+
+    #[test]
+    fn local_failures_are_internal_errors() {
+        assert_eq!(
+            mapped(gemini_rust::Error::RequestError("bad model".to_string())),
+            (500, "Failed to build Gemini request: bad model".to_string())
+        );
+        assert_eq!(
+            mapped(gemini_rust::Error::MissingApiKey),
+            (500, "Gemini API key is missing".to_string())
+        );
+        assert_eq!(
+            mapped(gemini_rust::Error::FunctionCallError(
+                "no such function".to_string()
+            )),
+            (
+                500,
+                "Gemini function call failed: no such function".to_string()
+            )
+        );
+    }
+}
@@ -0,0 +1,61 @@
+use std::sync::Arc;
+
+use axum::{
+    Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use chrono::Utc;
+
+use crate::{
+    errors::api_errors::AppError,
+    models::{
+        app::AppState,
+        user::{AccountStatus, UserDB, UserProfile},
+    },
+};
+
+/// Every account, oldest first. Admin only.
+pub async fn list_users(State(state): State<Arc<AppState>>) -> Result<Json<Vec<UserProfile>>, AppError> {
+    let users: Vec<UserDB> = sqlx::query_as("SELECT * FROM users ORDER BY id ASC")
+        .fetch_all(&state.db)
+        .await?;
+
+    Ok(Json(users.into_iter().map(UserProfile::from).collect()))
+}
+
+/// Disables or re-enables an account. Disabling ends its sessions at once. Admin only.
+pub async fn update_account_status(
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Json(payload): Json<AccountStatus>,
+) -> Result<Json<UserProfile>, AppError> {
+    let mut tx = state.db.begin().await?;
+
+    // Re-disabling keeps the original date
+    let user: Option<UserDB> = sqlx::query_as(
+        "UPDATE users SET disabled_at = CASE WHEN ?1 THEN COALESCE(disabled_at, ?2) END, updated_at = ?2
+WHERE id = ?3 RETURNING *",
+    )
+    .bind(payload.disabled)
+    .bind(Utc::now().timestamp())
+    .bind(id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let user = user.ok_or_else(|| {
+        AppError::new(StatusCode::NOT_FOUND, "User not found", "id", "No user with this ID.")
+    })?;
+
+    if payload.disabled {
+        sqlx::query("DELETE FROM tokens WHERE user_id = ?1")
+            .bind(id)
+            .execute(&mut *tx)
+            .await?;
+    }
+
+    tx.commit().await?;
+    state.forget_account(id);
+
+    Ok(Json(user.into()))
+}
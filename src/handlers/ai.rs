@@ -1,449 +1,2949 @@
-use std::{env, sync::Arc, time::Duration};
+use std::{
+    convert::Infallible,
+    future::Future,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use axum::{
     Extension, Json, debug_handler,
     extract::{
         Path, Query, State, WebSocketUpgrade,
-        ws::{Message, WebSocket},
+        ws::{CloseFrame, Message, WebSocket, close_code},
     },
-    http::StatusCode,
-    response::Response,
+    http::{HeaderMap, HeaderName, HeaderValue, StatusCode, header},
+    response::sse::{Event, KeepAlive, Sse},
+    response::{IntoResponse, Response},
 };
 use chrono::Utc;
-use gemini_rust::{Error, Gemini};
-use serde::Deserialize;
+use futures::{
+    SinkExt, Stream, StreamExt,
+    channel::mpsc,
+    stream::{self, BoxStream},
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use sqlx::{Acquire, QueryBuilder, Sqlite, SqliteExecutor};
+use tokio::sync::{broadcast::error::RecvError, watch};
+use tracing::Instrument;
+use validator::Validate;
 
 use crate::{
-    database::connection::insert_chat_message_to_db,
-    errors::api_errors::GeminiApiErrorWrapper,
+    ai::{
+        AiProvider, AiRequest, AiResult, AiStream, ModelInfo, ProviderError, Turn,
+        cache::{CacheKey, ResponseCache},
+    },
+    database::connection::{
+        IdempotencyClaim, claim_idempotency_key, complete_idempotency_key, count_user_turns_before,
+        get_conversation_history, get_conversation_limit, get_conversation_settings,
+        get_system_prompt, insert_chat_message_to_db, load_conversation_tags, load_tags,
+        record_token_usage, release_idempotency_key,
+    },
+    errors::api_errors::{AiErrorWrapper, AppError},
+    handlers::usage::{exhausted_token_budget, token_budget_error},
+    middleware::auth::{authenticate, websocket_token},
     models::{
-        ai::{AiResponse, ConvMessage, Conversation, Message as UserText, Title, UserMessage},
-        app::AppState,
+        ai::{
+            AiResponse, BulkDelete, BulkDeleteResult, ControlFrame, ConvMessage, Conversation,
+            ConversationPage, EditMessage, GenerationParams, ImportConversation,
+            ImportedConversation, ImportedMessage, KeyedPrompt, MAX_TITLE_CHARS,
+            Message as UserText, MessagePage, MoveMessage, NewConversation, Pin, Position,
+            ReplyMeta, SystemPrompt, Title, UserMessage,
+        },
+        app::{AppState, Generation, GenerationEnd, GenerationEvent, Settings},
         auth::TokenClaims,
     },
-    utils::validation::{ValidationDetail, ValidationError},
+    utils::{
+        normalization::{normalize_text, tidy_whitespace},
+        tokens::hash_token,
+        validation::{ValidationDetail, ValidationError},
+    },
 };
 
 #[debug_handler]
 #[allow(unused)]
 pub async fn analyze_text(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
     Json(payload): Json<UserText>,
-) -> Result<Json<AiResponse>, GeminiApiErrorWrapper> {
-    let text = make_request_to_ai(&payload.msg).await;
-
-    match text {
-        Ok(text) => return Ok(Json(text)),
-        Err(e) => match e {
-            _ => {
-                let json_start = e.to_string().find("{").expect("Not a pure json");
-                let new_e: GeminiApiErrorWrapper =
-                    serde_json::from_str(&e.to_string()[json_start..])
-                        .expect("Incorrect GeminiApiError json");
-                return Err(new_e);
-            }
-        },
+) -> Result<Json<AiResponse>, AppError> {
+    payload.validate()?;
+    check_prompt_length(&state.settings, "msg", &payload.msg)?;
+    moderate_prompt(&state, user_data.user_id, None, &payload.msg)?;
+
+    if let Some(budget) = exhausted_token_budget(&state, user_data.user_id).await? {
+        return Err((StatusCode::TOO_MANY_REQUESTS, token_budget_error(budget)).into());
     }
-}
 
-pub async fn make_request_to_ai(msg: &str) -> Result<AiResponse, Error> {
-    let key = env::var("GEMINI_API_KEY").unwrap();
+    let options = ReplyOptions {
+        model: resolve_model(
+            &state.settings,
+            state.ai_provider(),
+            payload.model.as_deref(),
+            ModelInfo {
+                streaming: false,
+                vision: !payload.images.is_empty(),
+            },
+        )?,
+        params: payload.params,
+        system_prompt: None,
+        images: payload.images,
+    };
+    let reply = make_request_to_ai(&state, &options, &[], 0, &payload.msg).await?;
+    record_token_usage(user_data.user_id, reply.total_tokens, &state.db).await?;
 
-    let client = Gemini::new(key);
+    Ok(Json(AiResponse {
+        ai_response: reply.text,
+    }))
+}
 
-    let response = client
-        .generate_content()
-        .with_user_message(msg)
-        .execute()
-        .await?;
+/// Rejects prompts longer than `MAX_MESSAGE_CHARS` before they're stored or cost a provider call.
+fn check_prompt_length(
+    settings: &Settings,
+    field: &str,
+    text: &str,
+) -> Result<(), ValidationError> {
+    let max = settings.max_message_chars;
+    if max == 0 || text.chars().count() <= max {
+        return Ok(());
+    }
 
-    return Ok(AiResponse {
-        ai_response: response.text(),
-    });
+    Err(ValidationError {
+        error: "Message too long".to_string(),
+        details: vec![ValidationDetail {
+            field: field.to_string(),
+            messages: vec![format!("Messages are limited to {} characters.", max)],
+        }],
+    })
 }
-pub async fn create_conversation(
-    Extension(user_data): Extension<TokenClaims>,
-    State(state): State<Arc<AppState>>,
-) -> Result<Json<Conversation>, ValidationError> {
-    let time_now = Utc::now().timestamp();
-    let _ = sqlx::query("INSERT INTO conversations (user_id, title, created_at, updated_at) VALUES (?1, ?2, ?3, ?4)")
-        .bind(user_data.user_id)
-        .bind("New chat")
-        .bind(time_now)
-        .bind(time_now)
-        .execute(&state.chat_db)
-        .await.map_err(|e| ValidationError {
-            error: "Database query failed".to_string(),
-            details: vec![ValidationDetail {
-                field: "credentials".to_string(),
-                messages: vec![format!("creating new conversation failed: {}",e)]
-            }]
-        })?;
 
-    let r: Conversation = sqlx::query_as("SELECT * FROM conversations where user_id = ? AND created_at = ?")
-        .bind(user_data.user_id)
-        .bind(time_now)
-        .fetch_one(&state.chat_db)
-        .await
-        .unwrap();
+/// Checks a prompt against the moderation denylist before it's stored or sent to the model.
+fn moderate_prompt(
+    state: &AppState,
+    user_id: i64,
+    conversation_id: Option<i64>,
+    text: &str,
+) -> Result<(), ValidationError> {
+    if !state.settings.moderation_enabled {
+        return Ok(());
+    }
 
-    println!("{:?}", r);
+    let Some(pattern) = state.moderate(text) else {
+        tracing::debug!(user_id, conversation_id, "prompt passed moderation");
+        return Ok(());
+    };
 
-    Ok(Json(r))
+    tracing::warn!(
+        user_id,
+        conversation_id,
+        pattern,
+        "prompt blocked by moderation"
+    );
+    Err(ValidationError {
+        error: "Message rejected".to_string(),
+        details: vec![ValidationDetail {
+            field: "msg".to_string(),
+            messages: vec!["This message isn't allowed by the content policy.".to_string()],
+        }],
+    })
 }
 
-#[debug_handler]
-pub async fn get_user_conversations(
-    Extension(user_data): Extension<TokenClaims>,
-    State(state): State<Arc<AppState>>,
-) -> Result<Json<Vec<Conversation>>, ValidationError> {
-    let r: Vec<Conversation> = sqlx::query_as("SELECT * FROM conversations where user_id = ?")
-        .bind(user_data.user_id)
-        .fetch_all(&state.chat_db)
-        .await
-        .map_err(|e| ValidationError {
-            error: "Database query failed".to_string(),
-            details: vec![ValidationDetail {
-                field: "credentials".to_string(),
-                messages: vec![format!("getting users conversations failed: {}", e)],
-            }],
-        })?;
+/// The model a request asked for, or the configured default when it didn't name one, checked
+/// against what the active provider serves: a streamed reply needs a model that streams and
+/// images need one with vision. Names may be given with or without the `models/` prefix the API uses.
+fn resolve_model(
+    settings: &Settings,
+    provider: &dyn AiProvider,
+    requested: Option<&str>,
+    needs: ModelInfo,
+) -> Result<String, ValidationError> {
+    let name = match requested {
+        Some(requested) => {
+            let name = requested.trim().trim_start_matches("models/");
+            let allowed = name == settings.gemini_model
+                || settings
+                    .gemini_allowed_models
+                    .iter()
+                    .any(|model| model == name);
 
-    Ok(Json(r))
+            if !allowed {
+                return Err(model_error(
+                    "Validation failed",
+                    "model",
+                    format!(
+                        "Unknown model, expected one of: {}",
+                        settings.gemini_allowed_models.join(", ")
+                    ),
+                ));
+            }
+            name
+        }
+        None => settings.gemini_model.as_str(),
+    };
+
+    let Some(info) = provider.model_info(name) else {
+        return Err(model_error(
+            "Unsupported model",
+            "model",
+            format!("{} isn't served by the {} provider.", name, provider.name()),
+        ));
+    };
+    if needs.streaming && !info.streaming {
+        return Err(model_error(
+            "Unsupported model",
+            "model",
+            format!("{} can't stream replies.", name),
+        ));
+    }
+    if needs.vision && !info.vision {
+        return Err(model_error(
+            "Unsupported model",
+            "images",
+            format!("{} can't read images.", name),
+        ));
+    }
+
+    Ok(name.to_string())
 }
 
-#[derive(Deserialize)]
-pub struct ConversationID {
-    pub id: i64,
+fn model_error(error: &str, field: &str, message: String) -> ValidationError {
+    ValidationError {
+        error: error.to_string(),
+        details: vec![ValidationDetail {
+            field: field.to_string(),
+            messages: vec![message],
+        }],
+    }
 }
 
-pub async fn get_user_conversations_by_id(
-    Extension(user_data): Extension<TokenClaims>,
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<i64>,
-) -> Result<Json<Vec<Conversation>>, ValidationError> {
-    let r: Vec<Conversation> =
-        sqlx::query_as("SELECT * FROM conversations WHERE user_id = (?1) AND id = (?2)")
-            .bind(user_data.user_id)
-            .bind(id)
-            .fetch_all(&state.chat_db)
-            .await
-            .map_err(|e| ValidationError {
-                error: "Database query failed".to_string(),
-                details: vec![ValidationDetail {
-                    field: "credentials".to_string(),
-                    messages: vec![format!("getting user's conversations by id failed: {}", e)],
-                }],
-            })?;
+/// Sent once a streamed reply is complete so clients know no more chunks follow.
+const DONE_FRAME: &str = "{\"done\":true}";
 
-    Ok(Json(r))
+/// Acknowledges a stop: no more chunks follow and the partial reply has been kept.
+const STOPPED_FRAME: &str = "{\"stopped\":true}";
+
+/// Builds the request turns from stored history plus the new prompt.
+/// The conversation's `system_prompt` becomes the system instruction, falling back to the latest
+/// system row; the other rows map onto user/model turns. With a
+/// non-zero `reminder_every`, every that-many-th user turn is prefixed with the system prompt again
+/// so long conversations don't drift from it. Turns are numbered across the whole conversation:
+/// `earlier_user_turns` counts the user messages that fell out of the replayed window.
+fn to_turns(
+    system_prompt: Option<&str>,
+    history: &[ConvMessage],
+    msg: &str,
+    reminder_every: usize,
+    earlier_user_turns: usize,
+) -> (Option<String>, Vec<Turn>) {
+    let system = system_prompt.map(str::to_string).or_else(|| {
+        history
+            .iter()
+            .rev()
+            .find(|turn| turn.role == "system")
+            .map(|turn| turn.content.clone())
+    });
+
+    let user_turns = history
+        .iter()
+        .filter(|turn| turn.role == "user")
+        .map(|turn| turn.content.as_str())
+        .chain([msg]);
+    let mut user_turns = user_turns.enumerate();
+
+    let mut with_reminder = |(index, content): (usize, &str)| match &system {
+        Some(system)
+            if reminder_every > 0
+                && (earlier_user_turns + index + 1).is_multiple_of(reminder_every) =>
+        {
+            Turn::user(format!("(Reminder: {})\n\n{}", system, content))
+        }
+        _ => Turn::user(content),
+    };
+
+    let mut turns = Vec::new();
+    for turn in history {
+        match turn.role.as_str() {
+            "assistant" => turns.push(Turn::model(turn.content.as_str())),
+            "system" => {}
+            _ => turns.extend(user_turns.next().map(&mut with_reminder)),
+        }
+    }
+    turns.extend(user_turns.next().map(&mut with_reminder));
+
+    (system, turns)
 }
 
-pub async fn update_conversation_by_id(
-    Extension(user_data): Extension<TokenClaims>,
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<i64>,
-    Json(payload): Json<Title>,
-) -> Result<Json<Conversation>, ValidationError> {
-    let existing: Option<Conversation> =
-        sqlx::query_as("SELECT * FROM conversations WHERE user_id = ?1 AND id = ?2")
-            .bind(user_data.user_id)
-            .bind(id)
-            .fetch_optional(&state.chat_db)
-            .await
-            .map_err(|e| ValidationError {
-                error: "Database query failed".to_string(),
-                details: vec![ValidationDetail {
-                    field: "id".to_string(),
-                    messages: vec![format!("Check existence failed: {}", e)],
-                }],
-            })?;
-
-    if existing.is_none() {
-        return Err(ValidationError {
-            error: "Conversation not found".to_string(),
-            details: vec![ValidationDetail {
-                field: "id".to_string(),
-                messages: vec!["No conversation with this ID for the current user.".to_string()],
-            }],
-        });
+/// User turns that precede the replayed window, so reminders keep their cadence once old messages
+/// stop being replayed. `first` is the oldest replayed message, or the prompt itself when it's
+/// already stored and nothing before it is replayed.
+async fn earlier_user_turns(
+    state: &AppState,
+    conversation_id: i64,
+    first: Option<&ConvMessage>,
+) -> Result<usize, sqlx::Error> {
+    if state.settings.system_reminder_every == 0 {
+        return Ok(0);
     }
 
-    let now = chrono::Utc::now().timestamp();
-    sqlx::query(
-        "UPDATE conversations SET title = ?1, updated_at = ?2 WHERE id = ?3 AND user_id = ?4",
+    let count = count_user_turns_before(
+        conversation_id,
+        first.map(|message| (message.timestamp, message.id)),
+        &state.db,
     )
-    .bind(&payload.title)
-    .bind(now)
-    .bind(id)
-    .bind(user_data.user_id)
-    .execute(&state.chat_db)
-    .await
-    .map_err(|e| ValidationError {
-        error: "Database update failed".to_string(),
-        details: vec![ValidationDetail {
-            field: "update".to_string(),
-            messages: vec![format!("Failed to update: {}", e)],
-        }],
-    })?;
+    .await?;
 
-    let updated: Conversation =
-        sqlx::query_as("SELECT * FROM conversations WHERE id = ?1 AND user_id = ?2")
-            .bind(id)
-            .bind(user_data.user_id)
-            .fetch_one(&state.chat_db)
-            .await
-            .map_err(|e| ValidationError {
-                error: "Fetch updated conversation failed".to_string(),
-                details: vec![ValidationDetail {
-                    field: "query".to_string(),
-                    messages: vec![format!("Failed to fetch after update: {}", e)],
-                }],
-            })?;
+    Ok(count as usize)
+}
 
-    Ok(Json(updated))
+/// Runs a provider call under the configured deadline. Rate limits, 5xx hiccups, network errors
+/// and timeouts are retried with exponential backoff plus jitter before the last error is returned.
+async fn call_provider<T, F, Fut>(settings: &Settings, mut call: F) -> Result<T, AiErrorWrapper>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, ProviderError>>,
+{
+    let deadline = Duration::from_secs(settings.gemini_timeout_secs);
+    let mut attempt = 0;
+
+    loop {
+        let error = match tokio::time::timeout(deadline, call()).await {
+            Ok(Ok(value)) => return Ok(value),
+            Ok(Err(e)) if e.transient => e.error,
+            Ok(Err(e)) => return Err(e.error),
+            Err(_) => AiErrorWrapper::new(
+                StatusCode::GATEWAY_TIMEOUT,
+                "Gemini did not respond in time",
+            ),
+        };
+
+        if attempt >= settings.gemini_max_retries {
+            return Err(error);
+        }
+
+        let backoff = backoff_ms(attempt) + rand::rng().random_range(0..250);
+        tokio::time::sleep(Duration::from_millis(backoff)).await;
+        attempt += 1;
+    }
 }
 
-pub async fn delete_conversation_by_id(
-    Extension(user_data): Extension<TokenClaims>,
-    State(state): State<Arc<AppState>>,
-    Path(id): Path<i64>,
-) -> Result<StatusCode, ValidationError> {
-    let result = sqlx::query("DELETE FROM conversations WHERE id = ?1 AND user_id = ?2")
-        .bind(id)
-        .bind(user_data.user_id)
-        .execute(&state.chat_db)
-        .await
-        .map_err(|e| ValidationError {
-            error: "Database delete failed".to_string(),
-            details: vec![ValidationDetail {
-                field: "id".to_string(),
-                messages: vec![format!("Error deleting conversation: {}", e)],
-            }],
-        })?;
+/// Longest wait between two Gemini attempts, whatever GEMINI_MAX_RETRIES is set to.
+const MAX_BACKOFF_MS: u64 = 30_000;
 
-    if result.rows_affected() == 0 {
-        return Err(ValidationError {
-            error: "Not found".to_string(),
-            details: vec![ValidationDetail {
-                field: "id".to_string(),
-                messages: vec!["No conversation with this ID for the current user.".to_string()],
-            }],
-        });
+// Doubles from 500ms per attempt; saturates instead of overflowing for large retry counts
+fn backoff_ms(attempt: u32) -> u64 {
+    500u64
+        .saturating_mul(2u64.saturating_pow(attempt))
+        .min(MAX_BACKOFF_MS)
+}
+
+/// What a reply is generated with besides the conversation itself.
+pub struct ReplyOptions {
+    pub model: String,
+    pub params: GenerationParams,
+    pub system_prompt: Option<String>,
+    /// Sent with the new prompt only; `resolve_model` has checked the model can read them
+    pub images: Vec<String>,
+}
+
+/// Asks for a complete reply, returned with its token counts (estimated when the provider doesn't
+/// report them), latency and finish reason.
+pub async fn make_request_to_ai(
+    state: &AppState,
+    options: &ReplyOptions,
+    history: &[ConvMessage],
+    earlier_user_turns: usize,
+    msg: &str,
+) -> Result<FinishedReply, AiErrorWrapper> {
+    let request = ai_request(state, options, history, earlier_user_turns, msg);
+
+    let cache = state
+        .response_cache()
+        .map(|cache| (cache, ResponseCache::key(&request)));
+
+    let mut reply = StreamedReply::start();
+    let response = match cache.and_then(|(cache, key)| cache.get(&key)) {
+        Some(hit) => hit,
+        None => {
+            let response =
+                call_provider(&state.settings, || state.ai_provider().generate(&request)).await?;
+            if let Some((cache, key)) = cache {
+                cache.insert(key, response.clone());
+            }
+            response
+        }
+    };
+
+    reply.absorb(&response);
+    Ok(reply.finish(msg))
+}
+
+/// Opens a streamed reply; only establishing the stream is retried, chunks are never replayed.
+pub async fn stream_request_to_ai(
+    state: &AppState,
+    options: &ReplyOptions,
+    history: &[ConvMessage],
+    earlier_user_turns: usize,
+    msg: &str,
+) -> Result<AiStream, AiErrorWrapper> {
+    let request = ai_request(state, options, history, earlier_user_turns, msg);
+
+    let Some(cache) = state.response_cache() else {
+        return call_provider(&state.settings, || {
+            state.ai_provider().generate_stream(&request)
+        })
+        .await;
+    };
+
+    let key = ResponseCache::key(&request);
+    if let Some(hit) = cache.get(&key) {
+        return Ok(Box::pin(stream::iter([Ok(hit)])));
     }
 
-    Ok(StatusCode::NO_CONTENT)
+    let chunks = call_provider(&state.settings, || {
+        state.ai_provider().generate_stream(&request)
+    })
+    .await?;
+    Ok(caching_stream(chunks, cache.clone(), key))
 }
 
-#[debug_handler]
-pub async fn delete_message_by_id(
+/// Passes the chunks through and caches the assembled reply once the stream ends cleanly. A
+/// stream that errors, or is dropped because the user stopped it, leaves nothing behind.
+fn caching_stream(chunks: AiStream, cache: ResponseCache, key: CacheKey) -> AiStream {
+    Box::pin(stream::unfold(
+        (chunks, Some(AiResult::default())),
+        move |(mut chunks, mut assembled)| {
+            let cache = cache.clone();
+            async move {
+                match chunks.next().await {
+                    Some(Ok(chunk)) => {
+                        if let Some(assembled) = assembled.as_mut() {
+                            assembled.text.push_str(&chunk.text);
+                            assembled.usage = chunk.usage.or(assembled.usage);
+                            assembled.finish_reason = chunk
+                                .finish_reason
+                                .clone()
+                                .or(assembled.finish_reason.take());
+                        }
+                        Some((Ok(chunk), (chunks, assembled)))
+                    }
+                    Some(Err(e)) => Some((Err(e), (chunks, None))),
+                    None => {
+                        if let Some(assembled) = assembled {
+                            cache.insert(key, assembled);
+                        }
+                        None
+                    }
+                }
+            }
+        },
+    ))
+}
+
+fn ai_request(
+    state: &AppState,
+    options: &ReplyOptions,
+    history: &[ConvMessage],
+    earlier_user_turns: usize,
+    msg: &str,
+) -> AiRequest {
+    let (system, turns) = to_turns(
+        options.system_prompt.as_deref(),
+        history,
+        msg,
+        state.settings.system_reminder_every,
+        earlier_user_turns,
+    );
+
+    AiRequest {
+        model: options.model.clone(),
+        system,
+        turns,
+        params: options.params,
+        images: options.images.clone(),
+    }
+}
+
+/// Lets clients retry a create safely: a repeated key gets the first response back instead of
+/// a second conversation or message.
+pub static IDEMPOTENCY_KEY: HeaderName = HeaderName::from_static("idempotency-key");
+
+/// Set on responses replayed for a repeated `Idempotency-Key`.
+pub static IDEMPOTENT_REPLAYED: HeaderName = HeaderName::from_static("idempotent-replayed");
+
+const MAX_IDEMPOTENCY_KEY_LEN: usize = 255;
+
+pub async fn create_conversation(
     Extension(user_data): Extension<TokenClaims>,
     State(state): State<Arc<AppState>>,
-    Path((conversation_id, message_id)): Path<(i64, i64)>,
-) -> Result<StatusCode, ValidationError> {
-    let conversation_exists =
-        sqlx::query_scalar::<_, i64>("SELECT 1 FROM conversations WHERE id = ?1 AND user_id = ?2")
-            .bind(conversation_id)
-            .bind(user_data.user_id)
-            .fetch_optional(&state.chat_db)
-            .await
-            .map_err(|e| ValidationError {
-                error: "Database check failed".to_string(),
-                details: vec![ValidationDetail {
-                    field: "conversation_id".to_string(),
-                    messages: vec![format!("Conversation check failed: {}", e)],
-                }],
-            })?;
-
-    if conversation_exists.is_none() {
-        return Err(ValidationError {
-            error: "Conversation not found or unauthorized".to_string(),
-            details: vec![ValidationDetail {
-                field: "conversation_id".to_string(),
-                messages: vec!["No conversation with this ID for the current user.".to_string()],
-            }],
-        });
+    headers: HeaderMap,
+    payload: Option<Json<NewConversation>>,
+) -> Result<Response, AppError> {
+    let Json(payload) = payload.unwrap_or_default();
+    payload.validate()?;
+
+    let key = idempotency_key(&headers)?;
+    let limit = conversation_limit(&state, user_data.user_id).await?;
+
+    // Claimed, used and completed in one transaction: any failure leaves the key unclaimed, and a
+    // concurrent retry waits for the write lock and then replays the committed response
+    let mut tx = state.db.begin().await?;
+
+    if let Some(key) = key.as_deref() {
+        let request_hash = hash_token(payload.system_prompt.as_deref().unwrap_or_default());
+        let claim = claim_idempotency_key(
+            user_data.user_id,
+            key,
+            "POST /conversations",
+            &request_hash,
+            state.settings.idempotency_ttl_secs,
+            &mut tx,
+        )
+        .await?;
+
+        match claim {
+            IdempotencyClaim::New => {}
+            IdempotencyClaim::Replay { status, body } => {
+                return Ok(replayed_response(status, body));
+            }
+            refused => return Err(refused_idempotency_key(&refused).into()),
+        }
     }
 
-    let result = sqlx::query("DELETE FROM messages WHERE conversation_id = ?1 AND timestamp = ?2")
-        .bind(conversation_id)
-        .bind(message_id)
-        .execute(&state.chat_db)
-        .await
-        .map_err(|e| ValidationError {
-            error: "Message deletion failed".to_string(),
-            details: vec![ValidationDetail {
-                field: "message_id".to_string(),
-                messages: vec![format!("Failed to delete message: {}", e)],
-            }],
-        })?;
+    let r = insert_conversation(
+        &mut *tx,
+        user_data.user_id,
+        &clean_title("New chat", &state.settings),
+        clean_system_prompt(payload.system_prompt.as_deref(), &state.settings),
+        limit,
+    )
+    .await?
+    .ok_or_else(|| (StatusCode::CONFLICT, conversation_limit_error(limit)))?;
 
-    if result.rows_affected() == 0 {
-        return Err(ValidationError {
-            error: "Message not found".to_string(),
-            details: vec![ValidationDetail {
-                field: "message_id".to_string(),
-                messages: vec!["No message with this ID in the conversation.".to_string()],
-            }],
-        });
+    if let Some(key) = key.as_deref() {
+        let body = serde_json::to_string(&r).map_err(|e| {
+            AppError::internal(
+                "Internal error",
+                "conversation",
+                format!("Failed to serialize: {}", e),
+            )
+        })?;
+        complete_idempotency_key(
+            user_data.user_id,
+            key,
+            StatusCode::OK.as_u16(),
+            &body,
+            &mut *tx,
+        )
+        .await?;
     }
 
-    Ok(StatusCode::NO_CONTENT)
+    tx.commit().await?;
+
+    Ok(Json(r).into_response())
 }
 
-#[derive(Deserialize)]
-pub struct PaginationParams {
-    pub page: Option<u32>,
-    pub limit: Option<u32>,
+/// How many open conversations the user may have, 0 for no limit.
+async fn conversation_limit(state: &AppState, user_id: i64) -> Result<i64, sqlx::Error> {
+    Ok(get_conversation_limit(user_id, &state.db)
+        .await?
+        .unwrap_or(state.settings.max_conversations))
 }
 
-pub async fn get_conversation_messages_by_id(
+/// Creates a conversation, or returns `None` when the user already has `limit` open ones. The
+/// count is checked by the insert itself, so concurrent creates can't overshoot the limit.
+async fn insert_conversation(
+    exec: impl SqliteExecutor<'_>,
+    user_id: i64,
+    title: &str,
+    system_prompt: Option<String>,
+    limit: i64,
+) -> Result<Option<Conversation>, sqlx::Error> {
+    let now = Utc::now().timestamp();
+    sqlx::query_as(
+        "INSERT INTO conversations (user_id, title, created_at, updated_at, system_prompt)
+SELECT ?1, ?2, ?3, ?3, ?4
+WHERE ?5 <= 0 OR (SELECT COUNT(*) FROM conversations WHERE user_id = ?1 AND archived_at IS NULL) < ?5
+RETURNING *",
+    )
+    .bind(user_id)
+    .bind(title)
+    .bind(now)
+    .bind(system_prompt)
+    .bind(limit)
+    .fetch_optional(exec)
+    .await
+}
+
+/// Creates a conversation from a chat exported elsewhere, all of its messages in one transaction.
+/// Messages keep the order they're sent in. One without a timestamp takes the timestamp of the
+/// message before it (the first given one, or now, at the start); given timestamps may not go
+/// backwards or lie in the future.
+pub async fn import_conversation(
     Extension(user_data): Extension<TokenClaims>,
     State(state): State<Arc<AppState>>,
-    Path(conversation_id): Path<i64>,
-    Query(params): Query<PaginationParams>,
-) -> Result<Json<Vec<ConvMessage>>, ValidationError> {
-    let page = params.page.unwrap_or(1);
-    let limit = params.limit.unwrap_or(10);
+    Json(payload): Json<ImportConversation>,
+) -> Result<Json<ImportedConversation>, AppError> {
+    payload.validate()?;
+    let timestamps = check_imported_messages(&state, user_data.user_id, &payload.messages)?;
 
-    if page == 0 || limit == 0 {
-        return Err(ValidationError {
-            error: "Invalid pagination parameters".into(),
-            details: vec![
-                ValidationDetail {
-                    field: "page".into(),
-                    messages: if page == 0 { vec!["Page must be greater than 0".into()] } else { vec![] },
-                },
-                ValidationDetail {
-                    field: "limit".into(),
-                    messages: if limit == 0 { vec!["Limit must be greater than 0".into()] } else { vec![] },
-                },
-            ],
+    let limit = conversation_limit(&state, user_data.user_id).await?;
+    let title = clean_title(&payload.title, &state.settings);
+
+    let mut tx = state.db.begin().await?;
+    let conversation = insert_conversation(&mut *tx, user_data.user_id, &title, None, limit)
+        .await?
+        .ok_or_else(|| (StatusCode::CONFLICT, conversation_limit_error(limit)))?;
+
+    let mut query = QueryBuilder::<Sqlite>::new(
+        "INSERT INTO messages (conversation_id, role, content, timestamp, token_count) ",
+    );
+    query.push_values(
+        payload.messages.iter().zip(timestamps),
+        |mut row, (message, timestamp)| {
+            let content = normalize_text(&message.content, state.settings.normalize_unicode);
+            let token_count = estimate_tokens(&content);
+            row.push_bind(conversation.id)
+                .push_bind(message.role.clone())
+                .push_bind(content)
+                .push_bind(timestamp)
+                .push_bind(token_count);
+        },
+    );
+    query.push(" RETURNING *");
+    let mut messages: Vec<ConvMessage> = query.build_query_as().fetch_all(&mut *tx).await?;
+    tx.commit().await?;
+
+    messages.sort_by_key(|message| (message.timestamp, message.id));
+    Ok(Json(ImportedConversation {
+        conversation,
+        messages,
+    }))
+}
+
+const IMPORTABLE_ROLES: [&str; 3] = ["user", "assistant", "system"];
+
+/// Checks every imported message, reporting all problems at once, and returns the timestamps
+/// they are stored with.
+fn check_imported_messages(
+    state: &AppState,
+    user_id: i64,
+    messages: &[ImportedMessage],
+) -> Result<Vec<i64>, ValidationError> {
+    let now = Utc::now().timestamp();
+    let max_bytes = state.settings.import_max_message_bytes;
+    let mut details = Vec::new();
+    let mut problem = |index: usize, field: &str, message: String| {
+        details.push(ValidationDetail {
+            field: format!("messages[{}].{}", index, field),
+            messages: vec![message],
         });
-    }
+    };
 
-    let offset = (page - 1) * limit;
+    let mut previous = messages
+        .iter()
+        .find_map(|message| message.timestamp)
+        .unwrap_or(now);
+    let mut timestamps = Vec::with_capacity(messages.len());
+    for (index, message) in messages.iter().enumerate() {
+        if !IMPORTABLE_ROLES.contains(&message.role.as_str()) {
+            problem(
+                index,
+                "role",
+                format!("Role must be one of: {}", IMPORTABLE_ROLES.join(", ")),
+            );
+        }
+        if message.content.trim().is_empty() {
+            problem(index, "content", "Content must not be empty".to_string());
+        } else if message.content.len() > max_bytes {
+            problem(
+                index,
+                "content",
+                format!("Messages are limited to {} bytes.", max_bytes),
+            );
+        } else if message.role == "user" {
+            // Held to the same checks as a prompt sent live, reported with their own wording
+            let checked = check_prompt_length(&state.settings, "content", &message.content)
+                .and_then(|()| moderate_prompt(state, user_id, None, &message.content));
+            if let Err(e) = checked {
+                for message in e.details.into_iter().flat_map(|detail| detail.messages) {
+                    problem(index, "content", message);
+                }
+            }
+        }
 
-    let result = sqlx::query_as::<_, ConvMessage>(
-        "SELECT * FROM messages WHERE conversation_id = ? LIMIT ? OFFSET ?",
-    )
-    .bind(conversation_id)
-    .bind(limit)
-    .bind(offset)
-    .fetch_all(&state.chat_db)
-    .await;
+        match message.timestamp {
+            Some(timestamp) if timestamp < previous || timestamp > now => problem(
+                index,
+                "timestamp",
+                "Timestamps must not go backwards or lie in the future".to_string(),
+            ),
+            Some(timestamp) => previous = timestamp,
+            None => {}
+        }
+        timestamps.push(previous);
+    }
 
-    match result {
-        Ok(messages) => Ok(Json(messages)),
-        Err(e) => Err(ValidationError {
-            error: "Database query failed".into(),
-            details: vec![ValidationDetail {
-                field: "database".into(),
-                messages: vec![format!("Failed to fetch conversation messages: {}", e)],
-            }],
-        }),
+    if details.is_empty() {
+        Ok(timestamps)
+    } else {
+        Err(ValidationError {
+            error: "Validation failed".to_string(),
+            details,
+        })
     }
 }
 
-#[debug_handler]
-pub async fn post_user_message(
-    State(state): State<Arc<AppState>>,
-    ws: WebSocketUpgrade,
-    Query(params): Query<UserMessage>,
-) -> Response {
-    println!("there");
-    ws.on_upgrade(move |socket| handle_user_message(socket, params, state))
+fn conversation_limit_error(limit: i64) -> ValidationError {
+    ValidationError {
+        error: "Conversation limit reached".to_string(),
+        details: vec![ValidationDetail {
+            field: "conversations".to_string(),
+            messages: vec![format!(
+                "At most {} conversations can be open at once; archive or delete one to start another.",
+                limit
+            )],
+        }],
+    }
 }
 
-async fn handle_user_message(mut socket: WebSocket, params: UserMessage, state: Arc<AppState>) {
-    while let Some(msg) = socket.recv().await {
-        if let Ok(msg) = msg {
-            let r = insert_chat_message_to_db(
-                "user", // shitty code
-                params.conversation_id,
-                msg.to_text().unwrap(),
-                &state.chat_db,
-            )
-            .await;
+/// The request's `Idempotency-Key`, if it sent one.
+fn idempotency_key(headers: &HeaderMap) -> Result<Option<String>, ValidationError> {
+    let Some(value) = headers.get(&IDEMPOTENCY_KEY) else {
+        return Ok(None);
+    };
 
-            if let Err(e) = r {
-                let _ = socket.send(e.into()).await;
-            }
+    match value.to_str() {
+        Ok(key) if is_valid_idempotency_key(key) => Ok(Some(key.to_string())),
+        _ => Err(invalid_idempotency_key("Idempotency-Key")),
+    }
+}
 
-            let key = env::var("GEMINI_API_KEY").expect("API key was not provided");
-            let client = Gemini::new(key);
-            let gemini_response = async {
-                let response = client
-                    .generate_content()
-                    .with_user_message(msg.to_text().unwrap())
-                    .execute()
-                    .await;
+fn is_valid_idempotency_key(key: &str) -> bool {
+    !key.is_empty()
+        && key.len() <= MAX_IDEMPOTENCY_KEY_LEN
+        && key.bytes().all(|b| b.is_ascii_graphic())
+}
 
-                match response {
-                    Ok(_) => {}
-                    Err(e) => {
-                        let json_start = e.to_string().find("{").expect("Not a pure json");
-                        let new_e: GeminiApiErrorWrapper =
-                            serde_json::from_str(&e.to_string()[json_start..])
-                                .expect("Incorrect GeminiApiError json");
+fn invalid_idempotency_key(field: &str) -> ValidationError {
+    ValidationError {
+        error: "Validation failed".to_string(),
+        details: vec![ValidationDetail {
+            field: field.to_string(),
+            messages: vec![format!(
+                "Must be 1 to {} printable ASCII characters",
+                MAX_IDEMPOTENCY_KEY_LEN
+            )],
+        }],
+    }
+}
 
-                        let stringified = serde_json::to_string(&new_e).unwrap_or_else(|_| {
-                            "{\"error\": \"Internal server error\"}".to_string() //shit
-                        });
+/// Claims the key for `endpoint`, returning the stored status and body when the request was
+/// already handled. Without a key there is nothing to claim.
+async fn claim_or_replay(
+    state: &AppState,
+    user_id: i64,
+    key: Option<&str>,
+    endpoint: &str,
+    request_hash: &str,
+) -> Result<Option<(u16, String)>, AppError> {
+    let Some(key) = key else {
+        return Ok(None);
+    };
 
-                        return Err(stringified);
-                    }
-                }
+    let mut conn = state.db.acquire().await?;
+    let claim = claim_idempotency_key(
+        user_id,
+        key,
+        endpoint,
+        request_hash,
+        state.settings.idempotency_ttl_secs,
+        &mut conn,
+    )
+    .await?;
 
-                let response = response.unwrap();
+    match claim {
+        IdempotencyClaim::New => Ok(None),
+        IdempotencyClaim::Replay { status, body } => Ok(Some((status, body))),
+        refused => Err(refused_idempotency_key(&refused).into()),
+    }
+}
 
-                enum ResponseStatus {
-                    NotReady,
-                    Ready,
-                }
+fn refused_idempotency_key(claim: &IdempotencyClaim) -> (StatusCode, ValidationError) {
+    let (status, error, message) = match claim {
+        IdempotencyClaim::Mismatch => (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "Idempotency key reused",
+            "This key was already used for a different request.",
+        ),
+        _ => (
+            StatusCode::CONFLICT,
+            "Request in progress",
+            "A request with this key is still being handled; retry once it has finished.",
+        ),
+    };
 
-                Ok((ResponseStatus::Ready, response))
-            };
+    (
+        status,
+        ValidationError {
+            error: error.to_string(),
+            details: vec![ValidationDetail {
+                field: "idempotency_key".to_string(),
+                messages: vec![message.to_string()],
+            }],
+        },
+    )
+}
 
-            let typing = async {
-                loop {
-                    tokio::time::sleep(Duration::from_secs(1)).await;
-                    let _ = socket.send("typing".into()).await;
-                }
-            };
+fn replayed_response(status: u16, body: String) -> Response {
+    let status = StatusCode::from_u16(status).unwrap_or(StatusCode::OK);
+    (
+        status,
+        [
+            (
+                header::CONTENT_TYPE,
+                HeaderValue::from_static("application/json"),
+            ),
+            (
+                IDEMPOTENT_REPLAYED.clone(),
+                HeaderValue::from_static("true"),
+            ),
+        ],
+        body,
+    )
+        .into_response()
+}
 
-            let result: Result<String, Message> = tokio::select! {
-                res = gemini_response => match res {
-                    Ok((_, response)) => {
-                        let response_text = response.text();
-                        Ok(response_text)
-                    },
-                    Err(e) => Err(e.into()),
-                },
-                never = typing => match never {}
-            };
+/// Records the response a claimed key replays from now on.
+async fn complete_key(
+    state: &AppState,
+    user_id: i64,
+    key: Option<&str>,
+    status: StatusCode,
+    body: &str,
+) {
+    if let Some(key) = key
+        && let Err(e) =
+            complete_idempotency_key(user_id, key, status.as_u16(), body, &state.db).await
+    {
+        tracing::error!(error = %e, user_id, "storing the idempotent response failed");
+    }
+}
 
-            match result {
-                Ok(response_text) => {
-                    let r = insert_chat_message_to_db(
-                        "assistant",
-                        params.conversation_id,
-                        &response_text,
-                        &state.chat_db,
-                    )
-                    .await;
+/// Frees a claimed key after the request failed, so a retry is handled afresh.
+async fn release_key(state: &AppState, user_id: i64, key: Option<&str>) {
+    if let Some(key) = key
+        && let Err(e) = release_idempotency_key(user_id, key, &state.db).await
+    {
+        tracing::error!(error = %e, user_id, "releasing an idempotency key failed");
+    }
+}
 
-                    if let Err(e) = r {
-                        let _ = socket.send(e.into()).await;
-                    }
+/// Keys for chat messages are scoped to the conversation, shared by the streaming POST and sockets.
+fn message_endpoint(conversation_id: i64) -> String {
+    format!("POST /conversations/{}/messages", conversation_id)
+}
 
-                    let _ = socket.send(Message::from(response_text)).await;
-                }
-                Err(err_msg) => {
-                    let _ = socket.send(err_msg).await;
-                }
-            }
+/// What a keyed chat message replays: the stored prompt and the reply it got.
+#[derive(Serialize, Deserialize)]
+struct Exchange {
+    prompt: ConvMessage,
+    reply: ConvMessage,
+}
+
+async fn complete_exchange(
+    state: &AppState,
+    user_id: i64,
+    key: Option<&str>,
+    prompt: ConvMessage,
+    reply: ConvMessage,
+) {
+    if key.is_none() {
+        return;
+    }
+    if let Ok(body) = serde_json::to_string(&Exchange { prompt, reply }) {
+        complete_key(state, user_id, key, StatusCode::OK, &body).await;
+    }
+}
+
+/// The frames the first request's socket got: the stored prompt, the reply as one chunk, the stored
+/// reply and the done frame.
+async fn replay_exchange(socket: &mut WebSocket, body: &str) {
+    let exchange = match serde_json::from_str::<Exchange>(body) {
+        Ok(exchange) => exchange,
+        Err(e) => {
+            tracing::error!(error = %e, "stored idempotent exchange is unreadable");
+            let _ = socket
+                .send(Message::from("{\"error\": \"Internal server error\"}"))
+                .await;
+            return;
+        }
+    };
+
+    let _ = socket.send(stored_message_frame(&exchange.prompt)).await;
+    let _ = socket
+        .send(Message::from(exchange.reply.content.clone()))
+        .await;
+    let _ = socket.send(stored_message_frame(&exchange.reply)).await;
+    let _ = socket.send(Message::from(DONE_FRAME)).await;
+}
+
+/// The events the first request streamed, with the whole reply as a single chunk.
+fn replayed_exchange_events(body: &str) -> BoxStream<'static, Result<Event, Infallible>> {
+    let (mut events, receiver) = mpsc::channel(3);
+    match serde_json::from_str::<Exchange>(body) {
+        Ok(exchange) => {
+            let _ = events.try_send(Ok(Event::default()
+                .event("prompt")
+                .data(stored_message_json(&exchange.prompt))));
+            let _ = events.try_send(Ok(Event::default().data(exchange.reply.content.clone())));
+            let _ = events.try_send(Ok(Event::default()
+                .event("done")
+                .data(stored_message_json(&exchange.reply))));
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "stored idempotent exchange is unreadable");
+            let _ = events.try_send(Ok(Event::default()
+                .event("error")
+                .data("{\"error\": \"Internal server error\"}")));
+        }
+    }
+    receiver.boxed()
+}
+
+#[derive(Deserialize)]
+pub struct ConversationListParams {
+    #[serde(default)]
+    pub include_archived: bool,
+    pub page: Option<u32>,
+    pub limit: Option<u32>,
+    // Only conversations carrying the tag with this name (any case)
+    pub tag: Option<String>,
+}
+
+/// Conversations listed per page by default; enough that most accounts fit on one.
+const DEFAULT_CONVERSATION_PAGE_SIZE: u32 = 50;
+
+/// The caller's conversations one page at a time: pinned ones first, then those placed by hand in
+/// their `sort_order`, then the rest most recently active first. `?tag=` narrows the list to one tag.
+#[debug_handler]
+pub async fn get_user_conversations(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<ConversationListParams>,
+) -> Result<Json<ConversationPage>, AppError> {
+    let page = params.page.unwrap_or(1);
+    let limit = params.limit.unwrap_or(DEFAULT_CONVERSATION_PAGE_SIZE);
+    validate_pagination(page, limit)?;
+
+    let total: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM conversations WHERE user_id = ?1 AND (?2 OR archived_at IS NULL)
+AND (?3 IS NULL OR id IN (SELECT ct.conversation_id FROM conversation_tags ct JOIN tags t ON t.id = ct.tag_id
+    WHERE t.user_id = ?1 AND t.name = ?3))",
+    )
+    .bind(user_data.user_id)
+    .bind(params.include_archived)
+    .bind(params.tag.as_deref())
+    .fetch_one(&state.db)
+    .await?;
+
+    let mut items: Vec<Conversation> = sqlx::query_as(
+        "SELECT * FROM conversations where user_id = ?1 AND (?2 OR archived_at IS NULL)
+AND (?5 IS NULL OR id IN (SELECT ct.conversation_id FROM conversation_tags ct JOIN tags t ON t.id = ct.tag_id
+    WHERE t.user_id = ?1 AND t.name = ?5))
+ORDER BY pinned DESC, sort_order IS NULL, sort_order, updated_at DESC, id DESC LIMIT ?3 OFFSET ?4",
+    )
+    .bind(user_data.user_id)
+    .bind(params.include_archived)
+    .bind(limit)
+    .bind(i64::from(page - 1) * i64::from(limit))
+    .bind(params.tag.as_deref())
+    .fetch_all(&state.db)
+    .await?;
+    load_tags(&mut items, &state.db).await?;
+
+    Ok(Json(ConversationPage {
+        items,
+        page,
+        limit,
+        total,
+        total_pages: (total + i64::from(limit) - 1) / i64::from(limit),
+    }))
+}
+
+#[derive(Deserialize)]
+pub struct ConversationID {
+    pub id: i64,
+}
+
+/// Whether `conversation_id` exists and belongs to `user_id`.
+pub(crate) async fn owns_conversation(
+    exec: impl SqliteExecutor<'_>,
+    conversation_id: i64,
+    user_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let owned =
+        sqlx::query_scalar::<_, i64>("SELECT 1 FROM conversations WHERE id = ?1 AND user_id = ?2")
+            .bind(conversation_id)
+            .bind(user_id)
+            .fetch_optional(exec)
+            .await?;
+
+    Ok(owned.is_some())
+}
+
+// Someone else's conversation is reported as missing so ids can't be probed
+pub(crate) fn conversation_not_found(field: &str) -> ValidationError {
+    ValidationError {
+        error: "Conversation not found".to_string(),
+        details: vec![ValidationDetail {
+            field: field.to_string(),
+            messages: vec!["No conversation with this ID for the current user.".to_string()],
+        }],
+    }
+}
+
+fn message_not_found() -> ValidationError {
+    ValidationError {
+        error: "Message not found".to_string(),
+        details: vec![ValidationDetail {
+            field: "message_id".to_string(),
+            messages: vec!["No message with this ID in the conversation.".to_string()],
+        }],
+    }
+}
+
+pub async fn get_user_conversations_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> Result<Json<Conversation>, AppError> {
+    let r: Option<Conversation> =
+        sqlx::query_as("SELECT * FROM conversations WHERE user_id = (?1) AND id = (?2)")
+            .bind(user_data.user_id)
+            .bind(id)
+            .fetch_optional(&state.db)
+            .await?;
+
+    let Some(mut r) = r else {
+        return Err((StatusCode::NOT_FOUND, conversation_not_found("id")).into());
+    };
+
+    load_conversation_tags(&mut r, &state.db).await?;
+    Ok(Json(r))
+}
+
+pub async fn update_conversation_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Json(payload): Json<Title>,
+) -> Result<Json<Conversation>, AppError> {
+    payload.validate()?;
+
+    if !owns_conversation(&state.db, id, user_data.user_id).await? {
+        return Err((StatusCode::NOT_FOUND, conversation_not_found("id")).into());
+    }
+
+    // expected_version or expected_updated_at guard against lost updates; without either the last
+    // write wins as before
+    let updated: Option<Conversation> = sqlx::query_as(
+        "UPDATE conversations SET title = ?1, updated_at = ?2, version = version + 1
+WHERE id = ?3 AND user_id = ?4 AND (?5 IS NULL OR version = ?5) AND (?6 IS NULL OR updated_at = ?6)
+RETURNING *",
+    )
+    .bind(clean_title(&payload.title, &state.settings))
+    .bind(chrono::Utc::now().timestamp())
+    .bind(id)
+    .bind(user_data.user_id)
+    .bind(payload.expected_version)
+    .bind(payload.expected_updated_at)
+    .fetch_optional(&state.db)
+    .await?;
+
+    let mut updated = updated.ok_or_else(|| conversation_modified(payload.expected_version))?;
+
+    load_conversation_tags(&mut updated, &state.db).await?;
+    Ok(Json(updated))
+}
+
+/// The generation defaults replies in this conversation start from.
+pub async fn get_conversation_settings_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> Result<Json<GenerationParams>, AppError> {
+    if !owns_conversation(&state.db, id, user_data.user_id).await? {
+        return Err((StatusCode::NOT_FOUND, conversation_not_found("id")).into());
+    }
+
+    Ok(Json(get_conversation_settings(id, &state.db).await?))
+}
+
+/// Replaces the conversation's generation defaults; unset fields clear the saved value.
+pub async fn update_conversation_settings_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Json(payload): Json<GenerationParams>,
+) -> Result<Json<GenerationParams>, AppError> {
+    payload.validate()?;
+
+    if !owns_conversation(&state.db, id, user_data.user_id).await? {
+        return Err((StatusCode::NOT_FOUND, conversation_not_found("id")).into());
+    }
+
+    sqlx::query(
+        "INSERT INTO conversation_settings (conversation_id, temperature, top_p, max_output_tokens)
+VALUES (?1, ?2, ?3, ?4)
+ON CONFLICT (conversation_id) DO UPDATE SET
+    temperature = excluded.temperature,
+    top_p = excluded.top_p,
+    max_output_tokens = excluded.max_output_tokens",
+    )
+    .bind(id)
+    .bind(payload.temperature)
+    .bind(payload.top_p)
+    .bind(payload.max_output_tokens)
+    .execute(&state.db)
+    .await?;
+
+    Ok(Json(payload))
+}
+
+/// Sets the conversation's system prompt, or removes it when the prompt is null or blank.
+pub async fn update_system_prompt_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Json(payload): Json<SystemPrompt>,
+) -> Result<Json<Conversation>, AppError> {
+    payload.validate()?;
+
+    if !owns_conversation(&state.db, id, user_data.user_id).await? {
+        return Err((StatusCode::NOT_FOUND, conversation_not_found("id")).into());
+    }
+
+    let updated: Option<Conversation> = sqlx::query_as(
+        "UPDATE conversations SET system_prompt = ?1, updated_at = ?2, version = version + 1
+WHERE id = ?3 AND user_id = ?4 AND (?5 IS NULL OR version = ?5) AND (?6 IS NULL OR updated_at = ?6)
+RETURNING *",
+    )
+    .bind(clean_system_prompt(
+        payload.system_prompt.as_deref(),
+        &state.settings,
+    ))
+    .bind(Utc::now().timestamp())
+    .bind(id)
+    .bind(user_data.user_id)
+    .bind(payload.expected_version)
+    .bind(payload.expected_updated_at)
+    .fetch_optional(&state.db)
+    .await?;
+
+    let mut updated = updated.ok_or_else(|| conversation_modified(payload.expected_version))?;
+
+    load_conversation_tags(&mut updated, &state.db).await?;
+    Ok(Json(updated))
+}
+
+/// A conditional update found the conversation changed; reported on the precondition the client sent.
+fn conversation_modified(expected_version: Option<i64>) -> AppError {
+    AppError::new(
+        StatusCode::CONFLICT,
+        "Conversation was modified",
+        if expected_version.is_some() {
+            "expected_version"
+        } else {
+            "expected_updated_at"
+        },
+        "The conversation changed since it was read; reload and retry.",
+    )
+}
+
+// Titles we store ourselves go through the same rule as the ones users send: no control
+// characters, no surrounding whitespace and at most MAX_TITLE_CHARS characters
+fn clean_title(title: &str, settings: &Settings) -> String {
+    let title: String = title.chars().filter(|c| !c.is_control()).collect();
+    normalize_text(title.trim(), settings.normalize_unicode)
+        .chars()
+        .take(MAX_TITLE_CHARS as usize)
+        .collect()
+}
+
+// A blank prompt is stored as none, so it doesn't become an empty system instruction
+fn clean_system_prompt(prompt: Option<&str>, settings: &Settings) -> Option<String> {
+    prompt
+        .map(|prompt| normalize_text(prompt.trim(), settings.normalize_unicode))
+        .filter(|prompt| !prompt.is_empty())
+}
+
+pub async fn archive_conversation_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> Result<Json<Conversation>, AppError> {
+    let now = Utc::now().timestamp();
+
+    // RETURNING yields no row for a missing or foreign conversation
+    let mut archived: Conversation = sqlx::query_as(
+        "UPDATE conversations SET archived_at = ?1, updated_at = ?1, version = version + 1 WHERE id = ?2 AND user_id = ?3 RETURNING *",
+    )
+    .bind(now)
+    .bind(id)
+    .bind(user_data.user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| (StatusCode::NOT_FOUND, conversation_not_found("id")))?;
+
+    load_conversation_tags(&mut archived, &state.db).await?;
+    Ok(Json(archived))
+}
+
+/// Pins or unpins a conversation, toggling when the body doesn't say which. Recency is left alone so
+/// unpinning puts the conversation back where it was.
+pub async fn pin_conversation_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    payload: Option<Json<Pin>>,
+) -> Result<Json<Conversation>, AppError> {
+    let Json(payload) = payload.unwrap_or_default();
+
+    let mut pinned: Conversation = sqlx::query_as(
+        "UPDATE conversations SET pinned = COALESCE(?1, NOT pinned), version = version + 1 WHERE id = ?2 AND user_id = ?3 RETURNING *",
+    )
+    .bind(payload.pinned)
+    .bind(id)
+    .bind(user_data.user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| (StatusCode::NOT_FOUND, conversation_not_found("id")))?;
+
+    load_conversation_tags(&mut pinned, &state.db).await?;
+    Ok(Json(pinned))
+}
+
+pub async fn position_conversation_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+    Json(payload): Json<Position>,
+) -> Result<Json<Conversation>, AppError> {
+    let mut placed: Conversation = sqlx::query_as(
+        "UPDATE conversations SET sort_order = ?1, version = version + 1 WHERE id = ?2 AND user_id = ?3 RETURNING *",
+    )
+    .bind(payload.sort_order)
+    .bind(id)
+    .bind(user_data.user_id)
+    .fetch_optional(&state.db)
+    .await?
+    .ok_or_else(|| (StatusCode::NOT_FOUND, conversation_not_found("id")))?;
+
+    load_conversation_tags(&mut placed, &state.db).await?;
+    Ok(Json(placed))
+}
+
+pub async fn delete_conversation_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    let result = sqlx::query("DELETE FROM conversations WHERE id = ?1 AND user_id = ?2")
+        .bind(id)
+        .bind(user_data.user_id)
+        .execute(&state.db)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, conversation_not_found("id")).into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Deletes every listed conversation the caller owns and reports the ids that were skipped.
+pub async fn bulk_delete_conversations(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<BulkDelete>,
+) -> Result<Json<BulkDeleteResult>, AppError> {
+    payload.validate()?;
+
+    let mut query = QueryBuilder::<Sqlite>::new("DELETE FROM conversations WHERE user_id = ");
+    query.push_bind(user_data.user_id).push(" AND id IN (");
+    let mut ids = query.separated(", ");
+    for id in &payload.ids {
+        ids.push_bind(id);
+    }
+    query.push(") RETURNING id");
+
+    let mut tx = state.db.begin().await?;
+    let deleted: Vec<i64> = query.build_query_scalar().fetch_all(&mut *tx).await?;
+    tx.commit().await?;
+
+    let mut not_found: Vec<i64> = payload
+        .ids
+        .iter()
+        .copied()
+        .filter(|id| !deleted.contains(id))
+        .collect();
+    not_found.sort_unstable();
+    not_found.dedup();
+
+    Ok(Json(BulkDeleteResult {
+        deleted: deleted.len(),
+        not_found,
+    }))
+}
+
+#[debug_handler]
+pub async fn delete_message_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path((conversation_id, message_id)): Path<(i64, i64)>,
+) -> Result<StatusCode, AppError> {
+    if !owns_conversation(&state.db, conversation_id, user_data.user_id).await? {
+        return Err((
+            StatusCode::NOT_FOUND,
+            conversation_not_found("conversation_id"),
+        )
+            .into());
+    }
+
+    let result = sqlx::query("DELETE FROM messages WHERE id = ?1 AND conversation_id = ?2")
+        .bind(message_id)
+        .bind(conversation_id)
+        .execute(&state.db)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, message_not_found()).into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Rewrites a user prompt and drops everything that followed it, returning the remaining history
+/// so the client can ask again from that point.
+#[debug_handler]
+pub async fn edit_message_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path((conversation_id, message_id)): Path<(i64, i64)>,
+    Json(payload): Json<EditMessage>,
+) -> Result<Json<Vec<ConvMessage>>, AppError> {
+    let mut tx = state.db.begin().await?;
+
+    if !owns_conversation(&mut *tx, conversation_id, user_data.user_id).await? {
+        return Err((
+            StatusCode::NOT_FOUND,
+            conversation_not_found("conversation_id"),
+        )
+            .into());
+    }
+
+    let message: Option<(String, i64)> = sqlx::query_as(
+        "SELECT role, timestamp FROM messages WHERE id = ?1 AND conversation_id = ?2",
+    )
+    .bind(message_id)
+    .bind(conversation_id)
+    .fetch_optional(&mut *tx)
+    .await?;
+
+    let timestamp = match message {
+        Some((role, timestamp)) if role == "user" => timestamp,
+        Some(_) => {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "Message cannot be edited",
+                "message_id",
+                "Only user messages can be edited.",
+            ));
+        }
+        None => return Err((StatusCode::NOT_FOUND, message_not_found()).into()),
+    };
+
+    let content = normalize_text(&payload.content, state.settings.normalize_unicode);
+    check_prompt_length(&state.settings, "content", &content)?;
+    moderate_prompt(&state, user_data.user_id, Some(conversation_id), &content)?;
+
+    sqlx::query("UPDATE messages SET content = ?1, token_count = ?2 WHERE id = ?3")
+        .bind(&content)
+        .bind(estimate_tokens(&content))
+        .bind(message_id)
+        .execute(&mut *tx)
+        .await?;
+
+    sqlx::query(
+        "DELETE FROM messages WHERE conversation_id = ?1 AND (timestamp > ?2 OR (timestamp = ?2 AND id > ?3))",
+    )
+    .bind(conversation_id)
+    .bind(timestamp)
+    .bind(message_id)
+    .execute(&mut *tx)
+    .await?;
+
+    sqlx::query("UPDATE conversations SET updated_at = ?1 WHERE id = ?2")
+        .bind(Utc::now().timestamp())
+        .bind(conversation_id)
+        .execute(&mut *tx)
+        .await?;
+
+    let history: Vec<ConvMessage> = sqlx::query_as(
+        "SELECT * FROM messages WHERE conversation_id = ? ORDER BY timestamp ASC, id ASC",
+    )
+    .bind(conversation_id)
+    .fetch_all(&mut *tx)
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(Json(history))
+}
+
+#[debug_handler]
+pub async fn move_message_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path((conversation_id, message_id)): Path<(i64, i64)>,
+    Json(payload): Json<MoveMessage>,
+) -> Result<StatusCode, AppError> {
+    let mut tx = state.db.begin().await?;
+
+    for (field, id) in [
+        ("conversation_id", conversation_id),
+        ("target_conversation_id", payload.target_conversation_id),
+    ] {
+        if !owns_conversation(&mut *tx, id, user_data.user_id).await? {
+            return Err((StatusCode::NOT_FOUND, conversation_not_found(field)).into());
+        }
+    }
+
+    let now = Utc::now().timestamp();
+
+    // Re-stamped so the moved message sorts after the target's existing history
+    let result = sqlx::query(
+        "UPDATE messages SET conversation_id = ?1, timestamp = ?2 WHERE id = ?3 AND conversation_id = ?4",
+    )
+    .bind(payload.target_conversation_id)
+    .bind(now)
+    .bind(message_id)
+    .bind(conversation_id)
+    .execute(&mut *tx)
+    .await?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, message_not_found()).into());
+    }
+
+    sqlx::query("UPDATE conversations SET updated_at = ?1 WHERE id IN (?2, ?3)")
+        .bind(now)
+        .bind(conversation_id)
+        .bind(payload.target_conversation_id)
+        .execute(&mut *tx)
+        .await?;
+
+    tx.commit().await?;
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Replaces the last assistant reply with a fresh one generated from the same context.
+#[debug_handler]
+pub async fn regenerate_last_reply(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+) -> Result<Json<AiResponse>, AppError> {
+    if !owns_conversation(&state.db, conversation_id, user_data.user_id).await? {
+        return Err((
+            StatusCode::NOT_FOUND,
+            conversation_not_found("conversation_id"),
+        )
+            .into());
+    }
+
+    if let Some(budget) = exhausted_token_budget(&state, user_data.user_id).await? {
+        return Err((StatusCode::TOO_MANY_REQUESTS, token_budget_error(budget)).into());
+    }
+
+    // Takes the conversation's generation slot, so no prompt can land while the reply is redone
+    if state.start_generation(conversation_id).is_none() {
+        return Err((StatusCode::CONFLICT, generation_busy_error()).into());
+    }
+
+    let stored = regenerate(&state, user_data.user_id, conversation_id).await;
+    let end = match &stored {
+        Ok(_) => GenerationEnd::Done,
+        Err(_) => {
+            GenerationEnd::Failed("{\"error\": \"Regenerating the reply failed\"}".to_string())
+        }
+    };
+    state.finish_generation(conversation_id, end);
+
+    Ok(Json(AiResponse {
+        ai_response: stored?.content,
+    }))
+}
+
+async fn regenerate(
+    state: &AppState,
+    user_id: i64,
+    conversation_id: i64,
+) -> Result<ConvMessage, AppError> {
+    // The reply plus the window the chat handler would have replayed for its prompt
+    let mut history =
+        get_conversation_history(conversation_id, state.history_window() + 2, &state.db).await?;
+
+    let last_reply = history.pop();
+    let prompt = history.pop();
+    let (last_reply, prompt) = match (last_reply, prompt) {
+        (Some(reply), Some(prompt)) if reply.role == "assistant" && prompt.role == "user" => {
+            (reply, prompt)
+        }
+        _ => {
+            return Err(AppError::new(
+                StatusCode::BAD_REQUEST,
+                "Nothing to regenerate",
+                "conversation_id",
+                "The conversation must end with an assistant reply to a user message.",
+            ));
+        }
+    };
+
+    let earlier =
+        earlier_user_turns(state, conversation_id, history.first().or(Some(&prompt))).await?;
+    // Answered by the model that wrote the replaced reply, unless it has left the allowlist since
+    let options = ReplyOptions {
+        model: resolve_model(
+            &state.settings,
+            state.ai_provider(),
+            last_reply.model.as_deref(),
+            ModelInfo::default(),
+        )
+        .unwrap_or_else(|_| state.settings.gemini_model.clone()),
+        params: get_conversation_settings(conversation_id, &state.db).await?,
+        system_prompt: get_system_prompt(conversation_id, &state.db).await?,
+        images: Vec::new(),
+    };
+    let reply = make_request_to_ai(state, &options, &history, earlier, &prompt.content).await?;
+    record_token_usage(user_id, reply.total_tokens, &state.db).await?;
+
+    // The old reply is only dropped once a replacement exists, and only while it's still the
+    // newest message; an edit or delete in the meantime leaves nothing to replace
+    let mut tx = state.db.begin().await?;
+
+    let newest: Option<i64> =
+        sqlx::query_scalar("SELECT MAX(id) FROM messages WHERE conversation_id = ?")
+            .bind(conversation_id)
+            .fetch_one(&mut *tx)
+            .await?;
+    if newest != Some(last_reply.id) {
+        return Err(AppError::new(
+            StatusCode::CONFLICT,
+            "Conversation changed",
+            "conversation_id",
+            "The last reply changed while a new one was generated; nothing was replaced.",
+        ));
+    }
+
+    sqlx::query("DELETE FROM messages WHERE id = ?")
+        .bind(last_reply.id)
+        .execute(&mut *tx)
+        .await?;
+
+    let stored = store_reply(
+        state,
+        conversation_id,
+        &reply,
+        &options.model,
+        false,
+        &mut *tx,
+    )
+    .await?;
+
+    tx.commit().await?;
+
+    Ok(stored)
+}
+
+/// Sends a prompt and streams the reply as server-sent events: `prompt` with the stored prompt,
+/// unnamed events with each chunk, then `done` with the stored reply or `error` with what went
+/// wrong. A client that goes away mid-stream doesn't stop the reply; it's still stored.
+pub async fn stream_user_message(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    headers: HeaderMap,
+    Json(payload): Json<UserText>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, AppError> {
+    payload.validate()?;
+    let key = idempotency_key(&headers)?;
+
+    let text = normalize_text(&payload.msg, state.settings.normalize_unicode);
+    check_prompt_length(&state.settings, "msg", &text)?;
+    moderate_prompt(&state, user_data.user_id, Some(conversation_id), &text)?;
+
+    if !owns_conversation(&state.db, conversation_id, user_data.user_id).await? {
+        return Err((
+            StatusCode::NOT_FOUND,
+            conversation_not_found("conversation_id"),
+        )
+            .into());
+    }
+
+    let model = resolve_model(
+        &state.settings,
+        state.ai_provider(),
+        payload.model.as_deref(),
+        ModelInfo {
+            streaming: true,
+            vision: !payload.images.is_empty(),
+        },
+    )?;
+
+    let endpoint = message_endpoint(conversation_id);
+    let request_hash = hash_token(&text);
+    if let Some((_, body)) = claim_or_replay(
+        &state,
+        user_data.user_id,
+        key.as_deref(),
+        &endpoint,
+        &request_hash,
+    )
+    .await?
+    {
+        return Ok(
+            Sse::new(replayed_exchange_events(&body)).keep_alive(keep_alive(&state.settings))
+        );
+    }
+
+    let budget = match exhausted_token_budget(&state, user_data.user_id).await {
+        Ok(budget) => budget,
+        Err(e) => {
+            release_key(&state, user_data.user_id, key.as_deref()).await;
+            return Err(e.into());
+        }
+    };
+    if let Some(budget) = budget {
+        release_key(&state, user_data.user_id, key.as_deref()).await;
+        return Err((StatusCode::TOO_MANY_REQUESTS, token_budget_error(budget)).into());
+    }
+
+    // Shares the claim with chat sockets, which can follow this reply as it streams
+    let Some(generation) = state.start_generation(conversation_id) else {
+        release_key(&state, user_data.user_id, key.as_deref()).await;
+        return Err((StatusCode::CONFLICT, generation_busy_error()).into());
+    };
+
+    let prepared = async {
+        let (history, earlier, options) =
+            load_reply_context(&state, conversation_id, &model, payload.params).await?;
+        let prompt = insert_chat_message_to_db(
+            "user",
+            conversation_id,
+            &text,
+            estimate_tokens(&text),
+            &ReplyMeta::default(),
+            &state.db,
+        )
+        .await?;
+        Ok::<_, sqlx::Error>((history, earlier, options, prompt))
+    }
+    .await;
+
+    let (history, earlier, mut options, prompt) = match prepared {
+        Ok(prepared) => prepared,
+        Err(e) => {
+            let frame = database_error_json("preparing the reply failed", e);
+            state.finish_generation(conversation_id, GenerationEnd::Failed(frame));
+            release_key(&state, user_data.user_id, key.as_deref()).await;
+            return Err(AppError::internal(
+                "Database error",
+                "conversation_id",
+                "Could not store the message.",
+            ));
+        }
+    };
+    options.images = payload.images;
+
+    let reply = StreamedReply::start();
+    let stream = match stream_request_to_ai(&state, &options, &history, earlier, &text).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            let frame = serde_json::to_string(&e)
+                .unwrap_or_else(|_| "{\"error\": \"Internal server error\"}".to_string());
+            state.finish_generation(conversation_id, GenerationEnd::Failed(frame));
+            release_key(&state, user_data.user_id, key.as_deref()).await;
+            return Err(e.into());
+        }
+    };
+
+    let (mut events, body) = mpsc::channel(16);
+    let _ = events.try_send(Ok(Event::default()
+        .event("prompt")
+        .data(stored_message_json(&prompt))));
+
+    let heartbeat = keep_alive(&state.settings);
+    let relay = ReplyRelay {
+        state,
+        conversation_id,
+        user_id: user_data.user_id,
+        model: options.model,
+        prompt: text,
+        stored_prompt: prompt,
+        idempotency_key: key,
+        generation,
+    };
+    tokio::spawn(relay.run(stream, reply, events));
+
+    Ok(Sse::new(body.boxed()).keep_alive(heartbeat))
+}
+
+/// Heartbeats are comments, which clients skip, so they can't be mistaken for reply chunks.
+fn keep_alive(settings: &Settings) -> KeepAlive {
+    KeepAlive::new()
+        .interval(Duration::from_secs(settings.sse_keepalive_secs.max(1)))
+        .text("keep-alive")
+}
+
+/// Drives a reply opened by `stream_user_message` to the end, whether or not anyone still listens.
+struct ReplyRelay {
+    state: Arc<AppState>,
+    conversation_id: i64,
+    user_id: i64,
+    model: String,
+    prompt: String,
+    stored_prompt: ConvMessage,
+    idempotency_key: Option<String>,
+    generation: Arc<Generation>,
+}
+
+impl ReplyRelay {
+    async fn run(
+        self,
+        mut stream: AiStream,
+        mut reply: StreamedReply,
+        mut events: mpsc::Sender<Result<Event, Infallible>>,
+    ) {
+        let Self {
+            state,
+            conversation_id,
+            user_id,
+            model,
+            prompt,
+            stored_prompt,
+            idempotency_key,
+            generation,
+        } = self;
+        let chunk_deadline = Duration::from_secs(state.settings.gemini_timeout_secs);
+
+        let failure = loop {
+            let chunk = match tokio::time::timeout(chunk_deadline, stream.next()).await {
+                Ok(Some(Ok(chunk))) => chunk,
+                Ok(Some(Err(e))) => break Some(ai_error_message(e)),
+                Ok(None) => break None,
+                Err(_) => break Some(ai_error_message(stalled_reply_error())),
+            };
+
+            let chunk = reply.absorb(&chunk);
+            if chunk.is_empty() {
+                continue;
+            }
+
+            generation.push(&chunk);
+            let _ = events.send(Ok(Event::default().data(chunk))).await;
+        };
+
+        if let Some(frame) = failure {
+            state.finish_generation(conversation_id, GenerationEnd::Failed(frame.clone()));
+            release_key(&state, user_id, idempotency_key.as_deref()).await;
+            let _ = events
+                .send(Ok(Event::default().event("error").data(frame)))
+                .await;
+            return;
+        }
+
+        let reply = reply.finish(&prompt);
+        if let Err(e) = record_token_usage(user_id, reply.total_tokens, &state.db).await {
+            tracing::error!(error = %e, user_id, "recording token usage failed");
+        }
+
+        let last =
+            match store_reply(&state, conversation_id, &reply, &model, false, &state.db).await {
+                Ok(stored) => {
+                    let done = Event::default()
+                        .event("done")
+                        .data(stored_message_json(&stored));
+                    complete_exchange(
+                        &state,
+                        user_id,
+                        idempotency_key.as_deref(),
+                        stored_prompt,
+                        stored,
+                    )
+                    .await;
+                    done
+                }
+                Err(e) => {
+                    release_key(&state, user_id, idempotency_key.as_deref()).await;
+                    Event::default().event("error").data(database_error_json(
+                        "adding assistant message to database failed",
+                        e,
+                    ))
+                }
+            };
+
+        // Only released once stored, so a socket joining now finds the reply in history
+        state.finish_generation(conversation_id, GenerationEnd::Done);
+        let _ = events.send(Ok(last)).await;
+    }
+}
+
+#[derive(Deserialize)]
+pub struct PaginationParams {
+    pub page: Option<u32>,
+    pub limit: Option<u32>,
+    // Message ids for cursor paging; `after` also lets a reconnecting client fetch what it missed
+    pub after: Option<i64>,
+    pub before: Option<i64>,
+}
+
+fn validate_pagination(page: u32, limit: u32) -> Result<(), ValidationError> {
+    if page != 0 && limit != 0 {
+        return Ok(());
+    }
+
+    Err(ValidationError {
+        error: "Invalid pagination parameters".into(),
+        details: vec![
+            ValidationDetail {
+                field: "page".into(),
+                messages: if page == 0 {
+                    vec!["Page must be greater than 0".into()]
+                } else {
+                    vec![]
+                },
+            },
+            ValidationDetail {
+                field: "limit".into(),
+                messages: if limit == 0 {
+                    vec!["Limit must be greater than 0".into()]
+                } else {
+                    vec![]
+                },
+            },
+        ],
+    })
+}
+
+/// A page of the conversation's messages, oldest first. `?after=<message id>` and
+/// `?before=<message id>` page from a cursor, which stays stable while new messages arrive; otherwise
+/// `page` and `limit` select an offset page. `total` counts what's on the requested side of the
+/// cursor, so a client that lost its socket sees how much it missed, replies included.
+pub async fn get_conversation_messages_by_id(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(conversation_id): Path<i64>,
+    Query(params): Query<PaginationParams>,
+) -> Result<Json<MessagePage>, AppError> {
+    let page = params.page.unwrap_or(1);
+    let limit = params.limit.unwrap_or(10);
+    validate_pagination(page, limit)?;
+
+    if params.after.is_some() && params.before.is_some() {
+        return Err(AppError::new(
+            StatusCode::BAD_REQUEST,
+            "Invalid pagination parameters",
+            "before",
+            "Page with either `before` or `after`, not both.",
+        ));
+    }
+
+    if !owns_conversation(&state.db, conversation_id, user_data.user_id).await? {
+        return Err((
+            StatusCode::NOT_FOUND,
+            conversation_not_found("conversation_id"),
+        )
+            .into());
+    }
+
+    let after = message_position(&state, conversation_id, params.after).await?;
+    let before = message_position(&state, conversation_id, params.before).await?;
+    let (after_timestamp, after_id) = after.unzip();
+    let (before_timestamp, before_id) = before.unzip();
+
+    let total: i64 = sqlx::query_scalar(
+        "SELECT COUNT(*) FROM messages WHERE conversation_id = ?1
+AND (?2 IS NULL OR timestamp > ?2 OR (timestamp = ?2 AND id > ?3))
+AND (?4 IS NULL OR timestamp < ?4 OR (timestamp = ?4 AND id < ?5))",
+    )
+    .bind(conversation_id)
+    .bind(after_timestamp)
+    .bind(after_id)
+    .bind(before_timestamp)
+    .bind(before_id)
+    .fetch_one(&state.db)
+    .await?;
+
+    // Cursor pages start at the cursor, so the offset only applies without one
+    let offset = if after.is_none() && before.is_none() {
+        (page - 1) * limit
+    } else {
+        0
+    };
+    // Before a cursor the newest messages are the nearest, so they're taken newest first and flipped
+    let direction = if before.is_some() { "DESC" } else { "ASC" };
+
+    let mut messages = sqlx::query_as::<_, ConvMessage>(&format!(
+        "SELECT * FROM messages WHERE conversation_id = ?1
+AND (?2 IS NULL OR timestamp > ?2 OR (timestamp = ?2 AND id > ?3))
+AND (?4 IS NULL OR timestamp < ?4 OR (timestamp = ?4 AND id < ?5))
+ORDER BY timestamp {direction}, id {direction} LIMIT ?6 OFFSET ?7",
+    ))
+    .bind(conversation_id)
+    .bind(after_timestamp)
+    .bind(after_id)
+    .bind(before_timestamp)
+    .bind(before_id)
+    .bind(limit)
+    .bind(offset)
+    .fetch_all(&state.db)
+    .await?;
+    if before.is_some() {
+        messages.reverse();
+    }
+
+    // Whether anything lies beyond either end of the page
+    let shown = i64::from(offset) + messages.len() as i64;
+    let (more_before, more_after) = match (after, before) {
+        (Some(_), _) => (true, shown < total),
+        (_, Some(_)) => (shown < total, true),
+        _ => (offset > 0, shown < total),
+    };
+
+    Ok(Json(MessagePage {
+        next_cursor: messages
+            .last()
+            .filter(|_| more_after)
+            .map(|message| message.id),
+        prev_cursor: messages
+            .first()
+            .filter(|_| more_before)
+            .map(|message| message.id),
+        items: messages,
+        page: if offset == 0 { 1 } else { page },
+        limit,
+        total,
+        total_pages: (total + i64::from(limit) - 1) / i64::from(limit),
+    }))
+}
+
+/// Where a cursor message sits in the conversation's (timestamp, id) order.
+async fn message_position(
+    state: &AppState,
+    conversation_id: i64,
+    message_id: Option<i64>,
+) -> Result<Option<(i64, i64)>, AppError> {
+    let Some(message_id) = message_id else {
+        return Ok(None);
+    };
+
+    let timestamp: Option<i64> =
+        sqlx::query_scalar("SELECT timestamp FROM messages WHERE id = ?1 AND conversation_id = ?2")
+            .bind(message_id)
+            .bind(conversation_id)
+            .fetch_optional(&state.db)
+            .await?;
+
+    match timestamp {
+        Some(timestamp) => Ok(Some((timestamp, message_id))),
+        None => Err((StatusCode::NOT_FOUND, message_not_found()).into()),
+    }
+}
+
+/// The history, earlier user turns and options a reply to a new prompt is generated from. Loaded
+/// before the prompt is stored so it isn't sent to the model twice; `params` win over the
+/// conversation's saved settings field by field.
+async fn load_reply_context(
+    state: &AppState,
+    conversation_id: i64,
+    model: &str,
+    params: GenerationParams,
+) -> Result<(Vec<ConvMessage>, usize, ReplyOptions), sqlx::Error> {
+    let history =
+        get_conversation_history(conversation_id, state.history_window(), &state.db).await?;
+    let earlier = earlier_user_turns(state, conversation_id, history.first()).await?;
+    let saved = get_conversation_settings(conversation_id, &state.db).await?;
+    let options = ReplyOptions {
+        model: model.to_string(),
+        params: params.or(saved),
+        system_prompt: get_system_prompt(conversation_id, &state.db).await?,
+        images: Vec::new(),
+    };
+
+    Ok((history, earlier, options))
+}
+
+/// A reply as it's put together from one response or a stream of chunks, with what the provider
+/// reported along the way. Started right before the request goes out, so the latency covers the
+/// whole call.
+struct StreamedReply {
+    started: Instant,
+    text: String,
+    response_tokens: Option<i64>,
+    total_tokens: Option<i64>,
+    finish_reason: Option<String>,
+    cached: bool,
+}
+
+/// A reply once it's complete, with token counts estimated where none were reported.
+pub struct FinishedReply {
+    pub text: String,
+    pub response_tokens: i64,
+    /// Prompt and reply together, what the call is charged
+    pub total_tokens: i64,
+    pub latency_ms: i64,
+    pub finish_reason: Option<String>,
+    pub cached: bool,
+}
+
+impl StreamedReply {
+    fn start() -> Self {
+        Self {
+            started: Instant::now(),
+            text: String::new(),
+            response_tokens: None,
+            total_tokens: None,
+            finish_reason: None,
+            cached: false,
+        }
+    }
+
+    /// Adds a chunk and returns its text, empty for chunks that only carry metadata.
+    fn absorb(&mut self, chunk: &AiResult) -> String {
+        if let Some(usage) = chunk.usage {
+            self.response_tokens = Some(usage.response_tokens);
+            self.total_tokens = Some(usage.total_tokens);
+        }
+        if let Some(reason) = &chunk.finish_reason {
+            self.finish_reason = Some(reason.clone());
+        }
+        self.cached |= chunk.cached;
+
+        self.text.push_str(&chunk.text);
+        chunk.text.clone()
+    }
+
+    fn finish(self, prompt: &str) -> FinishedReply {
+        let response_tokens = self
+            .response_tokens
+            .unwrap_or_else(|| estimate_tokens(&self.text));
+        FinishedReply {
+            total_tokens: self
+                .total_tokens
+                .unwrap_or_else(|| estimate_tokens(prompt) + response_tokens),
+            response_tokens,
+            latency_ms: elapsed_ms(self.started),
+            finish_reason: self.finish_reason,
+            cached: self.cached,
+            text: self.text,
+        }
+    }
+}
+
+fn elapsed_ms(started: Instant) -> i64 {
+    i64::try_from(started.elapsed().as_millis()).unwrap_or(i64::MAX)
+}
+
+fn stalled_reply_error() -> AiErrorWrapper {
+    AiErrorWrapper::new(
+        StatusCode::GATEWAY_TIMEOUT,
+        "Gemini stopped responding mid-reply",
+    )
+}
+
+/// Stores a finished (or stopped) reply, tidied first when that's configured.
+async fn store_reply(
+    state: &AppState,
+    conversation_id: i64,
+    reply: &FinishedReply,
+    model: &str,
+    stopped: bool,
+    exec: impl Acquire<'_, Database = Sqlite>,
+) -> Result<ConvMessage, sqlx::Error> {
+    let text = if state.settings.tidy_assistant_whitespace {
+        tidy_whitespace(&reply.text)
+    } else {
+        reply.text.clone()
+    };
+
+    // Stopped and cached replies say nothing about how long the model takes
+    if !stopped && !reply.cached {
+        state.record_reply_latency(u64::try_from(reply.latency_ms).unwrap_or_default());
+    }
+
+    let meta = ReplyMeta {
+        model: Some(model),
+        stopped,
+        latency_ms: Some(reply.latency_ms),
+        finish_reason: reply.finish_reason.as_deref(),
+        cached: reply.cached,
+    };
+    insert_chat_message_to_db(
+        "assistant",
+        conversation_id,
+        &text,
+        reply.response_tokens,
+        &meta,
+        exec,
+    )
+    .await
+}
+
+#[debug_handler]
+pub async fn post_user_message(
+    State(state): State<Arc<AppState>>,
+    headers: HeaderMap,
+    ws: WebSocketUpgrade,
+    Query(params): Query<UserMessage>,
+) -> Response {
+    let user_data = match websocket_token(&headers, params.token.as_deref()) {
+        Ok(token) => match authenticate(&state, &token).await {
+            Ok(claims) => claims,
+            Err(e) => return e.into_response(),
+        },
+        Err(e) => return e.into_response(),
+    };
+
+    match owns_conversation(&state.db, params.conversation_id, user_data.user_id).await {
+        Ok(true) => {}
+        Ok(false) => {
+            return (
+                StatusCode::NOT_FOUND,
+                conversation_not_found("conversation_id"),
+            )
+                .into_response();
+        }
+        Err(e) => {
+            tracing::error!(error = %e, "conversation ownership check failed");
+            return AppError::internal(
+                "Database error",
+                "conversation_id",
+                "Could not check the conversation.",
+            )
+            .into_response();
+        }
+    }
+
+    let needs = ModelInfo {
+        streaming: true,
+        vision: false,
+    };
+    let model = match resolve_model(
+        &state.settings,
+        state.ai_provider(),
+        params.model.as_deref(),
+        needs,
+    ) {
+        Ok(model) => model,
+        Err(e) => return e.into_response(),
+    };
+
+    if let Err(e) = params.params().validate() {
+        return AppError::from(e).into_response();
+    }
+
+    // Echo the subprotocol back when the token came through it, otherwise browsers drop the socket
+    // The session keeps the upgrade request's span, so its logs carry that request id
+    let span = tracing::Span::current();
+    ws.protocols(["bearer"]).on_upgrade(move |socket| {
+        handle_user_message(socket, params, model, user_data.user_id, state).instrument(span)
+    })
+}
+
+async fn handle_user_message(
+    mut socket: WebSocket,
+    params: UserMessage,
+    model: String,
+    user_id: i64,
+    state: Arc<AppState>,
+) {
+    if let Some(generation) = state.active_generation(params.conversation_id) {
+        follow_generation(&mut socket, &generation).await;
+    }
+
+    let mut shutdown = state.shutdown_signal();
+    let mut heartbeat = Heartbeat::new(&state.settings);
+    while let Some(msg) = next_message(&mut socket, &mut shutdown, &mut heartbeat).await {
+        if let Ok(msg) = msg {
+            if !state.check_message_rate(user_id) {
+                let stringified = serde_json::to_string(&ValidationError {
+                    error: "Rate limit exceeded".to_string(),
+                    details: vec![ValidationDetail {
+                        field: "message".to_string(),
+                        messages: vec!["Too many messages, slow down and reconnect".to_string()],
+                    }],
+                })
+                .unwrap_or_else(|_| "{\"error\": \"Rate limit exceeded\"}".to_string());
+                let _ = socket.send(stringified.into()).await;
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::POLICY,
+                        reason: "rate limit exceeded".into(),
+                    })))
+                    .await;
+                return;
+            }
+
+            // Nothing is streaming between replies, so a stop has nothing to act on
+            if control_frame(&msg).is_some() {
+                continue;
+            }
+
+            let (text, key) = match &msg {
+                Message::Text(text) if text.len() > state.settings.ws_max_message_bytes => {
+                    let _ = socket
+                        .send(oversized_message(state.settings.ws_max_message_bytes))
+                        .await;
+                    continue;
+                }
+                Message::Text(text) => match serde_json::from_str::<KeyedPrompt>(text.as_str()) {
+                    Ok(prompt) if !is_valid_idempotency_key(&prompt.idempotency_key) => {
+                        let _ = socket
+                            .send(validation_error_message(&invalid_idempotency_key(
+                                "idempotency_key",
+                            )))
+                            .await;
+                        continue;
+                    }
+                    Ok(prompt) => (
+                        normalize_text(&prompt.msg, state.settings.normalize_unicode),
+                        Some(prompt.idempotency_key),
+                    ),
+                    Err(_) => (
+                        normalize_text(text.as_str(), state.settings.normalize_unicode),
+                        None,
+                    ),
+                },
+                Message::Binary(_) => {
+                    let _ = socket.send(binary_frame_message()).await;
+                    continue;
+                }
+                _ => continue,
+            };
+
+            // Overlong or blocked prompts are neither stored nor sent, and the socket stays usable
+            if let Err(e) = check_prompt_length(&state.settings, "msg", &text)
+                .and_then(|_| moderate_prompt(&state, user_id, Some(params.conversation_id), &text))
+            {
+                let _ = socket.send(validation_error_message(&e)).await;
+                continue;
+            }
+
+            // Ownership was checked at upgrade, but the conversation may have been deleted since
+            match owns_conversation(&state.db, params.conversation_id, user_id).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    let frame = serde_json::to_string(&conversation_not_found("conversation_id"))
+                        .unwrap_or_else(|_| "{\"error\": \"Conversation not found\"}".to_string());
+                    let _ = socket.send(frame.into()).await;
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "conversation not found".into(),
+                        })))
+                        .await;
+                    return;
+                }
+                Err(e) => {
+                    let _ = socket
+                        .send(database_error_message(
+                            "checking the conversation failed",
+                            e,
+                        ))
+                        .await;
+                    continue;
+                }
+            }
+
+            if let Some(key) = &key {
+                let endpoint = message_endpoint(params.conversation_id);
+                let ttl = state.settings.idempotency_ttl_secs;
+                let claim = match state.db.acquire().await {
+                    Ok(mut conn) => {
+                        claim_idempotency_key(
+                            user_id,
+                            key,
+                            &endpoint,
+                            &hash_token(&text),
+                            ttl,
+                            &mut conn,
+                        )
+                        .await
+                    }
+                    Err(e) => Err(e),
+                };
+                match claim {
+                    Ok(IdempotencyClaim::New) => {}
+                    Ok(IdempotencyClaim::Replay { body, .. }) => {
+                        replay_exchange(&mut socket, &body).await;
+                        continue;
+                    }
+                    Ok(refused) => {
+                        let (_, error) = refused_idempotency_key(&refused);
+                        let _ = socket.send(validation_error_message(&error)).await;
+                        continue;
+                    }
+                    Err(e) => {
+                        let _ = socket
+                            .send(database_error_message(
+                                "checking the idempotency key failed",
+                                e,
+                            ))
+                            .await;
+                        continue;
+                    }
+                }
+            }
+
+            match exhausted_token_budget(&state, user_id).await {
+                Ok(None) => {}
+                Ok(Some(budget)) => {
+                    release_key(&state, user_id, key.as_deref()).await;
+                    let frame = serde_json::to_string(&token_budget_error(budget))
+                        .unwrap_or_else(|_| "{\"error\": \"Token budget exhausted\"}".to_string());
+                    let _ = socket.send(frame.into()).await;
+                    continue;
+                }
+                Err(e) => {
+                    release_key(&state, user_id, key.as_deref()).await;
+                    let _ = socket
+                        .send(database_error_message(
+                            "checking the token budget failed",
+                            e,
+                        ))
+                        .await;
+                    continue;
+                }
+            }
+
+            // Claimed before the prompt is stored so a refused message leaves no trace
+            let Some(generation) = state.start_generation(params.conversation_id) else {
+                release_key(&state, user_id, key.as_deref()).await;
+                let _ = socket.send(generation_busy_message()).await;
+                continue;
+            };
+
+            // Loaded before the new message is stored so it isn't sent to the model twice
+            // Re-read per message so saved changes apply to sockets that are already open
+            let loaded =
+                load_reply_context(&state, params.conversation_id, &model, params.params()).await;
+
+            let (history, earlier, options) = match loaded {
+                Ok(loaded) => loaded,
+                // Answering without the history and system prompt would store a reply out of context
+                Err(e) => {
+                    let frame = database_error_json("loading conversation history failed", e);
+                    release_key(&state, user_id, key.as_deref()).await;
+                    state.finish_generation(
+                        params.conversation_id,
+                        GenerationEnd::Failed(frame.clone()),
+                    );
+                    let _ = socket.send(Message::from(frame)).await;
+                    continue;
+                }
+            };
+
+            // Usage for the prompt alone isn't reported by the provider, so the user row is estimated
+            let r = insert_chat_message_to_db(
+                "user", // shitty code
+                params.conversation_id,
+                &text,
+                estimate_tokens(&text),
+                &ReplyMeta::default(),
+                &state.db,
+            )
+            .await;
+
+            // A reply with no prompt before it would read as an answer to the previous one
+            let stored_prompt = match r {
+                Ok(stored) => {
+                    let _ = socket.send(stored_message_frame(&stored)).await;
+                    stored
+                }
+                Err(e) => {
+                    let frame = database_error_json("adding user message to database failed", e);
+                    release_key(&state, user_id, key.as_deref()).await;
+                    state.finish_generation(
+                        params.conversation_id,
+                        GenerationEnd::Failed(frame.clone()),
+                    );
+                    let _ = socket.send(Message::from(frame)).await;
+                    continue;
+                }
+            };
+
+            // Chunks go out as they arrive; the assembled reply is only stored once the stream ends.
+            // The socket is read meanwhile so a stop can end the stream early, which drops the
+            // request to Gemini.
+            let result: Result<(FinishedReply, bool), String> = async {
+                let mut reply = StreamedReply::start();
+                let mut stream = stream_request_to_ai(&state, &options, &history, earlier, &text)
+                    .await
+                    .map_err(ai_error_message)?;
+
+                let chunk_deadline = Duration::from_secs(state.settings.gemini_timeout_secs);
+                let mut stopped = false;
+                let mut client_open = true;
+                loop {
+                    let next = tokio::select! {
+                        next = tokio::time::timeout(chunk_deadline, stream.next()) => next,
+                        frame = socket.recv(), if client_open => {
+                            match frame {
+                                Some(Ok(frame)) if control_frame(&frame) == Some(ControlFrame::Stop) => {
+                                    stopped = true;
+                                    break;
+                                }
+                                Some(Ok(Message::Text(_))) => {
+                                    let _ = socket.send(generation_busy_message()).await;
+                                }
+                                Some(Ok(Message::Binary(_))) => {
+                                    let _ = socket.send(binary_frame_message()).await;
+                                }
+                                Some(Ok(Message::Pong(_))) => heartbeat.pong(),
+                                Some(Ok(Message::Close(_))) => client_open = false,
+                                Some(Ok(_)) => {}
+                                // The reply is still finished and stored for when the client is back
+                                Some(Err(_)) | None => client_open = false,
+                            }
+                            continue;
+                        }
+                    };
+
+                    let chunk = match next {
+                        Ok(Some(chunk)) => chunk.map_err(ai_error_message)?,
+                        Ok(None) => break,
+                        Err(_) => return Err(ai_error_message(stalled_reply_error())),
+                    };
+
+                    let chunk = reply.absorb(&chunk);
+                    if chunk.is_empty() {
+                        continue;
+                    }
+
+                    generation.push(&chunk);
+                    let _ = socket.send(Message::from(chunk)).await;
+                }
+
+                Ok((reply.finish(&text), stopped))
+            }
+            .await;
+
+            // A stopped reply is still charged for what was generated before the stop
+            if let Ok((reply, _)) = &result
+                && let Err(e) = record_token_usage(user_id, reply.total_tokens, &state.db).await
+            {
+                let _ = socket
+                    .send(database_error_message("recording token usage failed", e))
+                    .await;
+            }
+
+            match result {
+                // Stopped before anything arrived: there's no partial reply to keep
+                Ok((reply, true)) if reply.text.is_empty() => {
+                    release_key(&state, user_id, key.as_deref()).await;
+                    state.finish_generation(params.conversation_id, GenerationEnd::Stopped);
+                    let _ = socket.send(Message::from(STOPPED_FRAME)).await;
+                }
+                // Stored whether or not the socket survived; a client that dropped mid-turn finds the
+                // reply with `?after=` once it's back
+                Ok((reply, stopped)) => {
+                    let r = store_reply(
+                        &state,
+                        params.conversation_id,
+                        &reply,
+                        &model,
+                        stopped,
+                        &state.db,
+                    )
+                    .await;
+
+                    match r {
+                        Ok(stored) => {
+                            let _ = socket.send(stored_message_frame(&stored)).await;
+                            complete_exchange(
+                                &state,
+                                user_id,
+                                key.as_deref(),
+                                stored_prompt,
+                                stored,
+                            )
+                            .await;
+                        }
+                        Err(e) => {
+                            release_key(&state, user_id, key.as_deref()).await;
+                            let _ = socket
+                                .send(database_error_message(
+                                    "adding assistant message to database failed",
+                                    e,
+                                ))
+                                .await;
+                        }
+                    }
+
+                    // Only released once stored, so a socket joining now finds the reply in history
+                    let end = if stopped {
+                        GenerationEnd::Stopped
+                    } else {
+                        GenerationEnd::Done
+                    };
+                    state.finish_generation(params.conversation_id, end.clone());
+                    let _ = socket.send(end_frame(end)).await;
+                }
+                Err(err_msg) => {
+                    release_key(&state, user_id, key.as_deref()).await;
+                    state.finish_generation(
+                        params.conversation_id,
+                        GenerationEnd::Failed(err_msg.clone()),
+                    );
+                    let _ = socket.send(Message::from(err_msg)).await;
+                }
+            }
         } else {
             // client disconnected
         };
     }
 }
+
+/// Tracks the ping sent to an idle socket so a peer that vanished without closing is noticed.
+struct Heartbeat {
+    interval: Option<Duration>,
+    timeout: Duration,
+    next_ping: Instant,
+    awaiting_pong: Option<Instant>,
+}
+
+impl Heartbeat {
+    fn new(settings: &Settings) -> Self {
+        let interval = Duration::from_secs(settings.ws_ping_interval_secs);
+        Self {
+            interval: (!interval.is_zero()).then_some(interval),
+            timeout: Duration::from_secs(settings.ws_pong_timeout_secs),
+            next_ping: Instant::now() + interval,
+            awaiting_pong: None,
+        }
+    }
+
+    /// When the next ping is due, or when the outstanding one counts as unanswered
+    fn deadline(&self) -> Option<Instant> {
+        self.interval?;
+        Some(match self.awaiting_pong {
+            Some(sent) => sent + self.timeout,
+            None => self.next_ping,
+        })
+    }
+
+    fn pinged(&mut self) {
+        self.awaiting_pong = Some(Instant::now());
+    }
+
+    fn pong(&mut self) {
+        if let Some(interval) = self.interval {
+            self.awaiting_pong = None;
+            self.next_ping = Instant::now() + interval;
+        }
+    }
+}
+
+/// Waits for the next client frame, pinging the socket while it's idle. Returns `None` once the
+/// client closes, stops answering pings, or shutdown starts ("going away").
+async fn next_message(
+    socket: &mut WebSocket,
+    shutdown: &mut watch::Receiver<bool>,
+    heartbeat: &mut Heartbeat,
+) -> Option<Result<Message, axum::Error>> {
+    loop {
+        let deadline = heartbeat.deadline();
+        tokio::select! {
+            msg = socket.recv() => match msg {
+                Some(Ok(Message::Pong(_))) => heartbeat.pong(),
+                // Pings are answered by the websocket layer itself
+                Some(Ok(Message::Ping(_))) => {}
+                // Flushes the reply the websocket layer queued, completing the close handshake
+                Some(Ok(Message::Close(_))) => {
+                    let _ = socket.flush().await;
+                    return None;
+                }
+                msg => return msg,
+            },
+            _ = async {
+                match deadline {
+                    Some(at) => tokio::time::sleep_until(at.into()).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                if heartbeat.awaiting_pong.is_some() {
+                    tracing::debug!("closing a chat socket that stopped answering pings");
+                    let _ = socket
+                        .send(Message::Close(Some(CloseFrame {
+                            code: close_code::POLICY,
+                            reason: "ping timeout".into(),
+                        })))
+                        .await;
+                    return None;
+                }
+                if socket.send(Message::Ping(Vec::new().into())).await.is_err() {
+                    return None;
+                }
+                heartbeat.pinged();
+            }
+            // The guard `wait_for` returns isn't Send, so it's dropped before the close frame goes out
+            _ = async { shutdown.wait_for(|stopping| *stopping).await.is_ok() } => {
+                let _ = socket
+                    .send(Message::Close(Some(CloseFrame {
+                        code: close_code::AWAY,
+                        reason: "server shutting down".into(),
+                    })))
+                    .await;
+                return None;
+            }
+        }
+    }
+}
+
+// Sends what the in-progress reply has produced so far, then forwards the remaining chunks
+async fn follow_generation(socket: &mut WebSocket, generation: &Generation) {
+    let mut sent = 0;
+
+    loop {
+        let (text, end, mut events) = generation.join_from(sent);
+
+        if !text.is_empty() {
+            sent += text.len();
+            if socket.send(Message::from(text)).await.is_err() {
+                return;
+            }
+        }
+
+        if let Some(end) = end {
+            let _ = socket.send(end_frame(end)).await;
+            return;
+        }
+
+        loop {
+            match events.recv().await {
+                Ok(GenerationEvent::Chunk(chunk)) => {
+                    sent += chunk.len();
+                    if socket.send(Message::from(chunk)).await.is_err() {
+                        return;
+                    }
+                }
+                Ok(GenerationEvent::End(end)) => {
+                    let _ = socket.send(end_frame(end)).await;
+                    return;
+                }
+                // Chunks that were skipped are re-sent from the accumulated text
+                Err(RecvError::Lagged(_)) => break,
+                Err(RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+fn end_frame(end: GenerationEnd) -> Message {
+    match end {
+        GenerationEnd::Done => Message::from(DONE_FRAME),
+        GenerationEnd::Stopped => Message::from(STOPPED_FRAME),
+        GenerationEnd::Failed(frame) => Message::from(frame),
+    }
+}
+
+// `{"message": {...}}` with the stored row, so clients can swap their optimistic copy for it
+fn stored_message_frame(message: &ConvMessage) -> Message {
+    stored_message_json(message).into()
+}
+
+fn stored_message_json(message: &ConvMessage) -> String {
+    serde_json::to_string(&serde_json::json!({ "message": message }))
+        .unwrap_or_else(|_| "{\"error\": \"Internal server error\"}".to_string())
+}
+
+fn control_frame(msg: &Message) -> Option<ControlFrame> {
+    match msg {
+        Message::Text(text) => serde_json::from_str(text.as_str()).ok(),
+        _ => None,
+    }
+}
+
+fn generation_busy_message() -> Message {
+    serde_json::to_string(&generation_busy_error())
+        .unwrap_or_else(|_| "{\"error\": \"Reply in progress\"}".to_string())
+        .into()
+}
+
+fn generation_busy_error() -> ValidationError {
+    ValidationError {
+        error: "Reply in progress".to_string(),
+        details: vec![ValidationDetail {
+            field: "conversation_id".to_string(),
+            messages: vec![
+                "Wait for the current reply to finish before sending another message.".to_string(),
+            ],
+        }],
+    }
+}
+
+fn oversized_message(max_bytes: usize) -> Message {
+    serde_json::to_string(&ValidationError {
+        error: "Message too large".to_string(),
+        details: vec![ValidationDetail {
+            field: "message".to_string(),
+            messages: vec![format!("Messages are limited to {} bytes.", max_bytes)],
+        }],
+    })
+    .unwrap_or_else(|_| "{\"error\": \"Message too large\"}".to_string())
+    .into()
+}
+
+fn binary_frame_message() -> Message {
+    serde_json::to_string(&ValidationError {
+        error: "Unsupported frame".to_string(),
+        details: vec![ValidationDetail {
+            field: "message".to_string(),
+            messages: vec!["Messages must be sent as text frames.".to_string()],
+        }],
+    })
+    .unwrap_or_else(|_| "{\"error\": \"Unsupported frame\"}".to_string())
+    .into()
+}
+
+// Rough chars/4 estimate for when the provider doesn't report usage
+fn estimate_tokens(text: &str) -> i64 {
+    text.chars().count().div_ceil(4) as i64
+}
+
+fn validation_error_message(error: &ValidationError) -> Message {
+    serde_json::to_string(error)
+        .unwrap_or_else(|_| "{\"error\": \"Validation failed\"}".to_string())
+        .into()
+}
+
+fn database_error_message(context: &str, e: sqlx::Error) -> Message {
+    database_error_json(context, e).into()
+}
+
+fn database_error_json(context: &str, e: sqlx::Error) -> String {
+    serde_json::to_string(&ValidationError {
+        error: "Database query failed".to_string(),
+        details: vec![ValidationDetail {
+            field: "database".to_string(),
+            messages: vec![format!("{}: {}", context, e)],
+        }],
+    })
+    .unwrap_or_else(|_| "{\"error\": \"Internal server error\"}".to_string())
+}
+
+// Kept as text so the same frame can be handed to sockets following the generation
+fn ai_error_message(e: impl Into<AiErrorWrapper>) -> String {
+    let new_e: AiErrorWrapper = e.into();
+
+    serde_json::to_string(&new_e)
+        .unwrap_or_else(|_| "{\"error\": \"Internal server error\"}".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::{Speaker, gemini::GeminiProvider};
+
+    fn message(id: i64, role: &str, content: &str) -> ConvMessage {
+        ConvMessage {
+            id,
+            conversation_id: 1,
+            role: role.to_string(),
+            content: content.to_string(),
+            timestamp: id,
+            token_count: 0,
+            model: None,
+            stopped: false,
+            latency_ms: None,
+            finish_reason: None,
+            cached: false,
+        }
+    }
+
+    fn model_settings() -> Settings {
+        let mut settings = Settings::from_env();
+        settings.gemini_model = "gemini-2.0-flash".to_string();
+        settings.gemini_allowed_models = vec!["gemini-1.5-pro".to_string()];
+        settings
+    }
+
+    fn resolve(
+        settings: &Settings,
+        requested: Option<&str>,
+        needs: ModelInfo,
+    ) -> Result<String, ValidationError> {
+        resolve_model(settings, &GeminiProvider::new("".into()), requested, needs)
+    }
+
+    #[test]
+    fn model_defaults_when_not_requested() {
+        assert_eq!(
+            resolve(&model_settings(), None, ModelInfo::default()).unwrap(),
+            "gemini-2.0-flash"
+        );
+    }
+
+    #[test]
+    fn allowed_models_are_accepted_with_or_without_prefix() {
+        let settings = model_settings();
+        let needs = ModelInfo {
+            streaming: true,
+            vision: false,
+        };
+        assert_eq!(
+            resolve(&settings, Some("gemini-1.5-pro"), needs).unwrap(),
+            "gemini-1.5-pro"
+        );
+        assert_eq!(
+            resolve(&settings, Some("models/gemini-1.5-pro"), needs).unwrap(),
+            "gemini-1.5-pro"
+        );
+        // The default is usable by name even when the allowlist leaves it out
+        assert_eq!(
+            resolve(&settings, Some("gemini-2.0-flash"), needs).unwrap(),
+            "gemini-2.0-flash"
+        );
+    }
+
+    #[test]
+    fn unknown_models_are_rejected() {
+        let error = resolve(
+            &model_settings(),
+            Some("gemini-ultra"),
+            ModelInfo::default(),
+        )
+        .unwrap_err();
+        assert_eq!(error.details[0].field, "model");
+    }
+
+    #[test]
+    fn allowed_models_must_be_served_by_the_provider_with_what_was_asked() {
+        let mut settings = model_settings();
+        settings.gemini_allowed_models.push("gpt-4o".to_string());
+
+        let error = resolve(&settings, Some("gpt-4o"), ModelInfo::default()).unwrap_err();
+        assert_eq!(error.details[0].field, "model");
+        assert_eq!(
+            error.details[0].messages,
+            ["gpt-4o isn't served by the gemini provider."]
+        );
+
+        let error = resolve(
+            &settings,
+            None,
+            ModelInfo {
+                streaming: true,
+                vision: true,
+            },
+        )
+        .unwrap_err();
+        assert_eq!(error.details[0].field, "images");
+    }
+
+    #[test]
+    fn conversation_system_prompt_wins_over_system_rows() {
+        let history = [message(1, "system", "Old rule."), message(2, "user", "one")];
+
+        let (system, _) = to_turns(Some("Speak like a pirate."), &history, "two", 0, 0);
+        assert_eq!(system.as_deref(), Some("Speak like a pirate."));
+
+        let (system, _) = to_turns(None, &history, "two", 0, 0);
+        assert_eq!(system.as_deref(), Some("Old rule."));
+    }
+
+    #[test]
+    fn stop_frames_are_recognized() {
+        assert_eq!(
+            control_frame(&Message::from("{\"type\":\"stop\"}")),
+            Some(ControlFrame::Stop)
+        );
+        assert_eq!(control_frame(&Message::from("stop")), None);
+        assert_eq!(control_frame(&Message::from("{\"type\":\"pause\"}")), None);
+        assert_eq!(control_frame(&Message::Binary(Vec::new().into())), None);
+    }
+
+    #[test]
+    fn request_params_override_saved_ones_field_by_field() {
+        let saved = GenerationParams {
+            temperature: Some(0.2),
+            top_p: Some(0.5),
+            max_output_tokens: None,
+        };
+        let requested = GenerationParams {
+            temperature: Some(1.5),
+            ..Default::default()
+        };
+
+        let merged = requested.or(saved);
+        assert_eq!(merged.temperature, Some(1.5));
+        assert_eq!(merged.top_p, Some(0.5));
+        assert_eq!(merged.max_output_tokens, None);
+    }
+
+    #[test]
+    fn backoff_doubles_and_stays_capped() {
+        assert_eq!(backoff_ms(0), 500);
+        assert_eq!(backoff_ms(3), 4_000);
+        assert_eq!(backoff_ms(6), MAX_BACKOFF_MS);
+        assert_eq!(backoff_ms(u32::MAX), MAX_BACKOFF_MS);
+    }
+
+    #[test]
+    fn reminder_is_injected_every_n_user_turns() {
+        let history = vec![
+            message(1, "system", "Answer in French."),
+            message(2, "user", "one"),
+            message(3, "assistant", "un"),
+            message(4, "user", "two"),
+            message(5, "assistant", "deux"),
+        ];
+
+        let (system, turns) = to_turns(None, &history, "three", 3, 0);
+
+        assert_eq!(system.as_deref(), Some("Answer in French."));
+        assert_eq!(turns.len(), 5);
+        assert_eq!(turns[1].speaker, Speaker::Model);
+        assert_eq!(turns[0].text, "one");
+        assert_eq!(turns[2].text, "two");
+        assert_eq!(turns[4].text, "(Reminder: Answer in French.)\n\nthree");
+    }
+
+    #[test]
+    fn reminder_cadence_counts_turns_outside_the_window() {
+        let history = vec![
+            message(1, "system", "Be brief."),
+            message(8, "user", "five"),
+            message(9, "assistant", "ok"),
+        ];
+
+        // Four user turns were already trimmed from the window, so "five" is the 5th and "six" the 6th
+        let (_, turns) = to_turns(None, &history, "six", 3, 4);
+
+        assert_eq!(turns[0].text, "five");
+        assert_eq!(turns[2].text, "(Reminder: Be brief.)\n\nsix");
+    }
+
+    #[test]
+    fn no_reminder_when_disabled_or_without_system_prompt() {
+        let history = vec![message(1, "system", "Be brief."), message(2, "user", "one")];
+        let (_, turns) = to_turns(None, &history, "two", 0, 0);
+        assert_eq!(turns[1].text, "two");
+
+        let (system, turns) = to_turns(None, &[message(2, "user", "one")], "two", 1, 0);
+        assert_eq!(system, None);
+        assert_eq!(turns[1].text, "two");
+    }
+}
@@ -0,0 +1,62 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::State,
+    http::{Method, StatusCode, Uri},
+    response::{IntoResponse, Response},
+};
+
+use crate::{
+    models::app::AppState,
+    utils::validation::{ValidationDetail, ValidationError},
+};
+
+/// Unknown paths get the same JSON error shape as every other endpoint.
+pub async fn not_found(uri: Uri) -> (StatusCode, ValidationError) {
+    (
+        StatusCode::NOT_FOUND,
+        ValidationError {
+            error: "Not found".to_string(),
+            details: vec![ValidationDetail {
+                field: "path".to_string(),
+                messages: vec![format!("No route for {}", uri.path())],
+            }],
+        },
+    )
+}
+
+pub async fn method_not_allowed(method: Method, uri: Uri) -> (StatusCode, ValidationError) {
+    (
+        StatusCode::METHOD_NOT_ALLOWED,
+        ValidationError {
+            error: "Method not allowed".to_string(),
+            details: vec![ValidationDetail {
+                field: "method".to_string(),
+                messages: vec![format!("{} is not supported on {}", method, uri.path())],
+            }],
+        },
+    )
+}
+
+/// Replaces the plain-text 413 axum sends when a body goes over `DefaultBodyLimit` with the usual
+/// JSON error shape.
+pub async fn payload_too_large(State(state): State<Arc<AppState>>, response: Response) -> Response {
+    if response.status() != StatusCode::PAYLOAD_TOO_LARGE {
+        return response;
+    }
+
+    (
+        StatusCode::PAYLOAD_TOO_LARGE,
+        ValidationError {
+            error: "Payload too large".to_string(),
+            details: vec![ValidationDetail {
+                field: "body".to_string(),
+                messages: vec![format!(
+                    "Request bodies are limited to {} bytes",
+                    state.settings.max_body_bytes
+                )],
+            }],
+        },
+    )
+        .into_response()
+}
@@ -0,0 +1,45 @@
+use std::{sync::Arc, time::Instant};
+
+use axum::{Json, extract::State, http::StatusCode};
+use serde::Serialize;
+
+use crate::models::app::AppState;
+
+#[derive(Serialize)]
+pub struct HealthStatus {
+    pub status: &'static str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub db_latency_ms: Option<u64>,
+}
+
+/// Liveness probe: answers as long as the process is serving requests.
+pub async fn health() -> Json<HealthStatus> {
+    Json(HealthStatus {
+        status: "ok",
+        db_latency_ms: None,
+    })
+}
+
+/// Readiness probe: reports 503 while the database can't answer a trivial query.
+pub async fn ready(State(state): State<Arc<AppState>>) -> (StatusCode, Json<HealthStatus>) {
+    let started = Instant::now();
+    let result = sqlx::query("SELECT 1").execute(&state.db).await;
+    let db_latency_ms = Some(started.elapsed().as_millis() as u64);
+
+    match result {
+        Ok(_) => (
+            StatusCode::OK,
+            Json(HealthStatus {
+                status: "ready",
+                db_latency_ms,
+            }),
+        ),
+        Err(_) => (
+            StatusCode::SERVICE_UNAVAILABLE,
+            Json(HealthStatus {
+                status: "unavailable",
+                db_latency_ms,
+            }),
+        ),
+    }
+}
@@ -1,2 +1,7 @@
+pub mod admin;
 pub mod ai;
-pub mod auth;
\ No newline at end of file
+pub mod auth;
+pub mod fallback;
+pub mod health;
+pub mod tags;
+pub mod usage;
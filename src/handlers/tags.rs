@@ -0,0 +1,170 @@
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json,
+    extract::{Path, State},
+    http::StatusCode,
+};
+use chrono::Utc;
+
+use crate::{
+    database::connection::load_conversation_tags,
+    errors::api_errors::AppError,
+    handlers::ai::{conversation_not_found, owns_conversation},
+    models::{
+        ai::{Conversation, NewTag, Tag},
+        app::AppState,
+        auth::TokenClaims,
+    },
+    utils::{
+        normalization::normalize_text,
+        validation::{ValidationDetail, ValidationError},
+    },
+};
+
+/// Longest tag name, in characters.
+const MAX_TAG_NAME_CHARS: usize = 50;
+
+/// The caller's tags, alphabetically.
+pub async fn list_tags(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+) -> Result<Json<Vec<Tag>>, AppError> {
+    let tags: Vec<Tag> =
+        sqlx::query_as("SELECT id, name, created_at FROM tags WHERE user_id = ?1 ORDER BY name")
+            .bind(user_data.user_id)
+            .fetch_all(&state.db)
+            .await?;
+
+    Ok(Json(tags))
+}
+
+pub async fn create_tag(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Json(payload): Json<NewTag>,
+) -> Result<Json<Tag>, AppError> {
+    let name = normalize_text(payload.name.trim(), state.settings.normalize_unicode);
+    if name.is_empty() || name.chars().count() > MAX_TAG_NAME_CHARS {
+        return Err(ValidationError {
+            error: "Validation failed".to_string(),
+            details: vec![ValidationDetail {
+                field: "name".to_string(),
+                messages: vec![format!("Tag names must be 1 to {} characters", MAX_TAG_NAME_CHARS)],
+            }],
+        }
+        .into());
+    }
+
+    // The unique index ignores case, so "Work" and "work" are the same tag
+    let created: Option<Tag> = sqlx::query_as(
+        "INSERT INTO tags (user_id, name, created_at) VALUES (?1, ?2, ?3)
+ON CONFLICT (user_id, name) DO NOTHING RETURNING id, name, created_at",
+    )
+    .bind(user_data.user_id)
+    .bind(&name)
+    .bind(Utc::now().timestamp())
+    .fetch_optional(&state.db)
+    .await?;
+
+    created.map(Json).ok_or_else(|| {
+        AppError::new(
+            StatusCode::CONFLICT,
+            "Tag already exists",
+            "name",
+            "You already have a tag with this name.",
+        )
+    })
+}
+
+/// Deletes the tag and takes it off every conversation.
+pub async fn delete_tag(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path(id): Path<i64>,
+) -> Result<StatusCode, AppError> {
+    let result = sqlx::query("DELETE FROM tags WHERE id = ?1 AND user_id = ?2")
+        .bind(id)
+        .bind(user_data.user_id)
+        .execute(&state.db)
+        .await?;
+
+    if result.rows_affected() == 0 {
+        return Err((StatusCode::NOT_FOUND, tag_not_found()).into());
+    }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Puts the tag on the conversation; tagging twice is harmless. Both must belong to the caller.
+pub async fn attach_tag(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path((id, tag_id)): Path<(i64, i64)>,
+) -> Result<Json<Conversation>, AppError> {
+    check_ownership(&state, user_data.user_id, id, tag_id).await?;
+
+    sqlx::query(
+        "INSERT INTO conversation_tags (conversation_id, tag_id) VALUES (?1, ?2)
+ON CONFLICT (conversation_id, tag_id) DO NOTHING",
+    )
+    .bind(id)
+    .bind(tag_id)
+    .execute(&state.db)
+    .await?;
+
+    Ok(Json(tagged_conversation(&state, id).await?))
+}
+
+pub async fn detach_tag(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Path((id, tag_id)): Path<(i64, i64)>,
+) -> Result<Json<Conversation>, AppError> {
+    check_ownership(&state, user_data.user_id, id, tag_id).await?;
+
+    sqlx::query("DELETE FROM conversation_tags WHERE conversation_id = ?1 AND tag_id = ?2")
+        .bind(id)
+        .bind(tag_id)
+        .execute(&state.db)
+        .await?;
+
+    Ok(Json(tagged_conversation(&state, id).await?))
+}
+
+async fn check_ownership(state: &AppState, user_id: i64, conversation_id: i64, tag_id: i64) -> Result<(), AppError> {
+    if !owns_conversation(&state.db, conversation_id, user_id).await? {
+        return Err((StatusCode::NOT_FOUND, conversation_not_found("id")).into());
+    }
+
+    let owned = sqlx::query_scalar::<_, i64>("SELECT 1 FROM tags WHERE id = ?1 AND user_id = ?2")
+        .bind(tag_id)
+        .bind(user_id)
+        .fetch_optional(&state.db)
+        .await?;
+    if owned.is_none() {
+        return Err((StatusCode::NOT_FOUND, tag_not_found()).into());
+    }
+
+    Ok(())
+}
+
+async fn tagged_conversation(state: &AppState, id: i64) -> Result<Conversation, sqlx::Error> {
+    let mut conversation: Conversation = sqlx::query_as("SELECT * FROM conversations WHERE id = ?1")
+        .bind(id)
+        .fetch_one(&state.db)
+        .await?;
+
+    load_conversation_tags(&mut conversation, &state.db).await?;
+    Ok(conversation)
+}
+
+fn tag_not_found() -> ValidationError {
+    ValidationError {
+        error: "Tag not found".to_string(),
+        details: vec![ValidationDetail {
+            field: "tag_id".to_string(),
+            messages: vec!["No tag with this ID for the current user.".to_string()],
+        }],
+    }
+}
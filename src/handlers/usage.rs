@@ -0,0 +1,147 @@
+use std::sync::Arc;
+
+use axum::{
+    Extension, Json,
+    extract::{Query, State},
+};
+use chrono::{DateTime, Datelike, TimeZone, Utc};
+use serde::Deserialize;
+
+use crate::{
+    database::connection::{get_token_budget, tokens_spent_since},
+    errors::api_errors::AppError,
+    models::{
+        ai::{DailyUsage, Usage},
+        app::AppState,
+        auth::TokenClaims,
+    },
+    utils::validation::{ValidationDetail, ValidationError},
+};
+
+/// Window reported when the request doesn't bound it.
+const DEFAULT_WINDOW_SECS: i64 = 30 * 24 * 60 * 60;
+
+/// Widest window a single request may ask for.
+const MAX_WINDOW_SECS: i64 = 366 * 24 * 60 * 60;
+
+#[derive(Deserialize)]
+pub struct UsageParams {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+}
+
+/// The caller's message and token totals over `?from=&to=`, the last 30 days by default.
+pub async fn get_usage(
+    Extension(user_data): Extension<TokenClaims>,
+    State(state): State<Arc<AppState>>,
+    Query(params): Query<UsageParams>,
+) -> Result<Json<Usage>, AppError> {
+    let to = params.to.unwrap_or_else(|| Utc::now().timestamp());
+    let from = params.from.unwrap_or(to - DEFAULT_WINDOW_SECS);
+    validate_window(from, to)?;
+
+    let days: Vec<DailyUsage> = sqlx::query_as(
+        "SELECT date(m.timestamp, 'unixepoch') AS day, COUNT(*) AS messages,
+    COALESCE(SUM(m.token_count), 0) AS tokens
+FROM messages m JOIN conversations c ON c.id = m.conversation_id
+WHERE c.user_id = ?1 AND m.timestamp BETWEEN ?2 AND ?3
+GROUP BY day ORDER BY day ASC",
+    )
+    .bind(user_data.user_id)
+    .bind(from)
+    .bind(to)
+    .fetch_all(&state.db)
+    .await?;
+
+    Ok(Json(Usage {
+        from,
+        to,
+        total_messages: days.iter().map(|day| day.messages).sum(),
+        total_tokens: days.iter().map(|day| day.tokens).sum(),
+        days,
+    }))
+}
+
+fn validate_window(from: i64, to: i64) -> Result<(), ValidationError> {
+    let mut details = Vec::new();
+
+    if from < 0 {
+        details.push(ValidationDetail {
+            field: "from".to_string(),
+            messages: vec!["from must be a unix timestamp of 0 or later".to_string()],
+        });
+    }
+    if to < from {
+        details.push(ValidationDetail {
+            field: "to".to_string(),
+            messages: vec!["to must not be earlier than from".to_string()],
+        });
+    } else if to - from > MAX_WINDOW_SECS {
+        details.push(ValidationDetail {
+            field: "to".to_string(),
+            messages: vec!["The window can span at most 366 days".to_string()],
+        });
+    }
+
+    if details.is_empty() {
+        return Ok(());
+    }
+
+    Err(ValidationError {
+        error: "Invalid usage window".to_string(),
+        details,
+    })
+}
+
+/// The user's monthly budget when the current calendar month (UTC) has used it up, `None` while
+/// tokens remain or no budget applies. An account's own budget wins over the configured default,
+/// and a budget of 0 means no limit.
+pub async fn exhausted_token_budget(
+    state: &AppState,
+    user_id: i64,
+) -> Result<Option<i64>, sqlx::Error> {
+    let budget = get_token_budget(user_id, &state.db)
+        .await?
+        .unwrap_or(state.settings.monthly_token_budget);
+    if budget <= 0 {
+        return Ok(None);
+    }
+
+    let spent = tokens_spent_since(user_id, month_start(Utc::now()), &state.db).await?;
+    Ok((spent >= budget).then_some(budget))
+}
+
+/// Sent with a `429` over HTTP, or as a frame on chat sockets.
+pub fn token_budget_error(budget: i64) -> ValidationError {
+    ValidationError {
+        error: "Token budget exhausted".to_string(),
+        details: vec![ValidationDetail {
+            field: "tokens".to_string(),
+            messages: vec![format!(
+                "The monthly budget of {} tokens is used up; it resets at the start of next month (UTC).",
+                budget
+            )],
+        }],
+    }
+}
+
+fn month_start(now: DateTime<Utc>) -> i64 {
+    Utc.with_ymd_and_hms(now.year(), now.month(), 1, 0, 0, 0)
+        .single()
+        .expect("the first of a month at midnight UTC exists")
+        .timestamp()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn month_starts_at_midnight_utc_on_the_first() {
+        let now = Utc.with_ymd_and_hms(2024, 3, 31, 23, 59, 59).unwrap();
+        assert_eq!(month_start(now), 1_709_251_200);
+
+        let first = Utc.with_ymd_and_hms(2024, 3, 1, 0, 0, 0).unwrap();
+        assert_eq!(month_start(first), first.timestamp());
+    }
+}
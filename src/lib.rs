@@ -1,6 +1,8 @@
-mod models;
-mod errors;
-mod database;
-mod middleware;
-mod handlers;
-mod utils;
\ No newline at end of file
+pub mod ai;
+pub mod models;
+pub mod errors;
+pub mod database;
+pub mod middleware;
+pub mod handlers;
+pub mod utils;
+pub mod routes;
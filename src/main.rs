@@ -1,122 +1,217 @@
-use std::{env, net::SocketAddr, sync::Arc};
+use std::{env, net::SocketAddr, sync::Arc, time::Duration};
 
 use axum::{
-    Router,
-    http::Method,
-    routing::{any, delete, get, post},
+    ServiceExt,
+    extract::Request,
+    http::{HeaderValue, Method, header},
 };
 
-use axum::extract::connect_info::IntoMakeServiceWithConnectInfo;
-use axum::middleware as axum_middleware;
-
-mod models;
-
-mod errors;
-
-mod database;
-
-mod middleware;
-use middleware::auth::auth_middleware;
-
-mod handlers;
-use handlers::ai::analyze_text;
 use tower::ServiceBuilder;
-use tower_governor::{
-    GovernorLayer, governor::GovernorConfigBuilder, key_extractor::PeerIpKeyExtractor,
-};
-mod utils;
 
-use crate::{
+use rback::{
+    ai::{AiProvider, gemini::GeminiProvider},
     database::connection::connect_to_database,
-    handlers::{
-        ai::{
-            create_conversation, delete_conversation_by_id, delete_message_by_id,
-            get_conversation_messages_by_id, get_user_conversations, get_user_conversations_by_id,
-            post_user_message, update_conversation_by_id,
-        },
-        auth::{login, logout, refresh, register},
-    },
-    models::app::AppState,
+    handlers::ai::{IDEMPOTENCY_KEY, IDEMPOTENT_REPLAYED},
+    middleware::request_id::X_REQUEST_ID,
+    models::app::{AppState, Settings},
+    routes::{router, trim_trailing_slash},
 };
 
 use tower_http::{
+    LatencyUnit,
     cors::{Any, CorsLayer},
-    trace::TraceLayer,
+    trace::{DefaultOnResponse, TraceLayer},
 };
+use tracing::Level;
+use tracing_subscriber::EnvFilter;
 
 #[tokio::main]
 async fn main() {
-    let pool = connect_to_database().await;
+    // RUST_LOG controls verbosity, e.g. `RUST_LOG=debug` or `RUST_LOG=rback=debug,tower_http=info`
+    tracing_subscriber::fmt()
+        .with_env_filter(
+            EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info")),
+        )
+        .init();
+
+    let settings = Settings::from_env();
+    let pool = connect_to_database(&settings).await;
 
     let salt = env::var("SALT").expect("Salt was not provided");
     let access_key = env::var("SECRET_KEY_ACCESS").expect("Secret key was not provided");
     let refresh_key = env::var("SECRET_KEY_REFRESH").expect("Refresh key was not provided");
+    let ai_provider: Arc<dyn AiProvider> = match settings.ai_provider.as_str() {
+        "gemini" => {
+            let api_key = env::var("GEMINI_API_KEY").expect("API key was not provided");
+            Arc::new(GeminiProvider::new(api_key.into()))
+        }
+        other => panic!("Unknown AI_PROVIDER {:?}, expected \"gemini\"", other),
+    };
 
-    let connection_db = Arc::new(AppState::new(
-        pool.clone(),
-        pool.clone(),
+    if settings.refresh_ttl_secs <= settings.access_ttl_secs {
+        panic!(
+            "REFRESH_TOKEN_TTL_SECS ({}) must be greater than ACCESS_TOKEN_TTL_SECS ({})",
+            settings.refresh_ttl_secs, settings.access_ttl_secs
+        );
+    }
+
+    let mut state = AppState::new(
         pool.clone(),
         salt.into(),
         access_key.into(),
         refresh_key.into(),
-    ));
-
-    let governor_conf = Arc::new(
-        GovernorConfigBuilder::default()
-            .per_second(1)
-            .burst_size(5)
-            .key_extractor(PeerIpKeyExtractor)
-            .finish()
-            .unwrap(),
+        ai_provider,
+        settings,
+    );
+    // Two-factor enrollment is refused until a key to seal the secrets with is configured
+    if let Ok(totp_key) = env::var("TOTP_ENCRYPTION_KEY") {
+        state = state.with_totp_key(totp_key.into());
+    }
+    let connection_db = Arc::new(state);
+
+    let cors_layer = cors_layer(connection_db.settings.dev_mode);
+
+    let app = router(connection_db.clone())
+        .layer(
+            ServiceBuilder::new()
+                // Only the path is recorded: query strings can carry tokens (`/verify`, the websocket)
+                .layer(
+                    TraceLayer::new_for_http()
+                        .make_span_with(|req: &axum::extract::Request| {
+                            tracing::info_span!(
+                                "request",
+                                method = %req.method(),
+                                path = %req.uri().path(),
+                                // Filled in by the router's request id middleware
+                                request_id = tracing::field::Empty,
+                            )
+                        })
+                        .on_response(
+                            DefaultOnResponse::new()
+                                .level(Level::INFO)
+                                .latency_unit(LatencyUnit::Millis),
+                        ),
+                )
+                .layer(cors_layer),
+        );
+
+    let app = ServiceExt::<Request>::into_make_service_with_connect_info::<SocketAddr>(
+        trim_trailing_slash(app),
     );
 
-    let ai_governor_layer = GovernorLayer {
-        config: governor_conf,
-    };
+    // BIND_ADDR wins over HOST/PORT so a full address can be given in one variable
+    let bind_addr = env::var("BIND_ADDR").unwrap_or_else(|_| {
+        let host = env::var("HOST").unwrap_or_else(|_| "127.0.0.1".to_string());
+        let port = env::var("PORT").unwrap_or_else(|_| "4006".to_string());
+        format!("{}:{}", host, port)
+    });
+    let bind_addr: SocketAddr = bind_addr
+        .parse()
+        .unwrap_or_else(|e| panic!("Invalid bind address {:?}: {}", bind_addr, e));
+
+    let listener = tokio::net::TcpListener::bind(bind_addr)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to bind {}: {}", bind_addr, e));
 
-    let cors_layer = CorsLayer::new()
-        .allow_origin(Any)
-        .allow_methods(Any)
-        .allow_headers(Any);
+    tracing::info!(
+        addr = %listener.local_addr().unwrap_or(bind_addr),
+        "listening"
+    );
 
-    let app = Router::new()
-        .route("/text", get(analyze_text).layer(ai_governor_layer))
-        .route(
-            "/conversations",
-            get(get_user_conversations).post(create_conversation),
-        )
-        .route(
-            "/conversations/{id}",
-            get(get_user_conversations_by_id)
-                .put(update_conversation_by_id)
-                .delete(delete_conversation_by_id),
-        )
-        .route(
-            "/conversations/{id}/messages/{message_id}",
-            delete(delete_message_by_id),
-        )
-        .route(
-            "/conversations/{id}/messages",
-            get(get_conversation_messages_by_id),
-        )
-        .layer(axum_middleware::from_fn(auth_middleware))
-        .route("/refresh", post(refresh))
-        .route("/register", post(register))
-        .route("/login", post(login))
-        .route("/logout", post(logout))
-        .route("/conversations_ws", get(post_user_message))
+    axum::serve(listener, app)
+        .with_graceful_shutdown(shutdown_signal(connection_db.clone()))
+        .await
+        .unwrap();
 
-        .layer(ServiceBuilder::new().layer(cors_layer))
-        .with_state(connection_db);
+    // Upgraded websockets aren't tracked by the server, so replies they are still streaming get
+    // a grace period to be stored before the pool goes away
+    let grace = Duration::from_secs(connection_db.settings.shutdown_grace_secs);
+    let waited = tokio::time::timeout(grace, async {
+        while connection_db.has_active_generations() {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+    })
+    .await;
+    if waited.is_err() {
+        tracing::warn!("shutdown grace period elapsed with replies still streaming");
+    }
+
+    pool.close().await;
+    tracing::info!("shutdown complete");
+}
 
-    let app: IntoMakeServiceWithConnectInfo<Router, SocketAddr> =
-        app.into_make_service_with_connect_info();
+/// Resolves on Ctrl+C or SIGTERM and tells open websockets to wind down.
+async fn shutdown_signal(state: Arc<AppState>) {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("Failed to listen for Ctrl+C");
+    };
 
-    let listener = tokio::net::TcpListener::bind("127.0.0.1:4006")
-        .await
-        .unwrap();
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Failed to listen for SIGTERM")
+            .recv()
+            .await;
+    };
 
-    println!("listening to 4006");
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+
+    tracing::info!("shutdown signal received, draining connections");
+    state.begin_shutdown();
+}
 
-    axum::serve(listener, app).await.unwrap();
+/// Browsers may only call the API from the origins in `CORS_ALLOWED_ORIGINS` (comma-separated),
+/// with credentials allowed. Without that list, any origin is accepted in dev mode and none otherwise.
+fn cors_layer(dev_mode: bool) -> CorsLayer {
+    let origins: Vec<HeaderValue> = env::var("CORS_ALLOWED_ORIGINS")
+        .unwrap_or_default()
+        .split(',')
+        .map(str::trim)
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            origin
+                .parse()
+                .unwrap_or_else(|_| panic!("Invalid origin in CORS_ALLOWED_ORIGINS: {:?}", origin))
+        })
+        .collect();
+
+    if !origins.is_empty() {
+        return CorsLayer::new()
+            .allow_origin(origins)
+            .allow_methods([
+                Method::GET,
+                Method::POST,
+                Method::PUT,
+                Method::PATCH,
+                Method::DELETE,
+                Method::OPTIONS,
+            ])
+            .allow_headers([
+                header::AUTHORIZATION,
+                header::CONTENT_TYPE,
+                X_REQUEST_ID.clone(),
+                IDEMPOTENCY_KEY.clone(),
+            ])
+            .expose_headers([X_REQUEST_ID.clone(), IDEMPOTENT_REPLAYED.clone()])
+            .allow_credentials(true);
+    }
+
+    if dev_mode {
+        CorsLayer::new()
+            .allow_origin(Any)
+            .allow_methods(Any)
+            .allow_headers(Any)
+            .expose_headers([X_REQUEST_ID.clone(), IDEMPOTENT_REPLAYED.clone()])
+    } else {
+        tracing::warn!("CORS_ALLOWED_ORIGINS is not set, cross-origin requests will be refused");
+        CorsLayer::new()
+    }
 }
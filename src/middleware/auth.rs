@@ -1,36 +1,98 @@
-use std::env;
+use std::sync::Arc;
 
 use axum::{
-    extract::Request,
-    http::{HeaderMap, StatusCode},
+    extract::{Request, State},
+    http::HeaderMap,
     middleware::Next,
     response::Response,
 };
 use jsonwebtoken::{Algorithm, DecodingKey, TokenData, Validation, decode};
 
-use crate::models::auth::TokenClaims;
+use crate::{
+    errors::api_errors::AuthError,
+    models::{
+        app::AppState,
+        auth::{Role, TokenClaims},
+    },
+};
 
 #[allow(unused)]
 pub async fn auth_middleware(
+    State(state): State<Arc<AppState>>,
     headers: HeaderMap,
     mut req: Request,
     next: Next,
-) -> Result<Response, StatusCode> {
+) -> Result<Response, AuthError> {
     let auth_header = headers
         .get("Authorization")
-        .and_then(|h| h.to_str().ok())
-        .ok_or(StatusCode::UNAUTHORIZED)?;
+        .ok_or(AuthError::MissingHeader)?
+        .to_str()
+        .map_err(|_| AuthError::MalformedHeader)?;
+
+    let token = auth_header.strip_prefix("Bearer ").ok_or_else(|| {
+        tracing::debug!("authorization header is not a bearer token");
+        AuthError::MalformedHeader
+    })?;
+
+    let claims = authenticate(&state, token).await?;
 
-    if !auth_header.starts_with("Bearer") {
-        println!("ERROR: Header doesn't start with Bearer");
-        return Err(StatusCode::UNAUTHORIZED);
+    req.extensions_mut().insert(claims);
+    Ok(next.run(req).await)
+}
+
+/// Lets a request through only when its token carries `role`. Layered with
+/// `from_fn_with_state(Role::Admin, require_role)` on routes that `auth_middleware` also covers.
+pub async fn require_role(
+    State(role): State<Role>,
+    req: Request,
+    next: Next,
+) -> Result<Response, AuthError> {
+    let claims = req
+        .extensions()
+        .get::<TokenClaims>()
+        .ok_or(AuthError::MissingHeader)?;
+
+    if claims.role != role {
+        tracing::debug!(user_id = claims.user_id, ?role, "route requires another role");
+        return Err(AuthError::Forbidden);
     }
 
-    let token = &auth_header[7..];
+    Ok(next.run(req).await)
+}
 
-    let validation = Validation::new(Algorithm::HS256);
+/// Decodes an access token and rejects it if its login session has been revoked or its account
+/// is gone or disabled.
+pub async fn authenticate(state: &AppState, token: &str) -> Result<TokenClaims, AuthError> {
+    let claims = decode_access_token(token, &state.get_access_key())?;
+
+    let active = state.account_active(claims.user_id).await.map_err(|e| {
+        tracing::error!(error = %e, "account status lookup failed");
+        AuthError::Unavailable
+    })?;
+
+    if !active {
+        tracing::debug!(user_id = claims.user_id, "token belongs to a deleted or disabled account");
+        return Err(AuthError::AccountInactive);
+    }
+
+    let revoked = sqlx::query_scalar::<_, i64>("SELECT 1 FROM revoked_sessions WHERE sid = ?")
+        .bind(&claims.sid)
+        .fetch_optional(&state.db)
+        .await
+        .map_err(|e| {
+            tracing::error!(error = %e, "session revocation lookup failed");
+            AuthError::Unavailable
+        })?;
+
+    if revoked.is_some() {
+        return Err(AuthError::SessionRevoked);
+    }
 
-    let access_key = env::var("SECRET_KEY_ACCESS").expect("SECRET_KEY_ACCESS not provided");
+    Ok(claims)
+}
+
+pub fn decode_access_token(token: &str, access_key: &str) -> Result<TokenClaims, AuthError> {
+    let validation = Validation::new(Algorithm::HS256);
 
     let user_token: TokenData<TokenClaims> = decode::<TokenClaims>(
         token,
@@ -38,11 +100,35 @@ pub async fn auth_middleware(
         &validation,
     )
     .map_err(|e| {
-        println!("FINAL ERROR: {:?}", e);
-        StatusCode::UNAUTHORIZED
+        tracing::debug!(error = %e, "access token rejected");
+        AuthError::from(e)
     })?;
 
+    // A refresh token must not pass as a bearer token, even while its signature checks out
+    if user_token.claims.token_type != "Access" {
+        tracing::debug!(token_type = %user_token.claims.token_type, "not an access token");
+        return Err(AuthError::InvalidToken);
+    }
 
-    req.extensions_mut().insert(user_token.claims);
-    Ok(next.run(req).await)
+    Ok(user_token.claims)
+}
+
+/// Browsers can't set `Authorization` on a WebSocket handshake, so the token travels either as
+/// `?token=` or as the second entry of `Sec-WebSocket-Protocol: bearer, <token>`.
+pub fn websocket_token(headers: &HeaderMap, query_token: Option<&str>) -> Result<String, AuthError> {
+    if let Some(token) = query_token {
+        return Ok(token.to_string());
+    }
+
+    let protocols = headers
+        .get("Sec-WebSocket-Protocol")
+        .ok_or(AuthError::MissingHeader)?
+        .to_str()
+        .map_err(|_| AuthError::MalformedHeader)?;
+
+    let mut parts = protocols.split(',').map(str::trim);
+    match (parts.next(), parts.next()) {
+        (Some("bearer"), Some(token)) if !token.is_empty() => Ok(token.to_string()),
+        _ => Err(AuthError::MalformedHeader),
+    }
 }
@@ -0,0 +1,133 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::{extract::ConnectInfo, http::Request};
+use tower_governor::{GovernorError, key_extractor::KeyExtractor};
+
+use crate::models::auth::TokenClaims;
+
+#[derive(Clone, Debug, Hash, PartialEq, Eq)]
+pub enum RateLimitKey {
+    User(i64),
+    Ip(IpAddr),
+}
+
+/// Buckets requests by the authenticated user, so accounts behind one NAT don't share a limit.
+/// Requests without claims fall back to the peer IP. Must sit inside `auth_middleware` to see
+/// the claims.
+#[derive(Clone, Copy, Debug)]
+pub struct UserKeyExtractor;
+
+impl KeyExtractor for UserKeyExtractor {
+    type Key = RateLimitKey;
+
+    fn extract<T>(&self, req: &Request<T>) -> Result<Self::Key, GovernorError> {
+        if let Some(claims) = req.extensions().get::<TokenClaims>() {
+            return Ok(RateLimitKey::User(claims.user_id));
+        }
+
+        req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| RateLimitKey::Ip(addr.ip()))
+            .ok_or(GovernorError::UnableToExtractKey)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use axum::{
+        Router,
+        body::Body,
+        extract::{ConnectInfo, Request},
+        http::StatusCode,
+        middleware::{self, Next},
+        routing::get,
+    };
+    use tower::ServiceExt;
+    use tower_governor::{GovernorLayer, governor::GovernorConfigBuilder};
+
+    use super::*;
+    use crate::models::auth::Role;
+
+    fn claims(user_id: i64) -> TokenClaims {
+        TokenClaims {
+            name: format!("user{}", user_id),
+            email: format!("user{}@example.com", user_id),
+            user_id,
+            exp: 0,
+            token_type: "access".to_string(),
+            used: false,
+            jti: String::new(),
+            sid: String::new(),
+            role: Role::User,
+        }
+    }
+
+    fn request_from(peer: &str, user_id: Option<i64>) -> Request<Body> {
+        let mut request = Request::new(Body::empty());
+        let addr: SocketAddr = peer.parse().unwrap();
+        request.extensions_mut().insert(ConnectInfo(addr));
+        if let Some(user_id) = user_id {
+            request.extensions_mut().insert(claims(user_id));
+        }
+        request
+    }
+
+    #[test]
+    fn keys_by_user_when_claims_are_present() {
+        let key = UserKeyExtractor.extract(&request_from("10.0.0.1:1000", Some(7)));
+        assert_eq!(key.unwrap(), RateLimitKey::User(7));
+    }
+
+    #[test]
+    fn falls_back_to_the_peer_ip() {
+        let key = UserKeyExtractor.extract(&request_from("10.0.0.1:1000", None));
+        assert_eq!(key.unwrap(), RateLimitKey::Ip("10.0.0.1".parse().unwrap()));
+    }
+
+    #[test]
+    fn fails_without_claims_or_connect_info() {
+        let key = UserKeyExtractor.extract(&Request::new(Body::empty()));
+        assert!(matches!(key, Err(GovernorError::UnableToExtractKey)));
+    }
+
+    #[tokio::test]
+    async fn users_behind_one_ip_get_separate_buckets() {
+        let config = Arc::new(
+            GovernorConfigBuilder::default()
+                .per_second(60)
+                .burst_size(1)
+                .key_extractor(UserKeyExtractor)
+                .finish()
+                .unwrap(),
+        );
+
+        // Stands in for auth_middleware, which would decode the claims from the bearer token
+        async fn fake_auth(mut request: Request, next: Next) -> axum::response::Response {
+            let user_id = request.uri().query().and_then(|q| q.parse().ok());
+            if let Some(user_id) = user_id {
+                request.extensions_mut().insert(claims(user_id));
+            }
+            next.run(request).await
+        }
+
+        let router = Router::new()
+            .route("/", get(|| async { "ok" }))
+            .layer(GovernorLayer { config })
+            .layer(middleware::from_fn(fake_auth));
+
+        let send = |user_id: i64| {
+            let mut request = Request::get(format!("/?{}", user_id))
+                .body(Body::empty())
+                .unwrap();
+            let addr: SocketAddr = "10.0.0.1:1000".parse().unwrap();
+            request.extensions_mut().insert(ConnectInfo(addr));
+            router.clone().oneshot(request)
+        };
+
+        assert_eq!(send(1).await.unwrap().status(), StatusCode::OK);
+        assert_eq!(send(1).await.unwrap().status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(send(2).await.unwrap().status(), StatusCode::OK);
+    }
+}
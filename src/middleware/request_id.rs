@@ -0,0 +1,128 @@
+use axum::{
+    body::{Body, Bytes, HttpBody},
+    extract::Request,
+    http::{HeaderName, HeaderValue, header},
+    middleware::Next,
+    response::Response,
+};
+use futures::{StreamExt, future, stream};
+use uuid::Uuid;
+
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+/// Longest client-supplied id that is passed through; anything else gets a fresh one.
+const MAX_REQUEST_ID_LEN: usize = 128;
+
+/// Error bodies bigger than this are passed through without the id.
+const MAX_ERROR_BODY_BYTES: usize = 64 * 1024;
+
+/// The id of the request being handled, in the request extensions.
+#[derive(Clone, Debug)]
+pub struct RequestId(pub String);
+
+/// Keeps the caller's `X-Request-Id` or makes one up, records it on the current `request` span,
+/// echoes it in the response header and adds it to JSON error bodies as `request_id`.
+pub async fn request_id(mut req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_usable(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    tracing::Span::current().record("request_id", id.as_str());
+    req.extensions_mut().insert(RequestId(id.clone()));
+
+    let mut response = next.run(req).await;
+    if is_json_error(&response) {
+        response = with_request_id_in_body(response, &id).await;
+    }
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        response.headers_mut().insert(X_REQUEST_ID.clone(), value);
+    }
+    response
+}
+
+fn is_usable(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.bytes().all(|b| b.is_ascii_graphic())
+}
+
+fn is_json_error(response: &Response) -> bool {
+    let status = response.status();
+    (status.is_client_error() || status.is_server_error())
+        && response
+            .headers()
+            .get(header::CONTENT_TYPE)
+            .is_some_and(|value| value.as_bytes().starts_with(b"application/json"))
+}
+
+async fn with_request_id_in_body(response: Response, id: &str) -> Response {
+    let (mut parts, body) = response.into_parts();
+
+    let declared_len = parts
+        .headers
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse::<u64>().ok())
+        .or_else(|| body.size_hint().exact());
+    if declared_len.is_some_and(|len| len > MAX_ERROR_BODY_BYTES as u64) {
+        return Response::from_parts(parts, body);
+    }
+
+    // Read chunk by chunk, so a body that turns out too big can still be sent on in full
+    let mut chunks = body.into_data_stream();
+    let mut buffered = Vec::new();
+    while let Some(chunk) = chunks.next().await {
+        let Ok(chunk) = chunk else {
+            // The body is gone at this point, so the status alone has to do
+            parts.headers.remove(header::CONTENT_LENGTH);
+            return Response::from_parts(parts, Body::empty());
+        };
+        buffered.extend_from_slice(&chunk);
+        if buffered.len() > MAX_ERROR_BODY_BYTES {
+            let read = stream::once(future::ready(Ok::<_, axum::Error>(Bytes::from(buffered))));
+            return Response::from_parts(parts, Body::from_stream(read.chain(chunks)));
+        }
+    }
+    let bytes = Bytes::from(buffered);
+
+    let body = match serde_json::from_slice::<serde_json::Value>(&bytes) {
+        Ok(serde_json::Value::Object(mut object)) => {
+            object.insert("request_id".to_string(), id.into());
+            serde_json::to_vec(&object).map(Body::from).unwrap_or_else(|_| Body::from(bytes))
+        }
+        _ => Body::from(bytes),
+    };
+
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_short_printable_ids_are_kept() {
+        assert!(is_usable("3f1c-abc_DEF.42"));
+        assert!(!is_usable(""));
+        assert!(!is_usable("has space"));
+        assert!(!is_usable("line\nbreak"));
+        assert!(!is_usable(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+    }
+
+    #[tokio::test]
+    async fn streamed_body_over_the_limit_passes_through_in_full() {
+        let json = format!("{{\"error\": \"{}\"}}", "x".repeat(MAX_ERROR_BODY_BYTES));
+        let chunks: Vec<Result<Bytes, axum::Error>> =
+            json.as_bytes().chunks(1000).map(|chunk| Ok(Bytes::copy_from_slice(chunk))).collect();
+        let response = Response::new(Body::from_stream(stream::iter(chunks)));
+
+        let response = with_request_id_in_body(response, "id-1").await;
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(body, json.as_bytes());
+    }
+}
@@ -1,10 +1,55 @@
 use axum::{Json, http::StatusCode, response::IntoResponse};
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
+use validator::Validate;
 
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct Message {
     pub msg: String,
+    // One of GEMINI_ALLOWED_MODELS; the configured default when absent
+    pub model: Option<String>,
+    // `data:` or `https:` URLs read along with this prompt, only by models with vision; not stored
+    #[serde(default)]
+    #[validate(
+        length(max = 4, message = "At most 4 images can be sent with a prompt"),
+        custom(function = "validate_images", message = "Images must be data: or https: URLs")
+    )]
+    pub images: Vec<String>,
+    #[serde(flatten)]
+    #[validate(nested)]
+    pub params: GenerationParams,
+}
+
+fn validate_images(images: &[String]) -> Result<(), validator::ValidationError> {
+    if images.iter().all(|image| image.starts_with("data:image/") || image.starts_with("https://")) {
+        Ok(())
+    } else {
+        Err(validator::ValidationError::new("invalid_image"))
+    }
+}
+
+/// Sampling controls for a reply. Accepted ranges: `temperature` 0 to 2, `top_p` 0 to 1 and
+/// `max_output_tokens` 1 to 8192. Anything left out falls back to the conversation's saved
+/// settings, then to the model's own default.
+#[derive(Serialize, Deserialize, Validate, FromRow, Clone, Copy, Debug, Default, PartialEq)]
+pub struct GenerationParams {
+    #[validate(range(min = 0.0, max = 2.0, message = "Temperature must be between 0 and 2"))]
+    pub temperature: Option<f32>,
+    #[validate(range(min = 0.0, max = 1.0, message = "top_p must be between 0 and 1"))]
+    pub top_p: Option<f32>,
+    #[validate(range(min = 1, max = 8192, message = "max_output_tokens must be between 1 and 8192"))]
+    pub max_output_tokens: Option<i32>,
+}
+
+impl GenerationParams {
+    /// Keeps the values set here and takes the rest from `defaults`.
+    pub fn or(self, defaults: GenerationParams) -> GenerationParams {
+        GenerationParams {
+            temperature: self.temperature.or(defaults.temperature),
+            top_p: self.top_p.or(defaults.top_p),
+            max_output_tokens: self.max_output_tokens.or(defaults.max_output_tokens),
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize)]
@@ -19,6 +64,27 @@ pub struct Conversation {
     pub title: String,
     pub created_at: i64,
     pub updated_at: i64,
+    // Set when the user hides the conversation instead of deleting it
+    pub archived_at: Option<i64>,
+    // Bumped on every title, archive or system prompt change, for optimistic concurrency
+    pub version: i64,
+    // Instruction sent with every request in this conversation
+    pub system_prompt: Option<String>,
+    // Listed above unpinned conversations
+    pub pinned: bool,
+    // Position the user dragged it to, ascending; unplaced ones follow by recency
+    pub sort_order: Option<i64>,
+    // Filled in from `conversation_tags` after the row is read
+    #[sqlx(skip)]
+    pub tags: Vec<Tag>,
+}
+
+/// A user's label for grouping conversations; names are unique per user, ignoring case.
+#[derive(Serialize, Deserialize, Debug, Clone, FromRow)]
+pub struct Tag {
+    pub id: i64,
+    pub name: String,
+    pub created_at: i64,
 }
 
 impl IntoResponse for Conversation {
@@ -29,20 +95,223 @@ impl IntoResponse for Conversation {
 
 #[derive(Serialize, Deserialize, Debug, FromRow)]
 pub struct ConvMessage {
-    conversation_id: i64,
-    role: String,
-    content: String,
-    timestamp: i64,
-    token_count: i64,
+    pub id: i64,
+    pub conversation_id: i64,
+    pub role: String,
+    pub content: String,
+    pub timestamp: i64,
+    pub token_count: i64,
+    // Model that wrote an assistant reply
+    pub model: Option<String>,
+    // The user stopped this reply before it was complete
+    pub stopped: bool,
+    // From sending the request to the model until its last chunk arrived
+    pub latency_ms: Option<i64>,
+    // Why the model ended the reply as it reported it, e.g. `STOP` or `MAX_TOKENS`
+    pub finish_reason: Option<String>,
+    // The reply was served from the response cache instead of being generated again
+    pub cached: bool,
+}
+
+/// What's recorded about how an assistant reply was produced; left empty for user messages.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ReplyMeta<'a> {
+    pub model: Option<&'a str>,
+    pub stopped: bool,
+    pub latency_ms: Option<i64>,
+    pub finish_reason: Option<&'a str>,
+    pub cached: bool,
+}
+
+#[derive(Serialize, Debug)]
+pub struct MessagePage {
+    pub items: Vec<ConvMessage>,
+    pub page: u32,
+    pub limit: u32,
+    pub total: i64,
+    pub total_pages: i64,
+    // Pass as `after` for the following messages; null when this page reaches the newest one
+    pub next_cursor: Option<i64>,
+    // Pass as `before` for the preceding messages; null when this page starts at the oldest one
+    pub prev_cursor: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ConversationPage {
+    pub items: Vec<Conversation>,
+    pub page: u32,
+    pub limit: u32,
+    pub total: i64,
+    pub total_pages: i64,
 }
 
 #[derive(Deserialize, Debug)]
 pub struct UserMessage {
     pub conversation_id: i64,
+    pub token: Option<String>,
+    // Model that answers every message sent over this socket
+    pub model: Option<String>,
+    // Spelled out rather than flattened: query strings can't deserialize numbers through a flatten
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_output_tokens: Option<i32>,
+}
+
+impl UserMessage {
+    pub fn params(&self) -> GenerationParams {
+        GenerationParams {
+            temperature: self.temperature,
+            top_p: self.top_p,
+            max_output_tokens: self.max_output_tokens,
+        }
+    }
+}
+
+/// JSON frames a chat socket accepts besides prompts, e.g. `{"type":"stop"}` to cut the reply
+/// being streamed short.
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ControlFrame {
+    Stop,
+}
+
+/// A prompt sent as JSON so it can carry an idempotency key, e.g.
+/// `{"msg": "hi", "idempotency_key": "3f1c..."}`. Resending it with the same key replays the stored
+/// exchange instead of asking the model again.
+#[derive(Deserialize, Debug)]
+pub struct KeyedPrompt {
+    pub msg: String,
+    pub idempotency_key: String,
 }
 
+pub const MAX_TITLE_CHARS: u64 = 200;
+
 //For updating conversation title
-#[derive(Deserialize)]
+#[derive(Deserialize, Validate)]
 pub struct Title {
-    pub title: String
-}
\ No newline at end of file
+    #[validate(
+        length(min = 1, max = MAX_TITLE_CHARS, message = "Title must be between 1 and 200 characters"),
+        custom(function = "validate_title", message = "Title must not be blank or contain control characters")
+    )]
+    pub title: String,
+    // version the client last saw; a mismatch means someone else changed the conversation
+    pub expected_version: Option<i64>,
+    // updated_at the client last saw, checked the same way; whole seconds, so two writes within
+    // one second aren't told apart and expected_version is the safer precondition
+    pub expected_updated_at: Option<i64>,
+}
+
+fn validate_title(title: &str) -> Result<(), validator::ValidationError> {
+    if title.trim().is_empty() || title.chars().any(char::is_control) {
+        Err(validator::ValidationError::new("invalid_title"))
+    } else {
+        Ok(())
+    }
+}
+
+//For starting a conversation; the body is optional
+#[derive(Deserialize, Validate, Default)]
+pub struct NewConversation {
+    #[validate(length(max = 4000, message = "System prompt must be at most 4000 characters"))]
+    pub system_prompt: Option<String>,
+}
+
+//For setting or clearing (null or empty) the system prompt
+#[derive(Deserialize, Validate)]
+pub struct SystemPrompt {
+    #[validate(length(max = 4000, message = "System prompt must be at most 4000 characters"))]
+    pub system_prompt: Option<String>,
+    pub expected_version: Option<i64>,
+    pub expected_updated_at: Option<i64>,
+}
+
+//For creating a tag
+#[derive(Deserialize)]
+pub struct NewTag {
+    pub name: String,
+}
+
+//For pinning; without a body the pin is toggled
+#[derive(Deserialize, Default)]
+pub struct Pin {
+    pub pinned: Option<bool>,
+}
+
+//For placing a conversation by hand, null to go back to ordering by recency
+#[derive(Deserialize)]
+pub struct Position {
+    pub sort_order: Option<i64>,
+}
+
+//For moving a message into another conversation
+#[derive(Deserialize)]
+pub struct MoveMessage {
+    pub target_conversation_id: i64,
+}
+
+//For editing a user message before re-asking
+#[derive(Deserialize)]
+pub struct EditMessage {
+    pub content: String,
+}
+
+//For importing a chat from another tool
+#[derive(Deserialize, Validate)]
+pub struct ImportConversation {
+    #[validate(
+        length(min = 1, max = MAX_TITLE_CHARS, message = "Title must be between 1 and 200 characters"),
+        custom(function = "validate_title", message = "Title must not be blank or contain control characters")
+    )]
+    pub title: String,
+    #[validate(length(min = 1, max = 1000, message = "Between 1 and 1000 messages can be imported at once"))]
+    pub messages: Vec<ImportedMessage>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ImportedMessage {
+    // `user`, `assistant` or `system`
+    pub role: String,
+    pub content: String,
+    // Unix seconds from the original chat
+    pub timestamp: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ImportedConversation {
+    #[serde(flatten)]
+    pub conversation: Conversation,
+    pub messages: Vec<ConvMessage>,
+}
+
+//For deleting several conversations at once
+#[derive(Deserialize, Validate)]
+pub struct BulkDelete {
+    #[validate(length(min = 1, max = 500, message = "Between 1 and 500 ids can be deleted at once"))]
+    pub ids: Vec<i64>,
+}
+
+#[derive(Serialize)]
+pub struct BulkDeleteResult {
+    pub deleted: usize,
+    // Ids that don't exist or belong to someone else
+    pub not_found: Vec<i64>,
+}
+
+/// Messages and tokens in the caller's conversations between `from` and `to` (unix seconds,
+/// inclusive), with one entry per UTC day that had any.
+#[derive(Serialize, Debug)]
+pub struct Usage {
+    pub from: i64,
+    pub to: i64,
+    pub total_messages: i64,
+    pub total_tokens: i64,
+    pub days: Vec<DailyUsage>,
+}
+
+#[derive(Serialize, Debug, FromRow)]
+pub struct DailyUsage {
+    // `YYYY-MM-DD`
+    pub day: String,
+    pub messages: i64,
+    pub tokens: i64,
+}
@@ -1,25 +1,451 @@
+use std::{
+    collections::{HashMap, hash_map::Entry},
+    env,
+    num::NonZeroU32,
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicU64, Ordering},
+    },
+    time::{Duration, Instant},
+};
+
+use dashmap::DashMap;
+use governor::{DefaultKeyedRateLimiter, Quota, RateLimiter};
 use secrecy::{ExposeSecret, SecretString};
 use sqlx::{Pool, Sqlite, SqlitePool};
+use tokio::sync::{broadcast, watch};
+
+use crate::{
+    ai::AiProvider,
+    ai::cache::ResponseCache,
+    utils::moderation::Moderator,
+};
+
+/// Assistant reply that is still being produced for a conversation.
+/// Sockets that join mid-generation get the partial text and then follow the event stream.
+pub struct Generation {
+    progress: Mutex<Progress>,
+    events: broadcast::Sender<GenerationEvent>,
+}
+
+struct Progress {
+    text: String,
+    end: Option<GenerationEnd>,
+}
+
+/// How a generation ended; `Failed` carries the error frame the generating socket received.
+#[derive(Clone, Debug, PartialEq)]
+pub enum GenerationEnd {
+    Done,
+    /// The user stopped it; what was produced so far is kept
+    Stopped,
+    Failed(String),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GenerationEvent {
+    Chunk(String),
+    End(GenerationEnd),
+}
+
+impl Generation {
+    fn new() -> Self {
+        let (events, _) = broadcast::channel(64);
+        Self {
+            progress: Mutex::new(Progress {
+                text: String::new(),
+                end: None,
+            }),
+            events,
+        }
+    }
+
+    pub fn push(&self, chunk: &str) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.text.push_str(chunk);
+        let _ = self.events.send(GenerationEvent::Chunk(chunk.to_string()));
+    }
+
+    // The end is recorded as well as broadcast, so sockets joining afterwards still see it
+    fn finish(&self, end: GenerationEnd) {
+        let mut progress = self.progress.lock().unwrap();
+        progress.end = Some(end.clone());
+        let _ = self.events.send(GenerationEvent::End(end));
+    }
+
+    /// Text produced after the first `offset` bytes, the end if already reached, and a receiver for
+    /// what follows. All three are taken under one lock so no event is lost or repeated.
+    pub fn join_from(
+        &self,
+        offset: usize,
+    ) -> (String, Option<GenerationEnd>, broadcast::Receiver<GenerationEvent>) {
+        let progress = self.progress.lock().unwrap();
+        let text = progress.text.get(offset..).unwrap_or_default().to_string();
+        (text, progress.end.clone(), self.events.subscribe())
+    }
+}
+
+/// Runtime options read from the environment at startup.
+pub struct Settings {
+    /// SQLite database to open, `sqlite::memory:` for a throwaway one (`DATABASE_URL`, default `sqlite://app.db`)
+    pub database_url: String,
+    /// Lifetime of access tokens (`ACCESS_TOKEN_TTL_SECS`, default 86400)
+    pub access_ttl_secs: i64,
+    /// Lifetime of refresh tokens, must exceed the access token's (`REFRESH_TOKEN_TTL_SECS`, default 604800)
+    pub refresh_ttl_secs: i64,
+    /// Apply Unicode NFC normalization to names, emails, titles and message content (`NORMALIZE_UNICODE`, default on)
+    pub normalize_unicode: bool,
+    /// Longest refresh token accepted before any hashing is attempted (`MAX_REFRESH_TOKEN_LEN`, default 2048)
+    pub max_refresh_token_len: usize,
+    /// How many earlier messages are replayed to the model with each prompt (`HISTORY_MAX_MESSAGES`, default 50)
+    pub history_max_messages: i64,
+    /// Reply time to aim for; while recent replies take longer, less history is replayed, in
+    /// proportion, 0 to always replay the full window (`TARGET_LATENCY_MS`, default 0)
+    pub target_latency_ms: u64,
+    /// Fewest earlier messages replayed however slow replies get (`HISTORY_MIN_MESSAGES`, default 4)
+    pub history_min_messages: i64,
+    /// Repeat the conversation's system prompt on every N-th user turn, 0 disables it (`SYSTEM_REMINDER_EVERY`, default 0)
+    pub system_reminder_every: usize,
+    /// Strip trailing whitespace and excess blank lines from assistant replies before storing them (`TIDY_ASSISTANT_WHITESPACE`, default off)
+    pub tidy_assistant_whitespace: bool,
+    /// Which model API replies come from; only `gemini` exists so far (`AI_PROVIDER`, default `gemini`)
+    pub ai_provider: String,
+    /// Model used when a request doesn't name one (`GEMINI_MODEL`, default `gemini-2.0-flash`)
+    pub gemini_model: String,
+    /// Models clients may ask for by name, comma-separated; the default model is always allowed
+    /// (`GEMINI_ALLOWED_MODELS`, default `gemini-2.0-flash,gemini-1.5-flash,gemini-1.5-pro`)
+    pub gemini_allowed_models: Vec<String>,
+    /// Deadline for a single Gemini call or streamed chunk (`GEMINI_TIMEOUT_SECS`, default 60)
+    pub gemini_timeout_secs: u64,
+    /// Retries for rate-limited, failing or timed out Gemini calls (`GEMINI_MAX_RETRIES`, default 2)
+    pub gemini_max_retries: u32,
+    /// Upper bound on pooled SQLite connections (`DB_MAX_CONNECTIONS`, default 10)
+    pub db_max_connections: u32,
+    /// How long a request waits for a free connection (`DB_ACQUIRE_TIMEOUT_SECS`, default 30)
+    pub db_acquire_timeout_secs: u64,
+    /// Idle connections are closed after this long (`DB_IDLE_TIMEOUT_SECS`, default 600)
+    pub db_idle_timeout_secs: u64,
+    /// Returns one-time tokens (email verification, password reset) in API responses
+    /// since no mailer is configured, and allows any CORS origin when no allowlist is set
+    /// (`DEV_MODE`, default false)
+    pub dev_mode: bool,
+    /// Make the very first account to register an admin, for bootstrapping a fresh database
+    /// (`FIRST_USER_IS_ADMIN`, default off)
+    pub first_user_is_admin: bool,
+    /// Lifetime of email verification tokens (`EMAIL_VERIFICATION_TTL_SECS`, default 86400)
+    pub email_verification_ttl_secs: i64,
+    /// Lifetime of password reset tokens (`PASSWORD_RESET_TTL_SECS`, default 900)
+    pub password_reset_ttl_secs: i64,
+    /// Wrong two-factor codes in a row before the account's second factor is locked (`TOTP_MAX_FAILURES`, default 5)
+    pub totp_max_failures: i64,
+    /// How long the second factor stays locked after too many wrong codes (`TOTP_LOCKOUT_SECS`, default 900)
+    pub totp_lockout_secs: i64,
+    /// Chat messages a user may send per minute over websockets (`WS_MESSAGES_PER_MINUTE`, default 20)
+    pub ws_messages_per_minute: u32,
+    /// Messages a user may send back to back before the rate applies (`WS_MESSAGE_BURST`, default 5)
+    pub ws_message_burst: u32,
+    /// Largest request body accepted, in bytes; bigger ones get a 413 (`MAX_BODY_BYTES`, default 1 MiB)
+    pub max_body_bytes: usize,
+    /// Compress responses with gzip or brotli when the client's `Accept-Encoding` allows it
+    /// (`COMPRESSION_ENABLED`, default on)
+    pub compression_enabled: bool,
+    /// Longest prompt accepted over HTTP or websockets, in characters, checked before anything is
+    /// stored or sent to the model; 0 for no limit (`MAX_MESSAGE_CHARS`, default 16000)
+    pub max_message_chars: usize,
+    /// Idle gap after which a server-sent event stream gets a `:keep-alive` comment, so proxies
+    /// don't drop it while the model is slow; at least 1 (`SSE_KEEPALIVE_SECS`, default 15)
+    pub sse_keepalive_secs: u64,
+    /// Largest single message accepted in a conversation import, in bytes; assistant replies can run
+    /// far longer than prompts (`IMPORT_MAX_MESSAGE_BYTES`, default 128 KiB)
+    pub import_max_message_bytes: usize,
+    /// Largest chat message accepted over websockets, in bytes (`WS_MAX_MESSAGE_BYTES`, default 32 KiB)
+    pub ws_max_message_bytes: usize,
+    /// How often an idle chat socket is pinged, 0 to never ping (`WS_PING_INTERVAL_SECS`, default 30)
+    pub ws_ping_interval_secs: u64,
+    /// How long a ping may go unanswered before the socket is closed (`WS_PONG_TIMEOUT_SECS`, default 10)
+    pub ws_pong_timeout_secs: u64,
+    /// Check prompts against `moderation_patterns` before they are stored or sent to the model
+    /// (`MODERATION_ENABLED`, default off)
+    pub moderation_enabled: bool,
+    /// Denylist regexes, one per line of the file named by `MODERATION_PATTERNS_FILE`; blank lines
+    /// and lines starting with `#` are skipped
+    pub moderation_patterns: Vec<String>,
+    /// Answer a request identical to a recent one (same model, settings, prompt and context) from
+    /// memory instead of the model (`RESPONSE_CACHE_ENABLED`, default off)
+    pub response_cache_enabled: bool,
+    /// How long a cached reply is served (`RESPONSE_CACHE_TTL_SECS`, default 3600)
+    pub response_cache_ttl_secs: u64,
+    /// Most replies kept in the cache at once (`RESPONSE_CACHE_CAPACITY`, default 1000)
+    pub response_cache_capacity: usize,
+    /// How long a processed `Idempotency-Key` is remembered and its response replayed (`IDEMPOTENCY_TTL_SECS`, default 86400)
+    pub idempotency_ttl_secs: i64,
+    /// How long shutdown waits for in-flight replies to be stored (`SHUTDOWN_GRACE_SECS`, default 10)
+    pub shutdown_grace_secs: u64,
+    /// Tokens a user may spend on the model per calendar month (UTC) unless their account sets its
+    /// own budget, 0 for no limit (`MONTHLY_TOKEN_BUDGET`, default 0)
+    pub monthly_token_budget: i64,
+    /// Conversations a user may have open (archived ones don't count) unless their account sets
+    /// its own limit, 0 for no limit (`MAX_CONVERSATIONS`, default 0)
+    pub max_conversations: i64,
+    /// How long a user's active/disabled state is trusted before the auth check reads it again,
+    /// 0 to read it on every request (`ACCOUNT_CACHE_TTL_SECS`, default 30)
+    pub account_cache_ttl_secs: u64,
+}
+
+impl Settings {
+    pub fn from_env() -> Self {
+        Self {
+            database_url: env::var("DATABASE_URL").unwrap_or_else(|_| "sqlite://app.db".to_string()),
+            access_ttl_secs: env_number("ACCESS_TOKEN_TTL_SECS", 24 * 60 * 60),
+            refresh_ttl_secs: env_number("REFRESH_TOKEN_TTL_SECS", 7 * 24 * 60 * 60),
+            normalize_unicode: env_flag("NORMALIZE_UNICODE", true),
+            max_refresh_token_len: env_number("MAX_REFRESH_TOKEN_LEN", 2048),
+            history_max_messages: env_number("HISTORY_MAX_MESSAGES", 50),
+            target_latency_ms: env_number("TARGET_LATENCY_MS", 0),
+            history_min_messages: env_number("HISTORY_MIN_MESSAGES", 4),
+            system_reminder_every: env_number("SYSTEM_REMINDER_EVERY", 0),
+            tidy_assistant_whitespace: env_flag("TIDY_ASSISTANT_WHITESPACE", false),
+            ai_provider: env::var("AI_PROVIDER").unwrap_or_else(|_| "gemini".to_string()),
+            gemini_model: env::var("GEMINI_MODEL").unwrap_or_else(|_| "gemini-2.0-flash".to_string()),
+            gemini_allowed_models: env_list(
+                "GEMINI_ALLOWED_MODELS",
+                &["gemini-2.0-flash", "gemini-1.5-flash", "gemini-1.5-pro"],
+            ),
+            gemini_timeout_secs: env_number("GEMINI_TIMEOUT_SECS", 60),
+            gemini_max_retries: env_number("GEMINI_MAX_RETRIES", 2),
+            db_max_connections: env_number("DB_MAX_CONNECTIONS", 10),
+            db_acquire_timeout_secs: env_number("DB_ACQUIRE_TIMEOUT_SECS", 30),
+            db_idle_timeout_secs: env_number("DB_IDLE_TIMEOUT_SECS", 600),
+            dev_mode: env_flag("DEV_MODE", false),
+            first_user_is_admin: env_flag("FIRST_USER_IS_ADMIN", false),
+            email_verification_ttl_secs: env_number("EMAIL_VERIFICATION_TTL_SECS", 24 * 60 * 60),
+            password_reset_ttl_secs: env_number("PASSWORD_RESET_TTL_SECS", 15 * 60),
+            totp_max_failures: env_number("TOTP_MAX_FAILURES", 5),
+            totp_lockout_secs: env_number("TOTP_LOCKOUT_SECS", 15 * 60),
+            ws_messages_per_minute: env_number("WS_MESSAGES_PER_MINUTE", 20),
+            ws_message_burst: env_number("WS_MESSAGE_BURST", 5),
+            max_body_bytes: env_number("MAX_BODY_BYTES", 1024 * 1024),
+            compression_enabled: env_flag("COMPRESSION_ENABLED", true),
+            max_message_chars: env_number("MAX_MESSAGE_CHARS", 16_000),
+            sse_keepalive_secs: env_number("SSE_KEEPALIVE_SECS", 15),
+            import_max_message_bytes: env_number("IMPORT_MAX_MESSAGE_BYTES", 128 * 1024),
+            ws_max_message_bytes: env_number("WS_MAX_MESSAGE_BYTES", 32 * 1024),
+            ws_ping_interval_secs: env_number("WS_PING_INTERVAL_SECS", 30),
+            ws_pong_timeout_secs: env_number("WS_PONG_TIMEOUT_SECS", 10),
+            moderation_enabled: env_flag("MODERATION_ENABLED", false),
+            moderation_patterns: env_patterns_file("MODERATION_PATTERNS_FILE"),
+            response_cache_enabled: env_flag("RESPONSE_CACHE_ENABLED", false),
+            response_cache_ttl_secs: env_number("RESPONSE_CACHE_TTL_SECS", 60 * 60),
+            response_cache_capacity: env_number("RESPONSE_CACHE_CAPACITY", 1000),
+            idempotency_ttl_secs: env_number("IDEMPOTENCY_TTL_SECS", 24 * 60 * 60),
+            shutdown_grace_secs: env_number("SHUTDOWN_GRACE_SECS", 10),
+            monthly_token_budget: env_number("MONTHLY_TOKEN_BUDGET", 0),
+            max_conversations: env_number("MAX_CONVERSATIONS", 0),
+            account_cache_ttl_secs: env_number("ACCOUNT_CACHE_TTL_SECS", 30),
+        }
+    }
+}
+
+fn env_flag(name: &str, default: bool) -> bool {
+    match env::var(name) {
+        Ok(value) => matches!(value.to_lowercase().as_str(), "1" | "true" | "yes" | "on"),
+        Err(_) => default,
+    }
+}
+
+fn env_list(name: &str, default: &[&str]) -> Vec<String> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(str::to_string)
+            .collect(),
+        Err(_) => default.iter().map(|item| item.to_string()).collect(),
+    }
+}
+
+fn env_patterns_file(name: &str) -> Vec<String> {
+    let Ok(path) = env::var(name) else {
+        return Vec::new();
+    };
+
+    std::fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{} ({}) could not be read: {}", name, path, e))
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect()
+}
+
+fn env_number<T: std::str::FromStr>(name: &str, default: T) -> T {
+    match env::var(name) {
+        Ok(value) => value
+            .parse()
+            .unwrap_or_else(|_| panic!("{} must be a number", name)),
+        Err(_) => default,
+    }
+}
 
 pub struct AppState {
-    pub users_db: Pool<Sqlite>,
-    pub tokens_db: Pool<Sqlite>,
-    pub chat_db: Pool<Sqlite>,
+    /// The one SQLite pool. Users, tokens and chats share a file because handlers such as password
+    /// reset update several of those tables inside a single transaction.
+    pub db: Pool<Sqlite>,
+    // The shared salt passwords used to be hashed with, now only needed to spot those hashes
     salt: SecretString,
     access_key: SecretString,
-    refresh_key: SecretString
+    refresh_key: SecretString,
+    ai_provider: Arc<dyn AiProvider>,
+    // Seals two-factor secrets; two-factor can't be turned on without it
+    totp_key: Option<SecretString>,
+    generations: Mutex<HashMap<i64, Arc<Generation>>>,
+    // Whether each recently authenticated user was active, and when that was read
+    accounts: DashMap<i64, (bool, Instant)>,
+    account_lookups: AtomicU64,
+    // Moving average of recent reply times in ms, 0 until the first reply
+    reply_latency_ms: AtomicU64,
+    message_limiter: DefaultKeyedRateLimiter<i64>,
+    // Built only when moderation is enabled, so the check costs nothing otherwise
+    moderator: Option<Moderator>,
+    response_cache: Option<ResponseCache>,
+    shutdown: watch::Sender<bool>,
+    pub settings: Settings,
 }
 
 impl AppState {
-    pub fn new(users_db: SqlitePool, tokens_db: SqlitePool, chat_db: SqlitePool, salt: SecretString, access_key: SecretString, refresh_key: SecretString) -> Self {
+    pub fn new(
+        db: SqlitePool,
+        salt: SecretString,
+        access_key: SecretString,
+        refresh_key: SecretString,
+        ai_provider: Arc<dyn AiProvider>,
+        settings: Settings,
+    ) -> Self {
+        let message_quota = Quota::per_minute(non_zero(settings.ws_messages_per_minute))
+            .allow_burst(non_zero(settings.ws_message_burst));
+
+        let moderator = settings.moderation_enabled.then(|| {
+            Moderator::new(&settings.moderation_patterns)
+                .unwrap_or_else(|e| panic!("Invalid pattern in MODERATION_PATTERNS_FILE: {}", e))
+        });
+
+        let response_cache = settings.response_cache_enabled.then(|| {
+            ResponseCache::new(
+                Duration::from_secs(settings.response_cache_ttl_secs),
+                settings.response_cache_capacity,
+            )
+        });
+
         Self {
-            users_db,
-            tokens_db,
-            chat_db,
+            db,
             salt,
             access_key,
-            refresh_key
+            refresh_key,
+            ai_provider,
+            totp_key: None,
+            generations: Mutex::new(HashMap::new()),
+            accounts: DashMap::new(),
+            account_lookups: AtomicU64::new(0),
+            reply_latency_ms: AtomicU64::new(0),
+            message_limiter: RateLimiter::keyed(message_quota),
+            moderator,
+            response_cache,
+            shutdown: watch::Sender::new(false),
+            settings,
+        }
+    }
+
+    /// Key that two-factor secrets are encrypted under at rest. Changing it invalidates every
+    /// enrolled authenticator.
+    pub fn with_totp_key(mut self, totp_key: SecretString) -> Self {
+        self.totp_key = Some(totp_key);
+        self
+    }
+
+    /// Takes one chat message from the user's websocket quota, returning false once it's spent.
+    /// Kept apart from the HTTP limiters so chat traffic and API calls don't eat each other's budget.
+    pub fn check_message_rate(&self, user_id: i64) -> bool {
+        self.message_limiter.check_key(&user_id).is_ok()
+    }
+
+    /// Index of the denylist pattern the prompt matches, `None` when it may be sent (always, with
+    /// moderation disabled).
+    pub fn moderate(&self, text: &str) -> Option<usize> {
+        self.moderator.as_ref()?.blocked_by(text)
+    }
+
+    /// Recent replies to reuse for identical requests; `None` unless the cache is enabled.
+    pub fn response_cache(&self) -> Option<&ResponseCache> {
+        self.response_cache.as_ref()
+    }
+
+    /// Whether the user still exists and isn't disabled, served from a short-lived cache.
+    /// Anything that deletes or disables an account must call `forget_account` so its tokens
+    /// stop working right away instead of when the entry expires.
+    pub async fn account_active(&self, user_id: i64) -> Result<bool, sqlx::Error> {
+        let ttl = Duration::from_secs(self.settings.account_cache_ttl_secs);
+        if let Some(entry) = self.accounts.get(&user_id)
+            && entry.1.elapsed() < ttl
+        {
+            return Ok(entry.0);
+        }
+
+        self.account_lookups.fetch_add(1, Ordering::Relaxed);
+        let disabled: Option<bool> =
+            sqlx::query_scalar("SELECT disabled_at IS NOT NULL FROM users WHERE id = ?")
+                .bind(user_id)
+                .fetch_optional(&self.db)
+                .await?;
+        let active = disabled == Some(false);
+
+        if !ttl.is_zero() {
+            // Expired entries are only swept once the map grows, which keeps inserts cheap
+            if self.accounts.len() >= ACCOUNT_CACHE_SWEEP_AT {
+                self.accounts.retain(|_, (_, read_at)| read_at.elapsed() < ttl);
+            }
+            self.accounts.insert(user_id, (active, Instant::now()));
+        }
+
+        Ok(active)
+    }
+
+    pub fn forget_account(&self, user_id: i64) {
+        self.accounts.remove(&user_id);
+    }
+
+    /// Account states read from the database rather than the cache since startup.
+    pub fn account_lookups(&self) -> u64 {
+        self.account_lookups.load(Ordering::Relaxed)
+    }
+
+    /// Folds a reply's time into the moving average `history_window` steers by.
+    pub fn record_reply_latency(&self, latency_ms: u64) {
+        let _ = self.reply_latency_ms.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |average| {
+            Some(if average == 0 { latency_ms } else { (3 * average + latency_ms) / 4 })
+        });
+    }
+
+    /// How many earlier messages to replay with a prompt. With `target_latency_ms` set and recent
+    /// replies slower than it, the window shrinks by the ratio between the two, trading context
+    /// for speed until replies catch up again.
+    pub fn history_window(&self) -> i64 {
+        let max = self.settings.history_max_messages;
+        let target = self.settings.target_latency_ms;
+        let recent = self.reply_latency_ms.load(Ordering::Relaxed);
+        if target == 0 || recent <= target {
+            return max;
         }
+
+        let scaled = (max as f64 * target as f64 / recent as f64) as i64;
+        let window = scaled.max(self.settings.history_min_messages).min(max);
+        tracing::info!(
+            recent_latency_ms = recent,
+            target_latency_ms = target,
+            window,
+            "history trimmed for latency"
+        );
+        window
     }
 
     pub fn get_salt(&self) -> String {
@@ -33,4 +459,119 @@ impl AppState {
     pub fn get_refresh_key(&self) -> String {
         self.refresh_key.expose_secret().to_string()
     }
-}
\ No newline at end of file
+
+    pub fn ai_provider(&self) -> &dyn AiProvider {
+        self.ai_provider.as_ref()
+    }
+
+    pub fn get_totp_key(&self) -> Option<String> {
+        self.totp_key.as_ref().map(|key| key.expose_secret().to_string())
+    }
+
+    /// Registers a new generation for the conversation, or returns `None` while another one is
+    /// still running there, so two replies never interleave in one conversation.
+    pub fn start_generation(&self, conversation_id: i64) -> Option<Arc<Generation>> {
+        match self.generations.lock().unwrap().entry(conversation_id) {
+            Entry::Occupied(_) => None,
+            Entry::Vacant(entry) => Some(entry.insert(Arc::new(Generation::new())).clone()),
+        }
+    }
+
+    pub fn active_generation(&self, conversation_id: i64) -> Option<Arc<Generation>> {
+        self.generations.lock().unwrap().get(&conversation_id).cloned()
+    }
+
+    /// Tells joined sockets how the generation ended and unregisters it.
+    pub fn finish_generation(&self, conversation_id: i64, end: GenerationEnd) {
+        if let Some(generation) = self.generations.lock().unwrap().remove(&conversation_id) {
+            generation.finish(end);
+        }
+    }
+
+    pub fn has_active_generations(&self) -> bool {
+        !self.generations.lock().unwrap().is_empty()
+    }
+
+    /// Tells open sockets to stop taking new messages; replies already streaming still finish.
+    pub fn begin_shutdown(&self) {
+        self.shutdown.send_replace(true);
+    }
+
+    pub fn shutdown_signal(&self) -> watch::Receiver<bool> {
+        self.shutdown.subscribe()
+    }
+}
+
+const ACCOUNT_CACHE_SWEEP_AT: usize = 10_000;
+
+fn non_zero(value: u32) -> NonZeroU32 {
+    NonZeroU32::new(value.max(1)).expect("value is at least 1")
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::sync::broadcast::error::TryRecvError;
+
+    use super::*;
+    use crate::ai::gemini::GeminiProvider;
+
+    #[test]
+    fn joining_mid_generation_gets_partial_then_remaining_chunks() {
+        let generation = Generation::new();
+        generation.push("Hello, ");
+
+        let (partial, end, mut events) = generation.join_from(0);
+        assert_eq!(partial, "Hello, ");
+        assert_eq!(end, None);
+
+        generation.push("world");
+        generation.finish(GenerationEnd::Done);
+
+        assert_eq!(events.try_recv(), Ok(GenerationEvent::Chunk("world".to_string())));
+        assert_eq!(events.try_recv(), Ok(GenerationEvent::End(GenerationEnd::Done)));
+    }
+
+    #[test]
+    fn joining_after_the_end_still_sees_it() {
+        let generation = Generation::new();
+        generation.push("partial");
+        generation.finish(GenerationEnd::Failed("{\"error\":\"boom\"}".to_string()));
+
+        let (text, end, mut events) = generation.join_from(0);
+        assert_eq!(text, "partial");
+        assert_eq!(end, Some(GenerationEnd::Failed("{\"error\":\"boom\"}".to_string())));
+        assert_eq!(events.try_recv(), Err(TryRecvError::Empty));
+    }
+
+    #[test]
+    fn lagging_joiner_can_resume_from_what_it_sent() {
+        let generation = Generation::new();
+        let (_, _, mut events) = generation.join_from(0);
+
+        for _ in 0..100 {
+            generation.push("ab");
+        }
+
+        assert!(matches!(events.try_recv(), Err(TryRecvError::Lagged(_))));
+        let (rest, end, _) = generation.join_from(10);
+        assert_eq!(rest.len(), 190);
+        assert_eq!(end, None);
+    }
+
+    #[tokio::test]
+    async fn one_generation_per_conversation() {
+        let pool = SqlitePool::connect_lazy("sqlite::memory:").unwrap();
+        let provider = Arc::new(GeminiProvider::new("".into()));
+        let state = AppState::new(pool, "salt".into(), "a".into(), "r".into(), provider, Settings::from_env());
+
+        let first = state.start_generation(1).expect("conversation is idle");
+        assert!(state.start_generation(1).is_none());
+        assert!(state.start_generation(2).is_some());
+
+        let (_, _, mut events) = first.join_from(0);
+        state.finish_generation(1, GenerationEnd::Done);
+        assert_eq!(events.try_recv(), Ok(GenerationEvent::End(GenerationEnd::Done)));
+        assert!(state.active_generation(1).is_none());
+        assert!(state.start_generation(1).is_some());
+    }
+}
@@ -1,6 +1,16 @@
 use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 
+/// What an account may do beyond using its own data, stored in `users.role`.
+#[derive(Serialize, Deserialize, sqlx::Type, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(type_name = "TEXT", rename_all = "lowercase")]
+pub enum Role {
+    #[default]
+    User,
+    Admin,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub struct TokenClaims {
     pub name: String,
@@ -9,7 +19,34 @@ pub struct TokenClaims {
     pub exp: i64,
     pub token_type: String,
     pub used: bool,
-    pub jti: String
+    pub jti: String,
+    // Login session shared by the access and refresh token minted together
+    #[serde(default)]
+    pub sid: String,
+    // Tokens minted before roles existed decode as a plain user
+    #[serde(default)]
+    pub role: Role,
+}
+
+/// Where a login or refresh request came from, stored on the refresh token it mints.
+#[derive(Clone, Debug, Default)]
+pub struct SessionDevice {
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// One active login as listed by `GET /sessions`.
+#[derive(Serialize, FromRow, Debug)]
+pub struct SessionInfo {
+    pub sid: String,
+    pub ip: Option<String>,
+    pub user_agent: Option<String>,
+    // When the session logged in, and when it last refreshed its tokens
+    pub started_at: i64,
+    pub last_seen_at: i64,
+    // The session the request itself was made with
+    #[sqlx(skip)]
+    pub current: bool,
 }
 
 #[derive(Serialize, Deserialize, Clone, FromRow, Debug)]
@@ -20,7 +57,10 @@ pub struct DBToken {
     pub email: String,
     pub user_id: i64,
     pub exp: i64,
-    pub used: bool
+    pub used: bool,
+    // Shared by every token rotated from the same login
+    pub family_id: String,
+    pub sid: String
 }
 
 
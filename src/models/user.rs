@@ -2,12 +2,100 @@ use serde::{Deserialize, Serialize};
 use sqlx::prelude::FromRow;
 use validator::Validate;
 
+use crate::models::auth::Role;
+
 #[derive(FromRow, Debug)]
 pub struct UserDB {
     pub id: i64,
     pub name: String,
     pub password: String,
     pub email: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub email_verified: bool,
+    pub role: Role,
+    pub disabled_at: Option<i64>,
+    pub totp_secret: Option<String>,
+    pub totp_enabled: bool,
+    pub totp_last_step: Option<i64>,
+}
+
+/// Public view of the authenticated user, returned by `GET /me`.
+#[derive(Serialize, Debug)]
+pub struct UserProfile {
+    pub id: i64,
+    pub name: String,
+    pub email: String,
+    pub created_at: i64,
+    pub updated_at: i64,
+    pub email_verified: bool,
+    pub role: Role,
+    pub disabled_at: Option<i64>,
+    pub two_factor_enabled: bool,
+}
+
+impl From<UserDB> for UserProfile {
+    fn from(user: UserDB) -> Self {
+        UserProfile {
+            id: user.id,
+            name: user.name,
+            email: user.email,
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+            email_verified: user.email_verified,
+            role: user.role,
+            disabled_at: user.disabled_at,
+            two_factor_enabled: user.totp_enabled,
+        }
+    }
+}
+
+/// Returned by `PATCH /me`.
+#[derive(Serialize, Debug)]
+pub struct UpdatedProfile {
+    #[serde(flatten)]
+    pub profile: UserProfile,
+    /// Only filled in dev mode, when the email changed and has to be verified again
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification_token: Option<String>,
+}
+
+//For changing one's own name or email; fields left out stay as they are
+#[derive(Deserialize, Validate, Debug)]
+pub struct UpdateProfile {
+    #[validate(length(
+        min = 3,
+        max = 48,
+        message = "Name must be between 3 and 48 characters"
+    ))]
+    pub name: Option<String>,
+
+    #[validate(
+        email(message = "Invalid email format"),
+        length(max = 254, message = "Email is too long")
+    )]
+    pub email: Option<String>,
+
+    // Needed to change the email, since whoever controls it can reset the password
+    #[validate(length(max = 128, message = "Password must be at most 128 characters"))]
+    pub current_password: Option<String>,
+}
+
+//For deleting one's own account; the password is asked for again
+#[derive(Deserialize, Validate, Debug)]
+pub struct DeleteAccount {
+    #[validate(length(
+        min = 1,
+        max = 128,
+        message = "Password must be between 1 and 128 characters"
+    ))]
+    pub password: String,
+}
+
+//For suspending or restoring an account
+#[derive(Deserialize, Debug)]
+pub struct AccountStatus {
+    pub disabled: bool,
 }
 
 #[derive(Serialize, Deserialize, Validate, Debug)]
@@ -54,14 +142,108 @@ fn validate_password_strength(password: &str) -> Result<(), validator::Validatio
     }
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Validate, Debug)]
 pub struct LoginData {
+    #[validate(length(
+        min = 1,
+        max = 128,
+        message = "Password must be between 1 and 128 characters"
+    ))]
     pub password: String,
-    pub email: String,
+
+    // Email address or username; older clients send it as `email`
+    #[serde(alias = "email")]
+    #[validate(length(
+        min = 1,
+        max = 254,
+        message = "Identifier must be between 1 and 254 characters"
+    ))]
+    pub identifier: String,
+
+    // Authenticator code or unused backup code, required once two-factor is enabled
+    pub totp_code: Option<String>,
+}
+
+/// Returned by `POST /2fa/enable`; the secret is shown once for manual entry.
+#[derive(Serialize, Debug)]
+pub struct TwoFactorSetup {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct TwoFactorCode {
+    pub code: String,
+}
+
+/// Single-use codes for logging in without the authenticator, only ever shown here.
+#[derive(Serialize, Debug)]
+pub struct BackupCodes {
+    pub backup_codes: Vec<String>,
 }
 
 #[derive(Deserialize, Serialize)]
 pub struct OnSuccessRegister {
     pub message: String,
     pub user_id: i64,
+    /// Only filled in dev mode, where there is no mailer to deliver it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+pub struct VerifyEmailParams {
+    pub token: String,
+}
+
+#[derive(Deserialize, Validate, Debug)]
+pub struct ResendVerificationRequest {
+    #[validate(
+        email(message = "Invalid email format"),
+        length(max = 254, message = "Email is too long")
+    )]
+    pub email: String,
+}
+
+#[derive(Serialize)]
+pub struct OnResendVerification {
+    pub message: String,
+    /// Only filled in dev mode, and only when the email belongs to an unverified account
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub verification_token: Option<String>,
+}
+
+#[derive(Deserialize, Validate, Debug)]
+pub struct PasswordResetRequest {
+    #[validate(
+        email(message = "Invalid email format"),
+        length(max = 254, message = "Email is too long")
+    )]
+    pub email: String,
+}
+
+#[derive(Serialize)]
+pub struct OnPasswordResetRequest {
+    pub message: String,
+    /// Only filled in dev mode, and only when the email belongs to an account
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reset_token: Option<String>,
+}
+
+#[derive(Deserialize, Validate, Debug)]
+pub struct PasswordResetConfirm {
+    pub token: String,
+
+    #[validate(
+        length(
+            min = 8,
+            max = 128,
+            message = "Password must be between 8 and 128 characters"
+        ),
+        custom(
+            function = "validate_password_strength",
+            message = "Password must contain at least one uppercase letter, one lowercase letter, one digit, and one special character"
+        )
+    )]
+    pub new_password: String,
 }
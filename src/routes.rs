@@ -0,0 +1,202 @@
+use std::sync::Arc;
+
+use axum::{
+    Router,
+    extract::DefaultBodyLimit,
+    middleware as axum_middleware,
+    routing::{delete, get, patch, post, put},
+};
+use tower::Layer;
+use tower_governor::{
+    GovernorLayer, governor::GovernorConfigBuilder, key_extractor::PeerIpKeyExtractor,
+};
+
+use tower_http::{
+    compression::CompressionLayer,
+    normalize_path::{NormalizePath, NormalizePathLayer},
+};
+
+use crate::{
+    handlers::{
+        admin::{list_users, update_account_status},
+        ai::{
+            analyze_text, archive_conversation_by_id, bulk_delete_conversations,
+            create_conversation, delete_conversation_by_id, delete_message_by_id,
+            edit_message_by_id, get_conversation_messages_by_id, get_conversation_settings_by_id,
+            get_user_conversations, get_user_conversations_by_id, import_conversation,
+            move_message_by_id, pin_conversation_by_id, position_conversation_by_id,
+            post_user_message, regenerate_last_reply, stream_user_message,
+            update_conversation_by_id, update_conversation_settings_by_id,
+            update_system_prompt_by_id,
+        },
+        auth::{
+            confirm_password_reset, delete_me, enable_two_factor, export_me, get_me, list_sessions,
+            login, logout, refresh, register, request_password_reset, resend_verification,
+            revoke_session, update_me, verify_email, verify_two_factor,
+        },
+        fallback::{method_not_allowed, not_found, payload_too_large},
+        health::{health, ready},
+        tags::{attach_tag, create_tag, delete_tag, detach_tag, list_tags},
+        usage::get_usage,
+    },
+    middleware::{
+        auth::{auth_middleware, require_role},
+        rate_limit::UserKeyExtractor,
+        request_id::request_id,
+    },
+    models::{app::AppState, auth::Role},
+};
+
+/// Every route of the API with its rate limits, auth layer and JSON fallbacks.
+/// Transport concerns such as CORS and request tracing are layered on by the binary.
+pub fn router(state: Arc<AppState>) -> Router {
+    let governor_conf = Arc::new(
+        GovernorConfigBuilder::default()
+            .per_second(1)
+            .burst_size(5)
+            .key_extractor(UserKeyExtractor)
+            .finish()
+            .unwrap(),
+    );
+
+    // One budget for every endpoint that calls the model
+    let ai_governor_layer = GovernorLayer {
+        config: governor_conf,
+    };
+
+    // Upgrades are cheap to attempt but each socket can drive streaming generations, so they get
+    // their own bucket; per-message limits are enforced inside the socket handler. The route sits
+    // outside `auth_middleware` (the token is checked during the upgrade), so there are no claims
+    // to key on and the bucket is per peer IP.
+    let ws_governor_conf = Arc::new(
+        GovernorConfigBuilder::default()
+            .per_second(2)
+            .burst_size(10)
+            .key_extractor(PeerIpKeyExtractor)
+            .finish()
+            .unwrap(),
+    );
+
+    let ws_governor_layer = GovernorLayer {
+        config: ws_governor_conf,
+    };
+
+    let router = Router::new()
+        .route("/text", get(analyze_text).layer(ai_governor_layer.clone()))
+        .route(
+            "/conversations",
+            get(get_user_conversations).post(create_conversation),
+        )
+        .route("/conversations/delete", post(bulk_delete_conversations))
+        .route("/conversations/import", post(import_conversation))
+        .route(
+            "/conversations/{id}",
+            get(get_user_conversations_by_id)
+                .put(update_conversation_by_id)
+                .patch(update_system_prompt_by_id)
+                .delete(delete_conversation_by_id),
+        )
+        .route(
+            "/conversations/{id}/regenerate",
+            post(regenerate_last_reply).layer(ai_governor_layer.clone()),
+        )
+        .route(
+            "/conversations/{id}/settings",
+            get(get_conversation_settings_by_id).put(update_conversation_settings_by_id),
+        )
+        .route(
+            "/conversations/{id}/archive",
+            patch(archive_conversation_by_id),
+        )
+        .route("/conversations/{id}/pin", patch(pin_conversation_by_id))
+        .route(
+            "/conversations/{id}/position",
+            patch(position_conversation_by_id),
+        )
+        .route(
+            "/conversations/{id}/tags/{tag_id}",
+            put(attach_tag).delete(detach_tag),
+        )
+        .route(
+            "/conversations/{id}/messages/{message_id}",
+            delete(delete_message_by_id).put(edit_message_by_id),
+        )
+        .route(
+            "/conversations/{id}/messages/{message_id}/move",
+            post(move_message_by_id),
+        )
+        .route(
+            "/conversations/{id}/messages/stream",
+            post(stream_user_message).layer(ai_governor_layer.clone()),
+        )
+        .route(
+            "/conversations/{id}/messages",
+            get(get_conversation_messages_by_id),
+        )
+        .route("/tags", get(list_tags).post(create_tag))
+        .route("/tags/{id}", delete(delete_tag))
+        .route("/sessions", get(list_sessions))
+        .route("/sessions/{sid}", delete(revoke_session))
+        .route("/me", get(get_me).patch(update_me).delete(delete_me))
+        .route("/me/export", get(export_me))
+        .route("/2fa/enable", post(enable_two_factor))
+        .route("/2fa/verify", post(verify_two_factor))
+        .route("/usage", get(get_usage))
+        .route(
+            "/admin/users",
+            get(list_users).layer(axum_middleware::from_fn_with_state(
+                Role::Admin,
+                require_role,
+            )),
+        )
+        .route(
+            "/admin/users/{id}",
+            patch(update_account_status).layer(axum_middleware::from_fn_with_state(
+                Role::Admin,
+                require_role,
+            )),
+        )
+        .layer(axum_middleware::from_fn_with_state(
+            state.clone(),
+            auth_middleware,
+        ))
+        .route("/refresh", post(refresh))
+        .route("/register", post(register))
+        .route("/verify", get(verify_email))
+        .route("/verify/resend", post(resend_verification))
+        .route("/password/reset/request", post(request_password_reset))
+        .route("/password/reset/confirm", post(confirm_password_reset))
+        .route("/login", post(login))
+        .route("/logout", post(logout))
+        .route(
+            "/conversations_ws",
+            get(post_user_message).layer(ws_governor_layer),
+        )
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .fallback(not_found)
+        .method_not_allowed_fallback(method_not_allowed)
+        .layer(DefaultBodyLimit::max(state.settings.max_body_bytes))
+        .layer(axum_middleware::map_response_with_state(
+            state.clone(),
+            payload_too_large,
+        ))
+        // Outermost, so errors from the layers above get the id too
+        .layer(axum_middleware::from_fn(request_id));
+
+    // Wraps the request id layer, which has to read error bodies before they are compressed. The
+    // default predicate leaves SSE streams and bodiless upgrade responses uncompressed.
+    let router = if state.settings.compression_enabled {
+        router.layer(CompressionLayer::new())
+    } else {
+        router
+    };
+
+    router.with_state(state)
+}
+
+/// Lets `/conversations/` reach the same handler as `/conversations`. Rewriting the path has to
+/// happen before routing, so this wraps the finished router instead of being a `Router::layer`.
+pub fn trim_trailing_slash(router: Router) -> NormalizePath<Router> {
+    NormalizePathLayer::trim_trailing_slash().layer(router)
+}
@@ -48,3 +48,292 @@ pub mod validation {
         }
     }
 }
+
+pub mod normalization {
+    use unicode_normalization::UnicodeNormalization;
+
+    /// Returns the NFC form of `input` so visually identical strings compare equal, or a plain copy when disabled.
+    pub fn normalize_text(input: &str, enabled: bool) -> String {
+        if enabled {
+            input.nfc().collect()
+        } else {
+            input.to_string()
+        }
+    }
+
+    /// Canonical form used to store and look up emails: trimmed, normalized and lowercased.
+    pub fn normalize_email(input: &str, enabled: bool) -> String {
+        normalize_text(input.trim(), enabled).to_lowercase()
+    }
+
+    /// Trims trailing whitespace on each line and collapses runs of three or more blank lines into one.
+    /// Lines inside ``` fences are left untouched.
+    pub fn tidy_whitespace(input: &str) -> String {
+        let mut lines: Vec<&str> = Vec::new();
+        let mut in_fence = false;
+        let mut blank_run = 0;
+
+        for line in input.lines() {
+            let is_fence = line.trim_start().starts_with("```");
+
+            if in_fence && !is_fence {
+                lines.push(line);
+                continue;
+            }
+
+            let line = line.trim_end();
+            if line.is_empty() {
+                blank_run += 1;
+                continue;
+            }
+
+            let keep = if blank_run >= 3 { 1 } else { blank_run };
+            lines.extend(std::iter::repeat_n("", keep));
+            blank_run = 0;
+
+            if is_fence {
+                in_fence = !in_fence;
+            }
+            lines.push(line);
+        }
+
+        lines.join("\n")
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn nfd_and_nfc_emails_normalize_to_the_same_address() {
+            let nfc = "Jos\u{e9}@Example.com";
+            let nfd = " jose\u{301}@example.COM ";
+
+            assert_ne!(nfc, nfd.trim());
+            assert_eq!(normalize_email(nfc, true), normalize_email(nfd, true));
+            assert_eq!(normalize_email(nfd, true), "jos\u{e9}@example.com");
+        }
+
+        #[test]
+        fn tidy_whitespace_cleans_prose() {
+            let input = "Hello   \nworld\t\n\n\n\n\nBye  \n\n";
+            assert_eq!(tidy_whitespace(input), "Hello\nworld\n\nBye");
+        }
+
+        #[test]
+        fn tidy_whitespace_keeps_one_or_two_blank_lines() {
+            assert_eq!(tidy_whitespace("a\n\nb\n\n\nc"), "a\n\nb\n\n\nc");
+        }
+
+        #[test]
+        fn tidy_whitespace_leaves_fenced_code_alone() {
+            let input = "Text  \n```python\ndef f():  \n\n\n\n    return 1\t\n```  \nafter   ";
+            assert_eq!(
+                tidy_whitespace(input),
+                "Text\n```python\ndef f():  \n\n\n\n    return 1\t\n```\nafter"
+            );
+        }
+
+        #[test]
+        fn normalization_can_be_disabled() {
+            assert_eq!(normalize_text("e\u{301}", false), "e\u{301}");
+            assert_eq!(normalize_text("e\u{301}", true), "\u{e9}");
+        }
+    }
+}
+
+pub mod tokens {
+    use rand::Rng;
+    use sha2::{Digest, Sha256};
+
+    /// Random URL-safe token for single-use links such as email verification.
+    pub fn generate_token() -> String {
+        let mut bytes = [0u8; 32];
+        rand::rng().fill(&mut bytes);
+        hex::encode(bytes)
+    }
+
+    /// The tokens are high-entropy, so an unsalted SHA-256 is enough and keeps them indexable.
+    pub fn hash_token(token: &str) -> String {
+        hex::encode(Sha256::digest(token.as_bytes()))
+    }
+}
+
+pub mod moderation {
+    use regex::{RegexSet, RegexSetBuilder};
+
+    /// Operator-supplied denylist that prompts are checked against before they reach the model.
+    /// Patterns are regexes matched case-insensitively anywhere in the text.
+    pub struct Moderator {
+        patterns: RegexSet,
+    }
+
+    impl Moderator {
+        pub fn new(patterns: &[String]) -> Result<Self, regex::Error> {
+            let patterns = RegexSetBuilder::new(patterns).case_insensitive(true).build()?;
+            Ok(Self { patterns })
+        }
+
+        /// Index of the first pattern the text matches, `None` when it's allowed. The index is what
+        /// gets logged, so blocked prompts don't end up in the logs themselves.
+        pub fn blocked_by(&self, text: &str) -> Option<usize> {
+            self.patterns.matches(text).iter().next()
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn patterns_match_anywhere_ignoring_case() {
+            let moderator = Moderator::new(&["forbidden".to_string(), r"\bkill\s+\w+".to_string()]).unwrap();
+
+            assert_eq!(moderator.blocked_by("this is FORBIDDEN text"), Some(0));
+            assert_eq!(moderator.blocked_by("how to kill   processes"), Some(1));
+            assert_eq!(moderator.blocked_by("skill issue"), None);
+            assert!(Moderator::new(&["(unclosed".to_string()]).is_err());
+        }
+    }
+}
+
+pub mod mailer {
+    /// Delivery hook for verification links. No provider is wired up yet, so the token only
+    /// reaches the user through the registration response in dev mode.
+    pub async fn send_verification_email(_email: &str, _token: &str) {}
+
+    /// Delivery hook for password reset links, unwired for the same reason.
+    pub async fn send_password_reset_email(_email: &str, _token: &str) {}
+}
+
+pub mod totp {
+    use aes_gcm::{Aes256Gcm, KeyInit, Nonce, aead::Aead};
+    use data_encoding::BASE32_NOPAD;
+    use hmac::{Hmac, Mac};
+    use rand::Rng;
+    use sha1::Sha1;
+    use sha2::{Digest, Sha256};
+
+    /// Seconds each code is valid for, the value authenticator apps assume.
+    pub const STEP_SECS: i64 = 30;
+
+    /// Fresh 160-bit secret, the size RFC 4226 recommends.
+    pub fn generate_secret() -> Vec<u8> {
+        let mut secret = vec![0u8; 20];
+        rand::rng().fill(&mut secret[..]);
+        secret
+    }
+
+    /// The `otpauth://` link authenticator apps import, usually shown to the user as a QR code.
+    pub fn otpauth_uri(issuer: &str, account: &str, secret: &[u8]) -> String {
+        format!(
+            "otpauth://totp/{issuer}:{account}?secret={secret}&issuer={issuer}&algorithm=SHA1&digits=6&period={period}",
+            issuer = percent_encode(issuer),
+            account = percent_encode(account),
+            secret = BASE32_NOPAD.encode(secret),
+            period = STEP_SECS,
+        )
+    }
+
+    pub fn encode_secret(secret: &[u8]) -> String {
+        BASE32_NOPAD.encode(secret)
+    }
+
+    pub fn step_at(unix_secs: i64) -> i64 {
+        unix_secs.div_euclid(STEP_SECS)
+    }
+
+    /// The six-digit code for a time step (RFC 6238 over HMAC-SHA1).
+    pub fn code_for_step(secret: &[u8], step: i64) -> u32 {
+        let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).expect("HMAC takes keys of any length");
+        mac.update(&step.to_be_bytes());
+        let hash = mac.finalize().into_bytes();
+
+        let offset = usize::from(hash[19] & 0x0f);
+        let truncated = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]);
+        (truncated & 0x7fff_ffff) % 1_000_000
+    }
+
+    /// The step `code` belongs to, tolerating one step of clock drift either way.
+    pub fn matching_step(secret: &[u8], code: &str, now: i64) -> Option<i64> {
+        let code = code.trim();
+        if code.len() != 6 || !code.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        let code: u32 = code.parse().ok()?;
+
+        let current = step_at(now);
+        (current - 1..=current + 1).find(|&step| code_for_step(secret, step) == code)
+    }
+
+    /// Seals a secret with AES-256-GCM under a key derived from `key`; the output is hex of
+    /// nonce followed by ciphertext.
+    pub fn encrypt_secret(key: &str, secret: &[u8]) -> String {
+        let mut nonce = [0u8; 12];
+        rand::rng().fill(&mut nonce);
+
+        let sealed = cipher(key)
+            .encrypt(Nonce::from_slice(&nonce), secret)
+            .expect("encrypting into a Vec cannot fail");
+
+        hex::encode([nonce.as_slice(), &sealed].concat())
+    }
+
+    /// `None` when the value was sealed under another key or has been tampered with.
+    pub fn decrypt_secret(key: &str, sealed: &str) -> Option<Vec<u8>> {
+        let bytes = hex::decode(sealed).ok()?;
+        if bytes.len() < 12 {
+            return None;
+        }
+        let (nonce, ciphertext) = bytes.split_at(12);
+
+        cipher(key).decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+    }
+
+    fn cipher(key: &str) -> Aes256Gcm {
+        Aes256Gcm::new_from_slice(&Sha256::digest(key.as_bytes())).expect("SHA-256 yields a 256-bit key")
+    }
+
+    fn percent_encode(value: &str) -> String {
+        value
+            .bytes()
+            .map(|b| match b {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => (b as char).to_string(),
+                _ => format!("%{:02X}", b),
+            })
+            .collect()
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        // RFC 6238 appendix B, SHA-1 column, truncated to six digits
+        #[test]
+        fn codes_match_the_rfc_vectors() {
+            let secret = b"12345678901234567890";
+            assert_eq!(code_for_step(secret, step_at(59)), 287_082);
+            assert_eq!(code_for_step(secret, step_at(1_111_111_109)), 81_804);
+            assert_eq!(code_for_step(secret, step_at(2_000_000_000)), 279_037);
+        }
+
+        #[test]
+        fn neighbouring_steps_are_accepted() {
+            let secret = b"12345678901234567890";
+            let now = 1_111_111_109;
+            let previous = format!("{:06}", code_for_step(secret, step_at(now) - 1));
+
+            assert_eq!(matching_step(secret, &previous, now), Some(step_at(now) - 1));
+            assert_eq!(matching_step(secret, "081804", now), Some(step_at(now)));
+            assert_eq!(matching_step(secret, "81804", now), None);
+            assert_eq!(matching_step(secret, "abcdef", now), None);
+        }
+
+        #[test]
+        fn sealed_secrets_only_open_with_their_key() {
+            let sealed = encrypt_secret("key-one", b"secret");
+            assert_eq!(decrypt_secret("key-one", &sealed).as_deref(), Some(b"secret".as_slice()));
+            assert_eq!(decrypt_secret("key-two", &sealed), None);
+        }
+    }
+}
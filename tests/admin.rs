@@ -0,0 +1,123 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use chrono::Utc;
+use jsonwebtoken::{EncodingKey, Header, encode};
+use serde_json::json;
+
+use common::spawn_app;
+
+#[tokio::test]
+async fn admin_routes_need_the_admin_role() {
+    let app = spawn_app().await;
+    let user = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app.request(Method::GET, "/admin/users", Some(&user.access_token), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    assert_eq!(body["error"], "insufficient_role");
+
+    let (status, _) = app.request(Method::GET, "/admin/users", None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn admins_can_list_users() {
+    let app = spawn_app().await;
+    app.register("alice", "alice@example.com").await;
+    app.register("root", "root@example.com").await;
+    sqlx::query("UPDATE users SET role = 'admin' WHERE name = 'root'")
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+    let admin = app.login("root@example.com").await;
+
+    let (status, body) = app.request(Method::GET, "/admin/users", Some(&admin.access_token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body[0]["name"], "alice");
+    assert_eq!(body[0]["role"], "user");
+    assert_eq!(body[1]["role"], "admin");
+    assert!(body[0].get("password").is_none());
+}
+
+#[tokio::test]
+async fn tokens_without_a_role_are_plain_users() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    // Shaped like an access token minted before roles existed
+    let claims = json!({
+        "name": "alice",
+        "email": "alice@example.com",
+        "user_id": session.user_id,
+        "exp": Utc::now().timestamp() + 60,
+        "token_type": "Access",
+        "used": false,
+        "jti": "legacy",
+        "sid": "legacy-session",
+    });
+    let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(b"test-access-key")).unwrap();
+
+    let (status, body) = app.request(Method::GET, "/me", Some(&token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["role"], "user");
+
+    let (status, _) = app.request(Method::GET, "/admin/users", Some(&token), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
+
+#[tokio::test]
+async fn disabling_an_account_ends_its_cached_sessions() {
+    let app = spawn_app().await;
+    let user = app.signed_in("alice", "alice@example.com").await;
+    app.register("root", "root@example.com").await;
+    sqlx::query("UPDATE users SET role = 'admin' WHERE name = 'root'")
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+    let admin = app.login("root@example.com").await;
+
+    // Caches alice as active
+    let (status, _) = app.request(Method::GET, "/me", Some(&user.access_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let uri = format!("/admin/users/{}", user.user_id);
+    let (status, body) = app
+        .request(Method::PATCH, &uri, Some(&admin.access_token), Some(json!({ "disabled": true })))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(body["disabled_at"].is_i64());
+
+    let (status, body) = app.request(Method::GET, "/me", Some(&user.access_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "account_inactive");
+
+    let (status, body) = app
+        .request(Method::PATCH, &uri, Some(&admin.access_token), Some(json!({ "disabled": false })))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(body["disabled_at"].is_null());
+
+    let (status, _) = app.request(Method::GET, "/me", Some(&user.access_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn first_user_is_made_admin_when_configured() {
+    let mut settings = common::test_settings();
+    settings.first_user_is_admin = true;
+    let app = common::spawn_app_with(settings).await;
+
+    let first = app.signed_in("root", "root@example.com").await;
+    let second = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, _) = app.request(Method::GET, "/admin/users", Some(&first.access_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = app.request(Method::GET, "/admin/users", Some(&second.access_token), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+
+    // Off by default
+    let app = spawn_app().await;
+    let first = app.signed_in("root", "root@example.com").await;
+    let (status, _) = app.request(Method::GET, "/admin/users", Some(&first.access_token), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+}
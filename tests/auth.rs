@@ -0,0 +1,919 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use rback::{middleware::auth::decode_access_token, models::ai::ReplyMeta};
+use serde_json::json;
+
+use common::spawn_app;
+
+#[tokio::test]
+async fn refresh_rotates_the_token() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/refresh",
+            None,
+            Some(json!({ "refresh_token": session.refresh_token })),
+        )
+        .await;
+
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_ne!(body["new_refresh_token"], json!(session.refresh_token));
+
+    let (status, _) = app
+        .request(Method::GET, "/me", Some(body["new_access_token"].as_str().unwrap()), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn logout_retires_the_refresh_token() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let body = json!({ "refresh_token": session.refresh_token });
+
+    let (status, _) = app.request(Method::POST, "/logout", None, Some(body.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = app.request(Method::POST, "/refresh", None, Some(body.clone())).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = app.request(Method::POST, "/logout", None, Some(body)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn replaying_a_used_refresh_token_revokes_the_family() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, rotated) = app
+        .request(
+            Method::POST,
+            "/refresh",
+            None,
+            Some(json!({ "refresh_token": session.refresh_token })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", rotated);
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/refresh",
+            None,
+            Some(json!({ "refresh_token": session.refresh_token })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", body);
+    assert_eq!(body["error"], "Refresh token reuse detected");
+
+    let remaining: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM tokens WHERE user_id = ?1")
+        .bind(session.user_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(remaining, 0);
+
+    // Access tokens of the revoked sessions stop working too
+    for token in [session.access_token.as_str(), rotated["new_access_token"].as_str().unwrap()] {
+        let (status, _) = app.request(Method::GET, "/me", Some(token), None).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    // The successor minted before the replay went with the rest of the family
+    let (status, _) = app
+        .request(
+            Method::POST,
+            "/refresh",
+            None,
+            Some(json!({ "refresh_token": rotated["new_refresh_token"] })),
+        )
+        .await;
+    assert!(status.is_client_error());
+}
+
+#[tokio::test]
+async fn a_refresh_token_is_not_accepted_as_a_bearer_token() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app.request(Method::GET, "/me", Some(&session.refresh_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", body);
+
+    // Nor the other way round
+    let (status, _) = app
+        .request(Method::POST, "/refresh", None, Some(json!({ "refresh_token": session.access_token })))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn refresh_rejects_a_forged_token() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let mut parts: Vec<&str> = session.refresh_token.split('.').collect();
+    parts[2] = "c2lnbmF0dXJl";
+    let forged = parts.join(".");
+
+    let (status, body) = app
+        .request(Method::POST, "/refresh", None, Some(json!({ "refresh_token": forged })))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", body);
+}
+
+#[tokio::test]
+async fn nfd_email_logs_into_the_nfc_account() {
+    let app = spawn_app().await;
+    let user_id = app.register("jose", "jose@caf\u{e9}.example").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "email": "Jose@Cafe\u{301}.Example", "password": common::PASSWORD })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let (status, body) = app
+        .request(Method::GET, "/me", Some(body["access_token"].as_str().unwrap()), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["id"], user_id);
+}
+
+#[tokio::test]
+async fn revoking_a_session_invalidates_its_access_and_refresh_tokens() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let other = app.login("alice@example.com").await;
+
+    let (status, me) = app
+        .request(Method::GET, "/me", Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(me["id"], session.user_id);
+
+    let sid: String = sqlx::query_scalar("SELECT sid FROM tokens WHERE user_id = ?1 ORDER BY id LIMIT 1")
+        .bind(session.user_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+
+    let (status, body) = app
+        .request(
+            Method::DELETE,
+            &format!("/sessions/{}", sid),
+            Some(&other.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT, "{}", body);
+
+    let (status, body) = app
+        .request(Method::GET, "/me", Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "session_revoked");
+
+    let (status, _) = app
+        .request(
+            Method::POST,
+            "/refresh",
+            None,
+            Some(json!({ "refresh_token": session.refresh_token })),
+        )
+        .await;
+    assert!(status.is_client_error());
+
+    // The other login is a different session and keeps working
+    let (status, _) = app
+        .request(Method::GET, "/me", Some(&other.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn failed_rotation_leaves_the_token_table_unchanged() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    // Fails the insert of the successor, after the old token was already marked used
+    sqlx::query(
+        "CREATE TRIGGER fail_rotation BEFORE INSERT ON tokens
+BEGIN SELECT RAISE(ABORT, 'simulated failure'); END",
+    )
+    .execute(&app.state.db)
+    .await
+    .unwrap();
+
+    let body = json!({ "refresh_token": session.refresh_token });
+    let (status, _) = app
+        .request(Method::POST, "/refresh", None, Some(body.clone()))
+        .await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+    let rows: Vec<(i64, bool)> = sqlx::query_as("SELECT id, used FROM tokens WHERE user_id = ?1")
+        .bind(session.user_id)
+        .fetch_all(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(rows.len(), 1);
+    assert!(!rows[0].1, "old token must not be marked used");
+
+    sqlx::query("DROP TRIGGER fail_rotation")
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+
+    let (status, body) = app.request(Method::POST, "/refresh", None, Some(body)).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn refreshing_prunes_the_users_expired_tokens() {
+    let app = spawn_app().await;
+    let stale = app.signed_in("alice", "alice@example.com").await;
+    let session = app.login("alice@example.com").await;
+
+    sqlx::query("UPDATE tokens SET exp = 0 WHERE user_id = ?1 AND id = (SELECT MIN(id) FROM tokens)")
+        .bind(stale.user_id)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+
+    let (status, body) = app
+        .request(Method::POST, "/refresh", None, Some(json!({ "refresh_token": session.refresh_token })))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    // The rotated token stays behind for replay detection, the expired one is gone
+    let exps: Vec<i64> = sqlx::query_scalar("SELECT exp FROM tokens WHERE user_id = ?1")
+        .bind(session.user_id)
+        .fetch_all(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(exps.len(), 2);
+    assert!(!exps.contains(&0));
+}
+
+#[tokio::test]
+async fn concurrent_refreshes_with_one_token_mint_a_single_successor() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let body = json!({ "refresh_token": session.refresh_token });
+
+    let (first, second) = tokio::join!(
+        app.request(Method::POST, "/refresh", None, Some(body.clone())),
+        app.request(Method::POST, "/refresh", None, Some(body.clone())),
+    );
+
+    let statuses = [first.0, second.0];
+    assert_eq!(
+        statuses.iter().filter(|status| **status == StatusCode::OK).count(),
+        1,
+        "{:?}",
+        statuses
+    );
+    assert!(statuses.contains(&StatusCode::UNAUTHORIZED), "{:?}", statuses);
+}
+
+async fn register_unverified(app: &common::TestApp, email: &str) -> String {
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/register",
+            None,
+            Some(json!({ "name": "alice", "email": email, "password": common::PASSWORD })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body["verification_token"].as_str().unwrap().to_string()
+}
+
+#[tokio::test]
+async fn registering_a_taken_name_conflicts_on_the_name() {
+    let app = spawn_app().await;
+    app.register("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/register",
+            None,
+            Some(json!({ "name": "alice", "email": "other@example.com", "password": common::PASSWORD })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert_eq!(body["details"].as_array().unwrap().len(), 1);
+    assert_eq!(body["details"][0]["field"], "name");
+}
+
+#[tokio::test]
+async fn registering_a_taken_email_conflicts_on_the_email() {
+    let app = spawn_app().await;
+    app.register("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/register",
+            None,
+            Some(json!({ "name": "alice2", "email": "alice@example.com", "password": common::PASSWORD })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert_eq!(body["details"].as_array().unwrap().len(), 1);
+    assert_eq!(body["details"][0]["field"], "email");
+}
+
+#[tokio::test]
+async fn failed_registration_leaves_no_user_behind() {
+    let app = spawn_app().await;
+    sqlx::query(
+        "CREATE TRIGGER fail_verification BEFORE INSERT ON email_verifications
+BEGIN SELECT RAISE(ABORT, 'simulated failure'); END",
+    )
+    .execute(&app.state.db)
+    .await
+    .unwrap();
+
+    let (status, _) = app
+        .request(
+            Method::POST,
+            "/register",
+            None,
+            Some(json!({ "name": "alice", "email": "alice@example.com", "password": common::PASSWORD })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(users, 0);
+}
+
+#[tokio::test]
+async fn resent_verification_token_replaces_the_old_one() {
+    let app = spawn_app().await;
+    let old_token = register_unverified(&app, "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/verify/resend",
+            None,
+            Some(json!({ "email": "ALICE@example.com" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let new_token = body["verification_token"].as_str().unwrap();
+
+    let (status, _) = app
+        .request(Method::GET, &format!("/verify?token={}", old_token), None, None)
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (status, body) = app
+        .request(Method::GET, &format!("/verify?token={}", new_token), None, None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    app.login("alice@example.com").await;
+}
+
+#[tokio::test]
+async fn resend_answers_the_same_for_unknown_and_verified_emails() {
+    let app = spawn_app().await;
+    app.register("bob", "bob@example.com").await;
+
+    for email in ["nobody@example.com", "bob@example.com"] {
+        let (status, body) = app
+            .request(Method::POST, "/verify/resend", None, Some(json!({ "email": email })))
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        assert!(body.get("verification_token").is_none(), "{}", body);
+    }
+}
+
+#[tokio::test]
+async fn password_reset_verifies_the_email() {
+    let app = spawn_app().await;
+    register_unverified(&app, "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/password/reset/request",
+            None,
+            Some(json!({ "email": "alice@example.com" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let reset_token = body["reset_token"].as_str().unwrap();
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/password/reset/confirm",
+            None,
+            Some(json!({ "token": reset_token, "new_password": common::PASSWORD })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    app.login("alice@example.com").await;
+}
+
+#[tokio::test]
+async fn deleting_a_user_invalidates_their_access_token_at_once() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    sqlx::query("DELETE FROM users WHERE id = ?1")
+        .bind(session.user_id)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+
+    let (status, body) = app
+        .request(Method::GET, "/me", Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "account_inactive");
+}
+
+#[tokio::test]
+async fn disabled_accounts_lose_their_tokens_and_cannot_log_in() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    sqlx::query("UPDATE users SET disabled_at = 1 WHERE id = ?1")
+        .bind(session.user_id)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+
+    let (status, body) = app
+        .request(Method::GET, "/conversations", Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "account_inactive");
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "email": "alice@example.com", "password": common::PASSWORD })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::FORBIDDEN, "{}", body);
+    assert_eq!(body["error"], "Account disabled");
+}
+
+// Stands in for a load benchmark: the query count is what the cache exists to cut
+#[tokio::test]
+async fn account_state_is_read_once_per_ttl_under_load() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let before = app.state.account_lookups();
+
+    for _ in 0..50 {
+        let (status, _) = app
+            .request(Method::GET, "/conversations", Some(&session.access_token), None)
+            .await;
+        assert_eq!(status, StatusCode::OK);
+    }
+    assert_eq!(app.state.account_lookups() - before, 1);
+
+    let mut settings = common::test_settings();
+    settings.account_cache_ttl_secs = 0;
+    let uncached = common::spawn_app_with(settings).await;
+    let session = uncached.signed_in("alice", "alice@example.com").await;
+    for _ in 0..50 {
+        uncached
+            .request(Method::GET, "/conversations", Some(&session.access_token), None)
+            .await;
+    }
+    assert_eq!(uncached.state.account_lookups(), 50);
+}
+
+#[tokio::test]
+async fn login_accepts_a_username_or_an_email_identifier() {
+    let app = spawn_app().await;
+    app.register("alice", "alice@example.com").await;
+
+    for identifier in ["alice", "Alice@Example.com"] {
+        let (status, body) = app
+            .request(
+                Method::POST,
+                "/login",
+                None,
+                Some(json!({ "identifier": identifier, "password": common::PASSWORD })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "{}: {}", identifier, body);
+        assert!(body["access_token"].is_string());
+    }
+
+    // The old key keeps working for clients that haven't moved over
+    let (status, _) = app
+        .request(
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "email": "alice", "password": common::PASSWORD })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+}
+
+#[tokio::test]
+async fn unknown_username_and_wrong_password_fail_alike() {
+    let app = spawn_app().await;
+    app.register("alice", "alice@example.com").await;
+
+    let (unknown_status, mut unknown) = app
+        .request(
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "identifier": "mallory", "password": common::PASSWORD })),
+        )
+        .await;
+    let (wrong_status, mut wrong) = app
+        .request(
+            Method::POST,
+            "/login",
+            None,
+            Some(json!({ "identifier": "alice", "password": "Wr0ng-password" })),
+        )
+        .await;
+
+    assert_eq!(unknown_status, StatusCode::BAD_REQUEST);
+    assert_eq!(unknown_status, wrong_status);
+    // Only the per-request id may differ
+    unknown.as_object_mut().unwrap().remove("request_id");
+    wrong.as_object_mut().unwrap().remove("request_id");
+    assert_eq!(unknown, wrong);
+}
+
+#[tokio::test]
+async fn two_factor_is_refused_without_an_encryption_key() {
+    let provider = std::sync::Arc::new(common::CannedProvider("Canned reply"));
+    let app = common::spawn_app_with_state(common::test_state(common::test_settings(), provider).await);
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(Method::POST, "/2fa/enable", Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE, "{}", body);
+    assert_eq!(body["error"], "Two-factor unavailable");
+}
+
+#[tokio::test]
+async fn two_factor_gates_login_once_confirmed() {
+    use rback::utils::totp;
+
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, setup) = app
+        .request(Method::POST, "/2fa/enable", Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", setup);
+    assert!(setup["otpauth_uri"].as_str().unwrap().starts_with("otpauth://totp/rback:"));
+    let secret = data_encoding::BASE32_NOPAD
+        .decode(setup["secret"].as_str().unwrap().as_bytes())
+        .unwrap();
+    let stored: String = sqlx::query_scalar("SELECT totp_secret FROM users WHERE id = ?1")
+        .bind(session.user_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_ne!(stored, setup["secret"].as_str().unwrap());
+
+    // Not enforced until confirmed
+    app.login("alice@example.com").await;
+
+    let now = chrono::Utc::now().timestamp();
+    let code = |step: i64| format!("{:06}", totp::code_for_step(&secret, step));
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/2fa/verify",
+            Some(&session.access_token),
+            Some(json!({ "code": code(totp::step_at(now) - 1) })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let backup = body["backup_codes"][0].as_str().unwrap().to_string();
+    assert_eq!(body["backup_codes"].as_array().unwrap().len(), 10);
+
+    let login = |totp_code: Option<String>| {
+        let body = json!({ "identifier": "alice", "password": common::PASSWORD, "totp_code": totp_code });
+        app.request(Method::POST, "/login", None, Some(body))
+    };
+
+    let (status, body) = login(None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "Two-factor code required");
+
+    let (status, _) = login(Some(code(totp::step_at(now)))).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // A code can't be replayed, and neither can anything from before it
+    let (status, body) = login(Some(code(totp::step_at(now)))).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "Invalid two-factor code");
+
+    let (status, _) = login(Some(backup.clone())).await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = login(Some(backup)).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn wrong_two_factor_codes_lock_the_second_factor() {
+    use rback::utils::totp;
+
+    let mut settings = common::test_settings();
+    settings.totp_max_failures = 3;
+    let app = common::spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (_, setup) = app
+        .request(Method::POST, "/2fa/enable", Some(&session.access_token), None)
+        .await;
+    let secret = data_encoding::BASE32_NOPAD
+        .decode(setup["secret"].as_str().unwrap().as_bytes())
+        .unwrap();
+    let now = chrono::Utc::now().timestamp();
+    let code = |step: i64| format!("{:06}", totp::code_for_step(&secret, step));
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/2fa/verify",
+            Some(&session.access_token),
+            Some(json!({ "code": code(totp::step_at(now) - 1) })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let login = |totp_code: String| {
+        let body = json!({ "identifier": "alice", "password": common::PASSWORD, "totp_code": totp_code });
+        app.request(Method::POST, "/login", None, Some(body))
+    };
+
+    for _ in 0..3 {
+        let (status, _) = login("not-a-code".to_string()).await;
+        assert_eq!(status, StatusCode::UNAUTHORIZED);
+    }
+
+    // Even the right code is refused while locked
+    let (status, body) = login(code(totp::step_at(now))).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{}", body);
+    assert_eq!(body["error"], "Too many two-factor attempts");
+
+    sqlx::query("UPDATE users SET totp_locked_until = ?1 WHERE id = ?2")
+        .bind(now - 1)
+        .bind(session.user_id)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+    let (status, body) = login(code(totp::step_at(now))).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn sessions_list_where_each_login_came_from() {
+    use axum::{body::Body, http::{Request, header}};
+
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let request = Request::post("/login")
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::USER_AGENT, "Phone/1.0")
+        .body(Body::from(
+            json!({ "identifier": "alice", "password": common::PASSWORD }).to_string(),
+        ))
+        .unwrap();
+    let (status, phone) = app.send(request).await;
+    assert_eq!(status, StatusCode::OK, "{}", phone);
+
+    let (status, sessions) = app
+        .request(Method::GET, "/sessions", Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", sessions);
+    let sessions = sessions.as_array().unwrap().clone();
+    assert_eq!(sessions.len(), 2);
+    assert!(sessions.iter().all(|s| s["ip"] == "127.0.0.1"));
+
+    let phone_session = sessions.iter().find(|s| s["user_agent"] == "Phone/1.0").unwrap();
+    assert_eq!(phone_session["current"], false);
+    assert!(sessions.iter().any(|s| s["current"] == true));
+
+    let uri = format!("/sessions/{}", phone_session["sid"].as_str().unwrap());
+    let (status, _) = app.request(Method::DELETE, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (_, sessions) = app
+        .request(Method::GET, "/sessions", Some(&session.access_token), None)
+        .await;
+    assert_eq!(sessions.as_array().unwrap().len(), 1);
+    assert_eq!(sessions[0]["current"], true);
+}
+
+#[tokio::test]
+async fn deleting_the_account_removes_its_data_and_tokens() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let other = app.signed_in("bob", "bob@example.com").await;
+    let conversation = app.create_conversation(&session).await;
+    rback::database::connection::insert_chat_message_to_db(
+        "user", conversation, "hello", 1, &ReplyMeta::default(), &app.state.db,
+    )
+    .await
+    .unwrap();
+    app.create_conversation(&other).await;
+
+    let (status, body) = app
+        .request(Method::DELETE, "/me", Some(&session.access_token), Some(json!({ "password": "Wr0ng-password" })))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED, "{}", body);
+
+    let (status, body) = app
+        .request(Method::DELETE, "/me", Some(&session.access_token), Some(json!({ "password": common::PASSWORD })))
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT, "{}", body);
+
+    for (table, expected) in [("users", 1), ("tokens", 1), ("conversations", 1), ("messages", 0)] {
+        let count: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM {}", table))
+            .fetch_one(&app.state.db)
+            .await
+            .unwrap();
+        assert_eq!(count, expected, "{}", table);
+    }
+
+    let (status, _) = app.request(Method::GET, "/me", Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn export_holds_only_the_callers_data() {
+    use axum::{body::{Body, to_bytes}, http::{Request, header}};
+
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let other = app.signed_in("bob", "bob@example.com").await;
+    let mine = app.create_conversation(&session).await;
+    let theirs = app.create_conversation(&other).await;
+    for (conversation, content) in [(mine, "one"), (mine, "two"), (theirs, "secret")] {
+        rback::database::connection::insert_chat_message_to_db(
+            "user", conversation, content, 1, &ReplyMeta::default(), &app.state.db,
+        )
+        .await
+        .unwrap();
+    }
+
+    let request = Request::get("/me/export")
+        .header(header::AUTHORIZATION, format!("Bearer {}", session.access_token))
+        .body(Body::empty())
+        .unwrap();
+    let response = app.response(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_TYPE], "application/json");
+    let disposition = response.headers()[header::CONTENT_DISPOSITION].to_str().unwrap().to_string();
+    assert!(disposition.starts_with("attachment; filename=\"rback-export-"), "{}", disposition);
+
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let export: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(export["profile"]["email"], "alice@example.com");
+    assert!(export["profile"].get("password").is_none());
+    assert!(export.get("tokens").is_none());
+    assert_eq!(export["conversations"].as_array().unwrap().len(), 1);
+    assert_eq!(export["conversations"][0]["id"], mine);
+    let contents: Vec<&str> = export["messages"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|m| m["content"].as_str().unwrap())
+        .collect();
+    assert_eq!(contents, ["one", "two"]);
+}
+
+#[tokio::test]
+async fn legacy_shared_salt_hash_is_replaced_on_login() {
+    let app = spawn_app().await;
+    let user_id = app.register("alice", "alice@example.com").await;
+
+    let legacy = argon2::hash_encoded(
+        common::PASSWORD.as_bytes(),
+        b"test-salt-value",
+        &argon2::Config::default(),
+    )
+    .unwrap();
+    sqlx::query("UPDATE users SET password = ?1 WHERE id = ?2")
+        .bind(&legacy)
+        .bind(user_id)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+
+    app.login("alice@example.com").await;
+
+    let stored: String = sqlx::query_scalar("SELECT password FROM users WHERE id = ?1")
+        .bind(user_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_ne!(stored, legacy);
+    assert!(stored.starts_with("$argon2id$v=19$m=19456,t=2,p=1$"));
+    assert!(argon2::verify_encoded(&stored, common::PASSWORD.as_bytes()).unwrap());
+
+    // The new hash is kept as is on the next login
+    app.login("alice@example.com").await;
+    let again: String = sqlx::query_scalar("SELECT password FROM users WHERE id = ?1")
+        .bind(user_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(again, stored);
+}
+
+#[tokio::test]
+async fn passwords_get_their_own_salts() {
+    let app = spawn_app().await;
+    app.register("alice", "alice@example.com").await;
+    app.register("bob", "bob@example.com").await;
+
+    let hashes: Vec<String> = sqlx::query_scalar("SELECT password FROM users")
+        .fetch_all(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(hashes.len(), 2);
+    assert_ne!(hashes[0], hashes[1]);
+}
+
+#[tokio::test]
+async fn profile_changes_are_checked_and_a_new_email_is_verified_again() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    app.signed_in("bob", "bob@example.com").await;
+
+    let (status, body) = app
+        .request(Method::PATCH, "/me", Some(&alice.access_token), Some(json!({ "name": "alicia" })))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["name"], "alicia");
+    assert_eq!(body["email_verified"], true);
+    assert!(body.get("verification_token").is_none());
+
+    for (change, field) in [(json!({ "name": "bob" }), "name"), (json!({ "email": "BOB@example.com" }), "email")] {
+        let (status, body) = app.request(Method::PATCH, "/me", Some(&alice.access_token), Some(change)).await;
+        assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+        assert_eq!(body["details"][0]["field"], field);
+    }
+
+    let (status, _) = app
+        .request(Method::PATCH, "/me", Some(&alice.access_token), Some(json!({ "name": "al" })))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    // The email only changes with the current password
+    let change = |password: Option<&str>| json!({ "email": "Alicia@Example.com", "current_password": password });
+    let (status, body) = app.request(Method::PATCH, "/me", Some(&alice.access_token), Some(change(None))).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["details"][0]["field"], "current_password");
+    let (status, _) = app
+        .request(Method::PATCH, "/me", Some(&alice.access_token), Some(change(Some("wrong"))))
+        .await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, body) = app
+        .request(Method::PATCH, "/me", Some(&alice.access_token), Some(change(Some(common::PASSWORD))))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["email"], "alicia@example.com");
+    assert_eq!(body["email_verified"], false);
+
+    let token = body["verification_token"].as_str().unwrap();
+    let (status, _) = app.request(Method::GET, &format!("/verify?token={}", token), None, None).await;
+    assert_eq!(status, StatusCode::OK);
+
+    // Issued tokens keep the old claims until they are refreshed
+    let (_, tokens) = app
+        .request(Method::POST, "/refresh", None, Some(json!({ "refresh_token": alice.refresh_token })))
+        .await;
+    let claims = decode_access_token(tokens["new_access_token"].as_str().unwrap(), "test-access-key").unwrap();
+    assert_eq!(claims.name, "alicia");
+    assert_eq!(claims.email, "alicia@example.com");
+}
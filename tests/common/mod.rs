@@ -0,0 +1,294 @@
+#![allow(dead_code)]
+
+use std::{net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use axum::{
+    Router,
+    body::{Body, to_bytes},
+    extract::connect_info::MockConnectInfo,
+    http::{Method, Request, StatusCode, header},
+    response::Response,
+};
+use futures::{StreamExt, future::BoxFuture, stream};
+use rback::{
+    ai::{AiProvider, AiRequest, AiResult, AiStream, ModelInfo, ProviderError, TokenUsage},
+    database::connection::connect_to_database,
+    models::app::{AppState, Settings},
+    routes::{router, trim_trailing_slash},
+};
+use serde_json::{Value, json};
+use tower::ServiceExt;
+use tower_http::normalize_path::NormalizePath;
+
+pub const PASSWORD: &str = "Passw0rd!!";
+
+/// The full router over a fresh in-memory database, driven without a socket.
+pub struct TestApp {
+    pub state: Arc<AppState>,
+    router: NormalizePath<Router>,
+}
+
+pub struct Session {
+    pub user_id: i64,
+    pub access_token: String,
+    pub refresh_token: String,
+}
+
+pub fn test_settings() -> Settings {
+    let mut settings = Settings::from_env();
+    settings.database_url = "sqlite::memory:".to_string();
+    // Every connection to `:memory:` is its own database
+    settings.db_max_connections = 1;
+    settings.dev_mode = true;
+    settings
+}
+
+pub async fn spawn_app() -> TestApp {
+    spawn_app_with(test_settings()).await
+}
+
+pub async fn spawn_app_with(settings: Settings) -> TestApp {
+    spawn_app_with_provider(settings, Arc::new(CannedProvider("Canned reply"))).await
+}
+
+pub async fn spawn_app_with_provider(settings: Settings, provider: Arc<dyn AiProvider>) -> TestApp {
+    let state = test_state(settings, provider).await.with_totp_key("test-totp-key".into());
+    spawn_app_with_state(state)
+}
+
+/// State over a fresh in-memory database with the test keys, for tests that finish it themselves.
+pub async fn test_state(settings: Settings, provider: Arc<dyn AiProvider>) -> AppState {
+    let pool = connect_to_database(&settings).await;
+    AppState::new(
+        pool,
+        "test-salt-value".into(),
+        "test-access-key".into(),
+        "test-refresh-key".into(),
+        provider,
+        settings,
+    )
+}
+
+pub fn spawn_app_with_state(state: AppState) -> TestApp {
+    let state = Arc::new(state);
+    let router = trim_trailing_slash(
+        router(state.clone()).layer(MockConnectInfo(SocketAddr::from(([127, 0, 0, 1], 4006)))),
+    );
+
+    TestApp { state, router }
+}
+
+/// Answers every prompt with the same text, streamed as one chunk per word.
+pub struct CannedProvider(pub &'static str);
+
+impl CannedProvider {
+    fn result(&self, text: &str, last: bool) -> AiResult {
+        AiResult {
+            text: text.to_string(),
+            usage: last.then_some(TokenUsage { response_tokens: 3, total_tokens: 10 }),
+            finish_reason: last.then(|| "STOP".to_string()),
+            cached: false,
+        }
+    }
+}
+
+/// The test providers stand in for Gemini: they serve its models, streamed and text-only.
+fn gemini_like(model: &str) -> Option<ModelInfo> {
+    model.starts_with("gemini-").then_some(ModelInfo { streaming: true, vision: false })
+}
+
+impl AiProvider for CannedProvider {
+    fn name(&self) -> &'static str {
+        "canned"
+    }
+
+    fn model_info(&self, model: &str) -> Option<ModelInfo> {
+        gemini_like(model)
+    }
+
+    fn generate<'a>(&'a self, _: &'a AiRequest) -> BoxFuture<'a, Result<AiResult, ProviderError>> {
+        Box::pin(async move { Ok(self.result(self.0, true)) })
+    }
+
+    fn generate_stream<'a>(&'a self, _: &'a AiRequest) -> BoxFuture<'a, Result<AiStream, ProviderError>> {
+        let words: Vec<&str> = self.0.split_inclusive(' ').collect();
+        let chunks: Vec<_> = words
+            .iter()
+            .enumerate()
+            .map(|(i, word)| Ok(self.result(word, i + 1 == words.len())))
+            .collect();
+        Box::pin(async move { Ok(Box::pin(stream::iter(chunks)) as AiStream) })
+    }
+}
+
+/// Streams its reply a word at a time with `delay` before each word, like a model taking its time.
+pub struct SlowProvider {
+    pub reply: &'static str,
+    pub delay: Duration,
+}
+
+impl AiProvider for SlowProvider {
+    fn name(&self) -> &'static str {
+        "slow"
+    }
+
+    fn model_info(&self, model: &str) -> Option<ModelInfo> {
+        gemini_like(model)
+    }
+
+    fn generate<'a>(&'a self, request: &'a AiRequest) -> BoxFuture<'a, Result<AiResult, ProviderError>> {
+        Box::pin(async move {
+            tokio::time::sleep(self.delay).await;
+            CannedProvider(self.reply).generate(request).await
+        })
+    }
+
+    fn generate_stream<'a>(&'a self, request: &'a AiRequest) -> BoxFuture<'a, Result<AiStream, ProviderError>> {
+        Box::pin(async move {
+            let delay = self.delay;
+            let words = CannedProvider(self.reply).generate_stream(request).await?;
+            Ok(Box::pin(words.then(move |word| async move {
+                tokio::time::sleep(delay).await;
+                word
+            })) as AiStream)
+        })
+    }
+}
+
+/// Answers like `CannedProvider` and keeps how many turns each request carried.
+#[derive(Default)]
+pub struct RecordingProvider {
+    pub turns: Mutex<Vec<usize>>,
+}
+
+impl AiProvider for RecordingProvider {
+    fn name(&self) -> &'static str {
+        "recording"
+    }
+
+    fn model_info(&self, model: &str) -> Option<ModelInfo> {
+        gemini_like(model)
+    }
+
+    fn generate<'a>(&'a self, request: &'a AiRequest) -> BoxFuture<'a, Result<AiResult, ProviderError>> {
+        self.turns.lock().unwrap().push(request.turns.len());
+        CannedProvider("Canned reply").generate(request)
+    }
+
+    fn generate_stream<'a>(&'a self, request: &'a AiRequest) -> BoxFuture<'a, Result<AiStream, ProviderError>> {
+        self.turns.lock().unwrap().push(request.turns.len());
+        Box::pin(async move { CannedProvider("Canned reply").generate_stream(request).await })
+    }
+}
+
+impl TestApp {
+    pub async fn request(
+        &self,
+        method: Method,
+        uri: &str,
+        token: Option<&str>,
+        body: Option<Value>,
+    ) -> (StatusCode, Value) {
+        let mut builder = Request::builder().method(method).uri(uri);
+        if let Some(token) = token {
+            builder = builder.header(header::AUTHORIZATION, format!("Bearer {}", token));
+        }
+
+        let request = match body {
+            Some(body) => builder
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(Body::from(body.to_string())),
+            None => builder.body(Body::empty()),
+        }
+        .unwrap();
+
+        self.send(request).await
+    }
+
+    /// Sends a hand-built request, for when headers beyond the bearer token matter.
+    pub async fn send(&self, request: Request<Body>) -> (StatusCode, Value) {
+        let response = self.response(request).await;
+        let status = response.status();
+        let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let body = serde_json::from_slice(&bytes).unwrap_or(Value::Null);
+
+        (status, body)
+    }
+
+    /// The raw response, for asserting on headers.
+    pub async fn response(&self, request: Request<Body>) -> Response {
+        self.router.clone().oneshot(request).await.unwrap()
+    }
+
+    /// Registers an account and confirms its email with the token dev mode hands back.
+    pub async fn register(&self, name: &str, email: &str) -> i64 {
+        let (status, body) = self
+            .request(
+                Method::POST,
+                "/register",
+                None,
+                Some(json!({ "name": name, "email": email, "password": PASSWORD })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "register failed: {}", body);
+
+        let user_id = body["user_id"].as_i64().unwrap();
+        let token = body["verification_token"].as_str().unwrap();
+        let (status, body) = self
+            .request(Method::GET, &format!("/verify?token={}", token), None, None)
+            .await;
+        assert_eq!(status, StatusCode::OK, "verify failed: {}", body);
+
+        user_id
+    }
+
+    pub async fn login(&self, email: &str) -> Session {
+        let (status, body) = self
+            .request(
+                Method::POST,
+                "/login",
+                None,
+                Some(json!({ "email": email, "password": PASSWORD })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK, "login failed: {}", body);
+
+        Session {
+            user_id: self.user_id(email).await,
+            access_token: body["access_token"].as_str().unwrap().to_string(),
+            refresh_token: body["refresh_token"].as_str().unwrap().to_string(),
+        }
+    }
+
+    pub async fn signed_in(&self, name: &str, email: &str) -> Session {
+        self.register(name, email).await;
+        self.login(email).await
+    }
+
+    pub async fn create_conversation(&self, session: &Session) -> i64 {
+        let (status, body) = self
+            .request(Method::POST, "/conversations", Some(&session.access_token), None)
+            .await;
+        assert_eq!(status, StatusCode::OK, "create conversation failed: {}", body);
+        body["id"].as_i64().unwrap()
+    }
+
+    /// Serves the router on an ephemeral port, for clients that need a real connection.
+    pub async fn spawn_server(&self) -> SocketAddr {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = axum::ServiceExt::<Request<Body>>::into_make_service_with_connect_info::<SocketAddr>(
+            trim_trailing_slash(router(self.state.clone())),
+        );
+        tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+        addr
+    }
+
+    pub async fn user_id(&self, email: &str) -> i64 {
+        sqlx::query_scalar("SELECT id FROM users WHERE email = ?1 COLLATE NOCASE")
+            .bind(email)
+            .fetch_one(&self.state.db)
+            .await
+            .unwrap()
+    }
+}
@@ -0,0 +1,1586 @@
+mod common;
+
+use axum::{
+    body::{Body, to_bytes},
+    http::{Method, Request, StatusCode, header},
+};
+use rback::{
+    database::connection::insert_chat_message_to_db,
+    models::{ai::ReplyMeta, app::GenerationEnd},
+};
+use serde_json::{Value, json};
+use std::{sync::Arc, time::Duration};
+
+use common::{RecordingProvider, SlowProvider, TestApp, spawn_app, spawn_app_with, spawn_app_with_provider, test_settings};
+
+async fn add_message(app: &TestApp, conversation_id: i64, role: &str, content: &str) -> i64 {
+    insert_chat_message_to_db(role, conversation_id, content, 1, &ReplyMeta::default(), &app.state.db)
+        .await
+        .unwrap()
+        .id
+}
+
+#[tokio::test]
+async fn message_moves_between_own_conversations() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let source = app.create_conversation(&session).await;
+    let target = app.create_conversation(&session).await;
+    let message_id = add_message(&app, source, "user", "misfiled").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            &format!("/conversations/{}/messages/{}/move", source, message_id),
+            Some(&session.access_token),
+            Some(json!({ "target_conversation_id": target })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::NO_CONTENT, "{}", body);
+
+    let (_, page) = app
+        .request(
+            Method::GET,
+            &format!("/conversations/{}/messages", target),
+            Some(&session.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(page["total"], 1);
+    assert_eq!(page["items"][0]["id"], message_id);
+    assert_eq!(page["items"][0]["content"], "misfiled");
+
+    let (_, page) = app
+        .request(
+            Method::GET,
+            &format!("/conversations/{}/messages", source),
+            Some(&session.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(page["total"], 0);
+}
+
+#[tokio::test]
+async fn message_cannot_move_into_someone_elses_conversation() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let source = app.create_conversation(&alice).await;
+    let foreign = app.create_conversation(&bob).await;
+    let message_id = add_message(&app, source, "user", "stays put").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            &format!("/conversations/{}/messages/{}/move", source, message_id),
+            Some(&alice.access_token),
+            Some(json!({ "target_conversation_id": foreign })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+    assert_eq!(body["details"][0]["field"], "target_conversation_id");
+
+    let conversation_id: i64 = sqlx::query_scalar("SELECT conversation_id FROM messages WHERE id = ?1")
+        .bind(message_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(conversation_id, source);
+}
+
+#[tokio::test]
+async fn conversation_is_created_read_and_deleted() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    let uri = format!("/conversations/{}", id);
+
+    let (status, body) = app.request(Method::GET, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["id"], id);
+
+    let (status, _) = app.request(Method::DELETE, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT);
+
+    let (status, _) = app.request(Method::GET, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (_, body) = app
+        .request(Method::GET, "/conversations", Some(&session.access_token), None)
+        .await;
+    assert_eq!(body["total"], 0);
+}
+
+#[tokio::test]
+async fn title_update_with_current_version_succeeds() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    let (status, body) = app
+        .request(
+            Method::PUT,
+            &format!("/conversations/{}", id),
+            Some(&session.access_token),
+            Some(json!({ "title": "Renamed", "expected_version": 1 })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["title"], "Renamed");
+    assert_eq!(body["version"], 2);
+}
+
+#[tokio::test]
+async fn overlong_or_blank_titles_are_rejected() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    for title in ["x".repeat(201), "   ".to_string(), "line\u{7}bell".to_string()] {
+        let (status, body) = app
+            .request(
+                Method::PUT,
+                &format!("/conversations/{}", id),
+                Some(&session.access_token),
+                Some(json!({ "title": title })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+        assert_eq!(body["details"][0]["field"], "title");
+    }
+
+    let (status, body) = app
+        .request(
+            Method::PUT,
+            &format!("/conversations/{}", id),
+            Some(&session.access_token),
+            Some(json!({ "title": "  Trip notes  " })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["title"], "Trip notes");
+}
+
+#[tokio::test]
+async fn stale_title_update_conflicts_even_within_the_same_second() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    let uri = format!("/conversations/{}", id);
+
+    // Both clients read version 1; the second write lands right after the first
+    let (status, _) = app
+        .request(
+            Method::PUT,
+            &uri,
+            Some(&session.access_token),
+            Some(json!({ "title": "First", "expected_version": 1 })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, body) = app
+        .request(
+            Method::PUT,
+            &uri,
+            Some(&session.access_token),
+            Some(json!({ "title": "Second", "expected_version": 1 })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+
+    let (_, body) = app
+        .request(Method::GET, &uri, Some(&session.access_token), None)
+        .await;
+    assert_eq!(body["title"], "First");
+}
+
+#[tokio::test]
+async fn title_update_checks_expected_updated_at() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    let uri = format!("/conversations/{}", id);
+
+    let (_, body) = app.request(Method::GET, &uri, Some(&session.access_token), None).await;
+    let updated_at = body["updated_at"].as_i64().unwrap();
+
+    let (status, body) = app
+        .request(
+            Method::PUT,
+            &uri,
+            Some(&session.access_token),
+            Some(json!({ "title": "Renamed", "expected_updated_at": updated_at })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["title"], "Renamed");
+
+    let (status, body) = app
+        .request(
+            Method::PUT,
+            &uri,
+            Some(&session.access_token),
+            Some(json!({ "title": "Stale", "expected_updated_at": updated_at - 60 })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert_eq!(body["details"][0]["field"], "expected_updated_at");
+}
+
+#[tokio::test]
+async fn title_update_without_version_always_applies() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    for title in ["One", "Two"] {
+        let (status, body) = app
+            .request(
+                Method::PUT,
+                &format!("/conversations/{}", id),
+                Some(&session.access_token),
+                Some(json!({ "title": title })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body["title"], title);
+    }
+}
+
+#[tokio::test]
+async fn editing_a_prompt_drops_the_replies_after_it() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    let prompt = add_message(&app, id, "user", "first question").await;
+    add_message(&app, id, "assistant", "first answer").await;
+
+    let (status, body) = app
+        .request(
+            Method::PUT,
+            &format!("/conversations/{}/messages/{}", id, prompt),
+            Some(&session.access_token),
+            Some(json!({ "content": "better question" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body.as_array().unwrap().len(), 1);
+    assert_eq!(body[0]["content"], "better question");
+}
+
+#[tokio::test]
+async fn editing_in_someone_elses_conversation_is_not_found() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let id = app.create_conversation(&bob).await;
+    let prompt = add_message(&app, id, "user", "bob's question").await;
+
+    let (status, body) = app
+        .request(
+            Method::PUT,
+            &format!("/conversations/{}/messages/{}", id, prompt),
+            Some(&alice.access_token),
+            Some(json!({ "content": "hijacked" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+
+    let (status, _) = app
+        .request(
+            Method::PUT,
+            &format!("/conversations/{}/messages/{}", id, prompt + 100),
+            Some(&bob.access_token),
+            Some(json!({ "content": "missing" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn regenerating_someone_elses_reply_is_not_found() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let id = app.create_conversation(&bob).await;
+    add_message(&app, id, "user", "question").await;
+    add_message(&app, id, "assistant", "answer").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            &format!("/conversations/{}/regenerate", id),
+            Some(&alice.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND, "{}", body);
+}
+
+#[tokio::test]
+async fn regenerating_without_a_reply_is_rejected() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    add_message(&app, id, "user", "unanswered").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            &format!("/conversations/{}/regenerate", id),
+            Some(&session.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["error"], "Nothing to regenerate");
+}
+
+#[tokio::test]
+async fn regenerating_waits_for_the_conversations_generation_slot() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    add_message(&app, id, "user", "question").await;
+    add_message(&app, id, "assistant", "answer").await;
+    let uri = format!("/conversations/{}/regenerate", id);
+
+    let _reply = app.state.start_generation(id).unwrap();
+    let (status, body) = app.request(Method::POST, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+
+    app.state.finish_generation(id, GenerationEnd::Done);
+    let (status, body) = app.request(Method::POST, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(!app.state.has_active_generations());
+
+    let contents: Vec<String> =
+        sqlx::query_scalar("SELECT content FROM messages WHERE conversation_id = ?1 ORDER BY id")
+            .bind(id)
+            .fetch_all(&app.state.db)
+            .await
+            .unwrap();
+    assert_eq!(contents, ["question", "Canned reply"]);
+}
+
+#[tokio::test]
+async fn regenerate_shares_the_ai_rate_limit() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    let uri = format!("/conversations/{}/regenerate", id);
+
+    // The burst of five is spent even by requests the handler rejects
+    for _ in 0..5 {
+        let (status, body) = app
+            .request(Method::POST, &uri, Some(&session.access_token), None)
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    }
+
+    let (status, _) = app
+        .request(Method::POST, &uri, Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
+
+#[tokio::test]
+async fn trailing_slashes_reach_the_same_routes() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    let (status, body) = app
+        .request(Method::GET, "/conversations/", Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let (status, body) = app
+        .request(
+            Method::GET,
+            &format!("/conversations/{}/messages/", id),
+            Some(&session.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+}
+
+#[tokio::test]
+async fn unknown_model_is_rejected_before_calling_gemini() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::GET,
+            "/text",
+            Some(&session.access_token),
+            Some(json!({ "msg": "hi", "model": "gemini-ultra" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["details"][0]["field"], "model");
+}
+
+#[tokio::test]
+async fn model_from_another_provider_is_rejected() {
+    let mut settings = test_settings();
+    settings.gemini_allowed_models.push("gpt-4o".to_string());
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::GET,
+            "/text",
+            Some(&session.access_token),
+            Some(json!({ "msg": "hi", "model": "gpt-4o" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["details"][0]["field"], "model");
+    assert_eq!(body["details"][0]["messages"][0], "gpt-4o isn't served by the canned provider.");
+}
+
+#[tokio::test]
+async fn images_for_a_text_only_model_are_rejected_before_anything_is_stored() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            &format!("/conversations/{}/messages/stream", id),
+            Some(&session.access_token),
+            Some(json!({ "msg": "What is this?", "images": ["https://example.com/cat.png"] })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["details"][0]["field"], "images");
+    assert_eq!(body["details"][0]["messages"][0], "gemini-2.0-flash can't read images.");
+
+    let (_, body) = app
+        .request(Method::GET, &format!("/conversations/{}/messages", id), Some(&session.access_token), None)
+        .await;
+    assert_eq!(body["items"], json!([]));
+}
+
+#[tokio::test]
+async fn history_shows_how_each_reply_was_made() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    add_message(&app, id, "user", "question").await;
+    let meta = ReplyMeta {
+        model: Some("gemini-1.5-pro"),
+        stopped: false,
+        latency_ms: Some(840),
+        finish_reason: Some("MAX_TOKENS"),
+        cached: false,
+    };
+    insert_chat_message_to_db("assistant", id, "answer", 1, &meta, &app.state.db)
+        .await
+        .unwrap();
+
+    let (status, body) = app
+        .request(
+            Method::GET,
+            &format!("/conversations/{}/messages", id),
+            Some(&session.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    for field in ["model", "latency_ms", "finish_reason"] {
+        assert_eq!(body["items"][0][field], serde_json::Value::Null, "{}", field);
+    }
+    assert_eq!(body["items"][1]["model"], "gemini-1.5-pro");
+    assert_eq!(body["items"][1]["latency_ms"], 840);
+    assert_eq!(body["items"][1]["finish_reason"], "MAX_TOKENS");
+}
+
+#[tokio::test]
+async fn generation_settings_are_saved_per_conversation() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    let uri = format!("/conversations/{}/settings", id);
+
+    let (status, body) = app.request(Method::GET, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["temperature"], serde_json::Value::Null);
+
+    let settings = json!({ "temperature": 0.5, "top_p": 0.25, "max_output_tokens": 256 });
+    let (status, body) = app
+        .request(Method::PUT, &uri, Some(&session.access_token), Some(settings.clone()))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let (_, body) = app.request(Method::GET, &uri, Some(&session.access_token), None).await;
+    assert_eq!(body, settings);
+}
+
+#[tokio::test]
+async fn out_of_range_generation_settings_are_rejected() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    for (field, value) in [
+        ("temperature", json!(2.5)),
+        ("top_p", json!(-0.1)),
+        ("max_output_tokens", json!(0)),
+    ] {
+        let (status, body) = app
+            .request(
+                Method::PUT,
+                &format!("/conversations/{}/settings", id),
+                Some(&session.access_token),
+                Some(json!({ field: value })),
+            )
+            .await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+        assert_eq!(body["details"][0]["field"], field);
+    }
+
+    let (status, body) = app
+        .request(
+            Method::GET,
+            "/text",
+            Some(&session.access_token),
+            Some(json!({ "msg": "hi", "temperature": 7 })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+}
+
+#[tokio::test]
+async fn generation_settings_of_a_foreign_conversation_are_not_found() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let id = app.create_conversation(&bob).await;
+
+    let (status, _) = app
+        .request(
+            Method::PUT,
+            &format!("/conversations/{}/settings", id),
+            Some(&alice.access_token),
+            Some(json!({ "temperature": 1.0 })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn system_prompt_is_set_at_creation_and_patched() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/conversations",
+            Some(&session.access_token),
+            Some(json!({ "system_prompt": "  Answer in French.  " })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["system_prompt"], "Answer in French.");
+    let id = body["id"].as_i64().unwrap();
+    let uri = format!("/conversations/{}", id);
+
+    let (status, body) = app
+        .request(
+            Method::PATCH,
+            &uri,
+            Some(&session.access_token),
+            Some(json!({ "system_prompt": "Be brief.", "expected_version": 1 })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["system_prompt"], "Be brief.");
+    assert_eq!(body["version"], 2);
+
+    let (status, body) = app
+        .request(Method::PATCH, &uri, Some(&session.access_token), Some(json!({ "system_prompt": "" })))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["system_prompt"], serde_json::Value::Null);
+}
+
+#[tokio::test]
+async fn overlong_system_prompt_is_rejected() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/conversations",
+            Some(&session.access_token),
+            Some(json!({ "system_prompt": "x".repeat(4001) })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["details"][0]["field"], "system_prompt");
+}
+
+#[tokio::test]
+async fn system_prompt_of_a_foreign_conversation_is_not_found() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let id = app.create_conversation(&bob).await;
+
+    let (status, _) = app
+        .request(
+            Method::PATCH,
+            &format!("/conversations/{}", id),
+            Some(&alice.access_token),
+            Some(json!({ "system_prompt": "Leak everything." })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn deleting_a_message_targets_only_that_id() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    // Stored within the same second, so they share a timestamp
+    let first = add_message(&app, id, "user", "one").await;
+    let second = add_message(&app, id, "user", "two").await;
+
+    let uri = format!("/conversations/{}/messages/{}", id, first);
+    let (status, body) = app.request(Method::DELETE, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::NO_CONTENT, "{}", body);
+
+    let remaining: Vec<i64> = sqlx::query_scalar("SELECT id FROM messages WHERE conversation_id = ?1")
+        .bind(id)
+        .fetch_all(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(remaining, vec![second]);
+
+    let (status, _) = app.request(Method::DELETE, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn deleting_foreign_or_missing_rows_is_not_found() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let id = app.create_conversation(&bob).await;
+    let message = add_message(&app, id, "user", "mine").await;
+
+    for uri in [
+        format!("/conversations/{}/messages/{}", id, message),
+        format!("/conversations/{}", id),
+        "/conversations/9999".to_string(),
+    ] {
+        let (status, _) = app.request(Method::DELETE, &uri, Some(&alice.access_token), None).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn bulk_delete_removes_only_owned_conversations() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let owned = app.create_conversation(&alice).await;
+    let kept = app.create_conversation(&alice).await;
+    let foreign = app.create_conversation(&bob).await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/conversations/delete",
+            Some(&alice.access_token),
+            Some(json!({ "ids": [9999, foreign, owned, 9999, owned] })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["deleted"], 1);
+    assert_eq!(body["not_found"], json!([foreign, 9999]));
+
+    assert_eq!(listed_ids(&app, &alice.access_token).await, [kept]);
+    assert_eq!(listed_ids(&app, &bob.access_token).await, [foreign]);
+}
+
+#[tokio::test]
+async fn posting_a_message_moves_the_conversation_to_the_top() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let older = app.create_conversation(&session).await;
+    let newer = app.create_conversation(&session).await;
+    // Backdated so the reorder doesn't depend on the clock ticking between requests
+    sqlx::query("UPDATE conversations SET updated_at = 100 + id")
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+
+    let ids = |body: serde_json::Value| -> Vec<i64> {
+        body["items"].as_array().unwrap().iter().map(|c| c["id"].as_i64().unwrap()).collect()
+    };
+
+    let (_, body) = app.request(Method::GET, "/conversations", Some(&session.access_token), None).await;
+    assert_eq!(ids(body), vec![newer, older]);
+
+    add_message(&app, older, "user", "bump").await;
+
+    let (_, body) = app.request(Method::GET, "/conversations", Some(&session.access_token), None).await;
+    assert_eq!(ids(body), vec![older, newer]);
+}
+
+#[tokio::test]
+async fn conversation_list_is_paginated() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    for _ in 0..5 {
+        app.create_conversation(&session).await;
+    }
+
+    let (status, body) = app
+        .request(Method::GET, "/conversations?page=2&limit=2", Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["items"].as_array().unwrap().len(), 2);
+    assert_eq!(body["total"], 5);
+    assert_eq!(body["total_pages"], 3);
+    assert_eq!(body["page"], 2);
+
+    let (_, body) = app.request(Method::GET, "/conversations", Some(&session.access_token), None).await;
+    assert_eq!(body["items"].as_array().unwrap().len(), 5);
+    assert_eq!(body["limit"], 50);
+
+    for uri in ["/conversations?page=0", "/conversations?limit=0"] {
+        let (status, body) = app.request(Method::GET, uri, Some(&session.access_token), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+        assert_eq!(body["error"], "Invalid pagination parameters");
+    }
+}
+
+#[tokio::test]
+async fn messages_page_forwards_and_back_from_a_cursor() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&session).await;
+    let mut ids = Vec::new();
+    for n in 0..5 {
+        ids.push(add_message(&app, conversation_id, "user", &format!("message {}", n)).await);
+    }
+    let page_ids = |body: &Value| -> Vec<i64> {
+        body["items"].as_array().unwrap().iter().map(|m| m["id"].as_i64().unwrap()).collect()
+    };
+
+    let uri = format!("/conversations/{}/messages?limit=2", conversation_id);
+    let (_, first) = app.request(Method::GET, &uri, Some(&session.access_token), None).await;
+    assert_eq!(page_ids(&first), ids[..2]);
+    assert_eq!(first["prev_cursor"], Value::Null);
+    assert_eq!(first["next_cursor"], ids[1]);
+
+    let uri = format!("/conversations/{}/messages?limit=2&after={}", conversation_id, ids[1]);
+    let (_, second) = app.request(Method::GET, &uri, Some(&session.access_token), None).await;
+    assert_eq!(page_ids(&second), ids[2..4]);
+    assert_eq!(second["total"], 3);
+    assert_eq!(second["prev_cursor"], ids[2]);
+    assert_eq!(second["next_cursor"], ids[3]);
+
+    let uri = format!("/conversations/{}/messages?limit=2&after={}", conversation_id, ids[3]);
+    let (_, last) = app.request(Method::GET, &uri, Some(&session.access_token), None).await;
+    assert_eq!(page_ids(&last), ids[4..]);
+    assert_eq!(last["next_cursor"], Value::Null);
+
+    let uri = format!("/conversations/{}/messages?limit=2&before={}", conversation_id, ids[2]);
+    let (_, back) = app.request(Method::GET, &uri, Some(&session.access_token), None).await;
+    assert_eq!(page_ids(&back), ids[..2]);
+    assert_eq!(back["prev_cursor"], Value::Null);
+    assert_eq!(back["next_cursor"], ids[1]);
+
+    let uri = format!("/conversations/{}/messages?before={}&after={}", conversation_id, ids[3], ids[1]);
+    let (status, _) = app.request(Method::GET, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn conversation_limit_stops_creation_at_the_boundary() {
+    let mut settings = test_settings();
+    settings.max_conversations = 2;
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let first = app.create_conversation(&session).await;
+    app.create_conversation(&session).await;
+
+    let (status, body) = app.request(Method::POST, "/conversations", Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert_eq!(body["error"], "Conversation limit reached");
+
+    // Archived conversations don't count
+    let (status, _) = app
+        .request(Method::PATCH, &format!("/conversations/{}/archive", first), Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK);
+    app.create_conversation(&session).await;
+
+    // An account's own limit wins over the default
+    sqlx::query("UPDATE users SET max_conversations = 3 WHERE id = ?1")
+        .bind(session.user_id)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+    app.create_conversation(&session).await;
+    let (status, _) = app.request(Method::POST, "/conversations", Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+}
+
+#[tokio::test]
+async fn imported_chat_keeps_its_messages_in_order() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/conversations/import",
+            Some(&session.access_token),
+            Some(json!({
+                "title": "From elsewhere",
+                "messages": [
+                    { "role": "system", "content": "Be brief." },
+                    { "role": "user", "content": "Hi", "timestamp": 1_700_000_000 },
+                    { "role": "assistant", "content": "Hello!" },
+                    { "role": "user", "content": "Bye", "timestamp": 1_700_000_060 },
+                ],
+            })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["title"], "From elsewhere");
+    let timestamps: Vec<i64> = body["messages"].as_array().unwrap().iter().map(|m| m["timestamp"].as_i64().unwrap()).collect();
+    assert_eq!(timestamps, [1_700_000_000, 1_700_000_000, 1_700_000_000, 1_700_000_060]);
+
+    let (_, page) = app
+        .request(
+            Method::GET,
+            &format!("/conversations/{}/messages", body["id"]),
+            Some(&session.access_token),
+            None,
+        )
+        .await;
+    let contents: Vec<&str> = page["items"].as_array().unwrap().iter().map(|m| m["content"].as_str().unwrap()).collect();
+    assert_eq!(contents, ["Be brief.", "Hi", "Hello!", "Bye"]);
+}
+
+#[tokio::test]
+async fn imported_replies_may_run_longer_than_a_prompt() {
+    let mut settings = test_settings();
+    settings.max_message_chars = 10;
+    settings.ws_max_message_bytes = 10;
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let import = |prompt: &str| {
+        json!({
+            "title": "Long answers",
+            "messages": [
+                { "role": "user", "content": prompt },
+                { "role": "assistant", "content": "An answer well past ten characters." },
+            ],
+        })
+    };
+
+    let (status, body) = app
+        .request(Method::POST, "/conversations/import", Some(&session.access_token), Some(import("Why?")))
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/conversations/import",
+            Some(&session.access_token),
+            Some(import("Why is that so long?")),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["details"][0]["field"], "messages[0].content");
+}
+
+#[tokio::test]
+async fn invalid_import_is_rejected_whole() {
+    let mut settings = test_settings();
+    settings.import_max_message_bytes = 16;
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/conversations/import",
+            Some(&session.access_token),
+            Some(json!({
+                "title": "Broken",
+                "messages": [
+                    { "role": "user", "content": "Hi", "timestamp": 1_700_000_060 },
+                    { "role": "tool", "content": "{}" },
+                    { "role": "assistant", "content": "far too long for the limit" },
+                    { "role": "user", "content": "Earlier?", "timestamp": 1_700_000_000 },
+                ],
+            })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    let fields: Vec<&str> = body["details"].as_array().unwrap().iter().map(|d| d["field"].as_str().unwrap()).collect();
+    assert_eq!(fields, ["messages[1].role", "messages[2].content", "messages[3].timestamp"]);
+
+    let (status, _) = app
+        .request(
+            Method::POST,
+            "/conversations/import",
+            Some(&session.access_token),
+            Some(json!({ "title": "Empty", "messages": [] })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    let (_, list) = app.request(Method::GET, "/conversations", Some(&session.access_token), None).await;
+    assert_eq!(list["total"], 0);
+}
+
+#[tokio::test]
+async fn oversized_error_bodies_reach_the_client_whole() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let messages: Vec<Value> = (0..1000).map(|_| json!({ "role": "tool", "content": "{}" })).collect();
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/conversations/import",
+            Some(&session.access_token),
+            Some(json!({ "title": "Tools", "messages": messages })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(body["details"].as_array().unwrap().len(), 1000);
+    // Too big to be rewritten, so it goes out without the id
+    assert!(body.get("request_id").is_none());
+}
+
+fn get_with_encoding(uri: &str, token: &str, encoding: &str) -> Request<Body> {
+    Request::builder()
+        .uri(uri)
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .header(header::ACCEPT_ENCODING, encoding)
+        .body(Body::empty())
+        .unwrap()
+}
+
+#[tokio::test]
+async fn large_responses_are_compressed_when_asked_for() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    for n in 0..40 {
+        add_message(&app, id, "user", &format!("message {} {}", n, "lorem ipsum ".repeat(20))).await;
+    }
+    let uri = format!("/conversations/{}/messages?limit=40", id);
+
+    let plain = app.response(get_with_encoding(&uri, &session.access_token, "identity")).await;
+    assert!(plain.headers().get(header::CONTENT_ENCODING).is_none());
+    let plain = to_bytes(plain.into_body(), usize::MAX).await.unwrap();
+
+    let response = app.response(get_with_encoding(&uri, &session.access_token, "gzip")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+    let compressed = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    assert!(compressed.len() < plain.len() / 4, "{} vs {}", compressed.len(), plain.len());
+
+    let response = app.response(get_with_encoding(&uri, &session.access_token, "br")).await;
+    assert_eq!(response.headers()[header::CONTENT_ENCODING], "br");
+
+    // Streamed replies go out as they are produced
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/conversations/{}/messages/stream", id))
+        .header(header::AUTHORIZATION, format!("Bearer {}", session.access_token))
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::ACCEPT_ENCODING, "gzip")
+        .body(Body::from(json!({ "msg": "hi" }).to_string()))
+        .unwrap();
+    let response = app.response(request).await;
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+}
+
+#[tokio::test]
+async fn compression_can_be_turned_off() {
+    let mut settings = test_settings();
+    settings.compression_enabled = false;
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    for _ in 0..40 {
+        add_message(&app, id, "user", &"lorem ipsum ".repeat(20)).await;
+    }
+
+    let uri = format!("/conversations/{}/messages?limit=40", id);
+    let response = app.response(get_with_encoding(&uri, &session.access_token, "gzip")).await;
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+}
+
+#[tokio::test]
+async fn oversized_body_is_rejected_with_a_json_413() {
+    let mut settings = test_settings();
+    settings.max_body_bytes = 1024;
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            "/conversations",
+            Some(&session.access_token),
+            Some(json!({ "system_prompt": "x".repeat(2048) })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    assert_eq!(body["error"], "Payload too large");
+
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM conversations")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(count, 0);
+}
+
+#[tokio::test]
+async fn errors_carry_the_request_id() {
+    use axum::{body::Body, http::Request};
+
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let request = Request::builder()
+        .uri("/conversations/999")
+        .header("authorization", format!("Bearer {}", session.access_token))
+        .header("x-request-id", "trace-me-42")
+        .body(Body::empty())
+        .unwrap();
+    let (status, body) = app.send(request).await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["request_id"], "trace-me-42");
+
+    // Without one, a fresh id is made up and echoed in the header and the body alike
+    let request = Request::builder().uri("/nowhere").body(Body::empty()).unwrap();
+    let response = app.response(request).await;
+    let header = response.headers()["x-request-id"].to_str().unwrap().to_string();
+    let bytes = axum::body::to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let body: serde_json::Value = serde_json::from_slice(&bytes).unwrap();
+    assert_eq!(body["request_id"], header.as_str());
+    assert_eq!(body["error"], "Not found");
+}
+
+#[tokio::test]
+async fn statuses_tell_unauthenticated_from_forbidden_and_foreign() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let id = app.create_conversation(&alice).await;
+
+    let (status, body) = app.request(Method::GET, "/conversations", None, None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    assert_eq!(body["error"], "missing_authorization_header");
+    let (status, _) = app.request(Method::GET, "/conversations", Some("not-a-token"), None).await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+
+    let (status, body) = app.request(Method::GET, "/admin/users", Some(&bob.access_token), None).await;
+    assert_eq!(status, StatusCode::FORBIDDEN);
+    assert_eq!(body["error"], "insufficient_role");
+
+    // Someone else's conversation looks exactly like one that doesn't exist
+    let foreign = [
+        (Method::GET, format!("/conversations/{}", id), None),
+        (Method::PUT, format!("/conversations/{}", id), Some(json!({ "title": "Mine now" }))),
+        (Method::PATCH, format!("/conversations/{}/archive", id), None),
+        (Method::PATCH, format!("/conversations/{}/pin", id), None),
+        (Method::PATCH, format!("/conversations/{}/position", id), Some(json!({ "sort_order": 1 }))),
+        (Method::GET, format!("/conversations/{}/messages", id), None),
+        (Method::GET, format!("/conversations/{}/settings", id), None),
+    ];
+    for (method, uri, body) in foreign {
+        let (status, body) = app.request(method, &uri, Some(&bob.access_token), body).await;
+        assert_eq!(status, StatusCode::NOT_FOUND, "{}: {}", uri, body);
+        assert_eq!(body["error"], "Conversation not found", "{}", uri);
+    }
+}
+
+#[tokio::test]
+async fn streaming_into_someone_elses_conversation_is_not_found() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let bobs = app.create_conversation(&bob).await;
+
+    let (status, _) = app
+        .request(
+            Method::POST,
+            &format!("/conversations/{}/messages/stream", bobs),
+            Some(&alice.access_token),
+            Some(json!({ "msg": "hi" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn streaming_while_a_reply_is_in_progress_conflicts() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    let uri = format!("/conversations/{}/messages/stream", id);
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            &uri,
+            Some(&session.access_token),
+            Some(json!({ "msg": "hi", "model": "gemini-ultra" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["details"][0]["field"], "model");
+
+    let _generation = app.state.start_generation(id).unwrap();
+    let (status, body) = app
+        .request(Method::POST, &uri, Some(&session.access_token), Some(json!({ "msg": "hi" })))
+        .await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert_eq!(body["error"], "Reply in progress");
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn streamed_reply_comes_from_the_configured_provider() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/conversations/{}/messages/stream", id))
+        .header(header::AUTHORIZATION, format!("Bearer {}", session.access_token))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "msg": "hi" }).to_string()))
+        .unwrap();
+    let response = app.response(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let events = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let events = String::from_utf8(events.to_vec()).unwrap();
+    assert!(events.contains("event: done"), "{}", events);
+
+    let (status, body) = app
+        .request(Method::GET, &format!("/conversations/{}/messages", id), Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let reply = &body["items"][1];
+    assert_eq!(reply["role"], "assistant", "{}", body);
+    assert_eq!(reply["content"], "Canned reply");
+    assert_eq!(reply["token_count"], 3);
+    assert_eq!(reply["finish_reason"], "STOP");
+}
+
+async fn stream_prompt(app: &TestApp, token: &str, conversation_id: i64, msg: &str) -> Value {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/conversations/{}/messages/stream", conversation_id))
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "msg": msg }).to_string()))
+        .unwrap();
+    let response = app.response(request).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    to_bytes(response.into_body(), usize::MAX).await.unwrap();
+
+    let (_, body) = app
+        .request(Method::GET, &format!("/conversations/{}/messages", conversation_id), Some(token), None)
+        .await;
+    body["items"][1].clone()
+}
+
+#[tokio::test]
+async fn slow_replies_are_kept_alive_between_chunks() {
+    let mut settings = test_settings();
+    settings.sse_keepalive_secs = 1;
+    let provider = SlowProvider { reply: "Slow reply", delay: Duration::from_millis(1500) };
+    let app = spawn_app_with_provider(settings, Arc::new(provider)).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(format!("/conversations/{}/messages/stream", id))
+        .header(header::AUTHORIZATION, format!("Bearer {}", session.access_token))
+        .header(header::CONTENT_TYPE, "application/json")
+        .body(Body::from(json!({ "msg": "hi" }).to_string()))
+        .unwrap();
+    let response = app.response(request).await;
+    let events = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    let events = String::from_utf8(events.to_vec()).unwrap();
+
+    assert!(events.matches(": keep-alive\n\n").count() >= 2, "{}", events);
+    // The chunks themselves arrive untouched around the heartbeats
+    let chunks: Vec<&str> = events
+        .split("\n\n")
+        .filter_map(|event| event.strip_prefix("data: "))
+        .collect();
+    assert_eq!(chunks, ["Slow ", "reply"]);
+    assert!(events.contains("event: done"), "{}", events);
+}
+
+#[tokio::test]
+async fn slow_recent_replies_shrink_the_replayed_history() {
+    let mut settings = test_settings();
+    settings.history_max_messages = 8;
+    settings.history_min_messages = 2;
+    settings.target_latency_ms = 1000;
+    let provider = Arc::new(RecordingProvider::default());
+    let app = spawn_app_with_provider(settings, provider.clone()).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    for i in 0..5 {
+        add_message(&app, id, "user", &format!("question {}", i)).await;
+        add_message(&app, id, "assistant", &format!("answer {}", i)).await;
+    }
+
+    stream_prompt(&app, &session.access_token, id, "fast?").await;
+    // Replies have lately been taking four times the target
+    for _ in 0..10 {
+        app.state.record_reply_latency(4000);
+    }
+    stream_prompt(&app, &session.access_token, id, "slow?").await;
+
+    // The full window of eight plus the prompt, then a quarter of it
+    assert_eq!(*provider.turns.lock().unwrap(), [9, 3]);
+}
+
+#[tokio::test]
+async fn repeated_prompt_is_answered_from_the_cache_when_enabled() {
+    let mut settings = test_settings();
+    settings.response_cache_enabled = true;
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let first = app.create_conversation(&session).await;
+    let second = app.create_conversation(&session).await;
+
+    let reply = stream_prompt(&app, &session.access_token, first, "What is Rust?").await;
+    assert_eq!(reply["cached"], false, "{}", reply);
+
+    let reply = stream_prompt(&app, &session.access_token, second, "What is  Rust? ").await;
+    assert_eq!(reply["content"], "Canned reply");
+    assert_eq!(reply["cached"], true, "{}", reply);
+
+    // Only the first reply cost tokens
+    let charged: i64 = sqlx::query_scalar("SELECT SUM(tokens) FROM token_usage WHERE user_id = ?")
+        .bind(session.user_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(charged, 10);
+}
+
+#[tokio::test]
+async fn replies_are_not_cached_by_default() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let first = app.create_conversation(&session).await;
+    let second = app.create_conversation(&session).await;
+
+    stream_prompt(&app, &session.access_token, first, "What is Rust?").await;
+    let reply = stream_prompt(&app, &session.access_token, second, "What is Rust?").await;
+    assert_eq!(reply["cached"], false, "{}", reply);
+}
+
+async fn create_with_key(app: &TestApp, token: &str, key: &str) -> (StatusCode, Option<String>, Value) {
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri("/conversations")
+        .header(header::AUTHORIZATION, format!("Bearer {}", token))
+        .header("Idempotency-Key", key)
+        .body(Body::empty())
+        .unwrap();
+    let response = app.response(request).await;
+    let status = response.status();
+    let replayed = response
+        .headers()
+        .get("idempotent-replayed")
+        .map(|value| value.to_str().unwrap().to_string());
+    let bytes = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+    (status, replayed, serde_json::from_slice(&bytes).unwrap())
+}
+
+#[tokio::test]
+async fn retried_create_with_the_same_key_returns_the_first_conversation() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+
+    let (status, replayed, first) = create_with_key(&app, &alice.access_token, "retry-1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(replayed, None);
+
+    let (status, replayed, second) = create_with_key(&app, &alice.access_token, "retry-1").await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(replayed.as_deref(), Some("true"));
+    assert_eq!(second["id"], first["id"]);
+
+    // Keys are per user
+    let (_, replayed, bobs) = create_with_key(&app, &bob.access_token, "retry-1").await;
+    assert_eq!(replayed, None);
+    assert_ne!(bobs["id"], first["id"]);
+
+    let (status, _, body) = create_with_key(&app, &alice.access_token, "has space").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+
+    let count: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM conversations")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(count, 2);
+}
+
+#[tokio::test]
+async fn a_key_reused_with_another_body_is_refused_and_expired_keys_are_purged() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    let create = |system_prompt: &str| {
+        Request::builder()
+            .method(Method::POST)
+            .uri("/conversations")
+            .header(header::AUTHORIZATION, format!("Bearer {}", session.access_token))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("Idempotency-Key", "retry-1")
+            .body(Body::from(json!({ "system_prompt": system_prompt }).to_string()))
+            .unwrap()
+    };
+
+    let response = app.response(create("Be brief.")).await;
+    assert_eq!(response.status(), StatusCode::OK);
+    let response = app.response(create("Be verbose.")).await;
+    assert_eq!(response.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // A stale key of someone else's goes with the next claim
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    create_with_key(&app, &bob.access_token, "old").await;
+    sqlx::query("UPDATE idempotency_keys SET created_at = 0 WHERE key = 'old'")
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+    create_with_key(&app, &session.access_token, "retry-2").await;
+
+    let keys: Vec<String> = sqlx::query_scalar("SELECT key FROM idempotency_keys ORDER BY key")
+        .fetch_all(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(keys, ["retry-1", "retry-2"]);
+}
+
+#[tokio::test]
+async fn retried_streamed_message_is_replayed_not_resent() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    let send = |key: &'static str, uri: String| {
+        Request::builder()
+            .method(Method::POST)
+            .uri(uri)
+            .header(header::AUTHORIZATION, format!("Bearer {}", session.access_token))
+            .header(header::CONTENT_TYPE, "application/json")
+            .header("Idempotency-Key", key)
+            .body(Body::from(json!({ "msg": "hi" }).to_string()))
+            .unwrap()
+    };
+    let uri = format!("/conversations/{}/messages/stream", id);
+
+    let mut bodies = Vec::new();
+    for _ in 0..2 {
+        let response = app.response(send("msg-1", uri.clone())).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        let events = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        bodies.push(String::from_utf8(events.to_vec()).unwrap());
+    }
+    assert!(bodies[1].contains("event: done"), "{}", bodies[1]);
+    assert!(bodies[1].contains("Canned reply"), "{}", bodies[1]);
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 2);
+
+    // The same key can't be spent on a different endpoint
+    let (status, _, body) = create_with_key(&app, &session.access_token, "msg-1").await;
+    assert_eq!(status, StatusCode::UNPROCESSABLE_ENTITY, "{}", body);
+    assert_eq!(body["error"], "Idempotency key reused");
+}
+
+async fn listed_ids(app: &TestApp, token: &str) -> Vec<i64> {
+    let (status, body) = app.request(Method::GET, "/conversations", Some(token), None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    body["items"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|item| item["id"].as_i64().unwrap())
+        .collect()
+}
+
+#[tokio::test]
+async fn archived_conversations_are_listed_only_when_asked_for() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let kept = app.create_conversation(&session).await;
+    let archived = app.create_conversation(&session).await;
+
+    let (status, body) = app
+        .request(Method::PATCH, &format!("/conversations/{}/archive", archived), Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert!(body["archived_at"].is_i64(), "{}", body);
+
+    assert_eq!(listed_ids(&app, &session.access_token).await, [kept]);
+
+    let (status, body) = app
+        .request(Method::GET, "/conversations?include_archived=true", Some(&session.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    let mut ids: Vec<i64> = body["items"].as_array().unwrap().iter().map(|item| item["id"].as_i64().unwrap()).collect();
+    ids.sort_unstable();
+    assert_eq!(ids, [kept, archived]);
+    assert_eq!(body["total"], 2);
+}
+
+#[tokio::test]
+async fn pinned_and_placed_conversations_are_listed_first() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let token = session.access_token.as_str();
+    let first = app.create_conversation(&session).await;
+    let second = app.create_conversation(&session).await;
+    let third = app.create_conversation(&session).await;
+    assert_eq!(listed_ids(&app, token).await, vec![third, second, first]);
+
+    let (status, body) = app
+        .request(Method::PATCH, &format!("/conversations/{}/pin", first), Some(token), None)
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["pinned"], true);
+    assert_eq!(listed_ids(&app, token).await, vec![first, third, second]);
+
+    // An explicit value doesn't toggle, so retrying it is harmless
+    let (_, body) = app
+        .request(
+            Method::PATCH,
+            &format!("/conversations/{}/pin", first),
+            Some(token),
+            Some(json!({ "pinned": true })),
+        )
+        .await;
+    assert_eq!(body["pinned"], true);
+
+    let (status, body) = app
+        .request(
+            Method::PATCH,
+            &format!("/conversations/{}/position", second),
+            Some(token),
+            Some(json!({ "sort_order": 1 })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["sort_order"], 1);
+    assert_eq!(listed_ids(&app, token).await, vec![first, second, third]);
+
+    app.request(Method::PATCH, &format!("/conversations/{}/pin", first), Some(token), None)
+        .await;
+    assert_eq!(listed_ids(&app, token).await, vec![second, third, first]);
+
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let (status, _) = app
+        .request(Method::PATCH, &format!("/conversations/{}/pin", first), Some(&bob.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn overlong_prompt_is_rejected_before_it_is_stored() {
+    let mut settings = test_settings();
+    settings.max_message_chars = 10;
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    // Counted in characters, not bytes
+    let (status, body) = app
+        .request(
+            Method::POST,
+            &format!("/conversations/{}/messages/stream", id),
+            Some(&session.access_token),
+            Some(json!({ "msg": "ééééééééééé" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["error"], "Message too long");
+    assert_eq!(body["details"][0]["messages"][0], "Messages are limited to 10 characters.");
+
+    let (status, body) = app
+        .request(Method::GET, "/text", Some(&session.access_token), Some(json!({ "msg": "x".repeat(11) })))
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn moderated_prompt_is_rejected_and_not_stored() {
+    let mut settings = test_settings();
+    settings.moderation_enabled = true;
+    settings.moderation_patterns = vec![r"\bforbidden\b".to_string()];
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            &format!("/conversations/{}/messages/stream", id),
+            Some(&session.access_token),
+            Some(json!({ "msg": "tell me something Forbidden" })),
+        )
+        .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["error"], "Message rejected");
+    assert_eq!(body["details"][0]["field"], "msg");
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+}
@@ -0,0 +1,156 @@
+mod common;
+
+use rback::{database::connection::insert_chat_message_to_db, models::ai::ReplyMeta};
+
+use common::spawn_app;
+
+async fn count(app: &common::TestApp, table: &str, user_id: i64) -> i64 {
+    let query = match table {
+        "messages" => "SELECT COUNT(*) FROM messages m JOIN conversations c ON c.id = m.conversation_id WHERE c.user_id = ?1".to_string(),
+        _ => format!("SELECT COUNT(*) FROM {} WHERE user_id = ?1", table),
+    };
+
+    sqlx::query_scalar(&query)
+        .bind(user_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap()
+}
+
+#[tokio::test]
+async fn deleting_a_user_cascades_to_their_rows() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&session).await;
+    insert_chat_message_to_db("user", conversation_id, "hello", 1, &ReplyMeta::default(), &app.state.db)
+        .await
+        .unwrap();
+
+    for table in ["conversations", "tokens", "messages"] {
+        assert_eq!(count(&app, table, session.user_id).await, 1, "{}", table);
+    }
+
+    sqlx::query("DELETE FROM users WHERE id = ?1")
+        .bind(session.user_id)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+
+    for table in ["conversations", "tokens"] {
+        assert_eq!(count(&app, table, session.user_id).await, 0, "{}", table);
+    }
+
+    let orphaned: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE conversation_id = ?1")
+        .bind(conversation_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(orphaned, 0);
+}
+
+#[tokio::test]
+async fn foreign_keys_are_enforced_on_pooled_connections() {
+    let app = spawn_app().await;
+
+    let enabled: i64 = sqlx::query_scalar("PRAGMA foreign_keys")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(enabled, 1);
+
+    let orphan = insert_chat_message_to_db("user", 999, "nowhere", 1, &ReplyMeta::default(), &app.state.db).await;
+    assert!(orphan.is_err());
+}
+
+#[tokio::test]
+async fn email_nocase_migration_names_case_variant_duplicates() {
+    use rback::database::migrations::{MIGRATIONS, run_migrations};
+
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+
+    // A database from before email_nocase, where case variants could still be registered
+    sqlx::query("CREATE TABLE _migrations (version INTEGER PRIMARY KEY, name TEXT NOT NULL, applied_at INTEGER NOT NULL)")
+        .execute(&pool)
+        .await
+        .unwrap();
+    for migration in MIGRATIONS.iter().take_while(|m| m.name != "email_nocase") {
+        for statement in migration.statements {
+            sqlx::query(statement).execute(&pool).await.unwrap();
+        }
+        sqlx::query("INSERT INTO _migrations (version, name, applied_at) VALUES (?1, ?2, 0)")
+            .bind(migration.version)
+            .bind(migration.name)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+    for email in ["Alice@example.com", "alice@example.com", "bob@example.com"] {
+        sqlx::query("INSERT INTO users (email, name, password) VALUES (?1, 'x', 'x')")
+            .bind(email)
+            .execute(&pool)
+            .await
+            .unwrap();
+    }
+
+    let error = run_migrations(&pool).await.unwrap_err().to_string();
+    assert!(error.contains("Alice@example.com (id 1)"), "{}", error);
+    assert!(error.contains("alice@example.com (id 2)"), "{}", error);
+    assert!(!error.contains("bob@example.com"), "{}", error);
+
+    // Nothing from the failed step was applied
+    let emails: Vec<String> = sqlx::query_scalar("SELECT email FROM users ORDER BY id")
+        .fetch_all(&pool)
+        .await
+        .unwrap();
+    assert_eq!(emails[0], "Alice@example.com");
+}
+
+#[tokio::test]
+async fn registering_a_case_variant_of_an_email_is_rejected() {
+    let app = spawn_app().await;
+    app.register("alice", "alice@example.com").await;
+
+    let (status, body) = app
+        .request(
+            axum::http::Method::POST,
+            "/register",
+            None,
+            Some(serde_json::json!({
+                "name": "alice2",
+                "email": "ALICE@example.com",
+                "password": common::PASSWORD,
+            })),
+        )
+        .await;
+    assert!(status.is_client_error(), "{}", status);
+    assert!(body.to_string().contains("already exists"), "{}", body);
+
+    let users: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM users")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(users, 1);
+}
+
+#[tokio::test]
+async fn inserted_message_comes_back_with_its_id() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&session).await;
+
+    let first = insert_chat_message_to_db("user", conversation_id, "one", 1, &ReplyMeta::default(), &app.state.db)
+        .await
+        .unwrap();
+    let second = insert_chat_message_to_db("user", conversation_id, "two", 1, &ReplyMeta::default(), &app.state.db)
+        .await
+        .unwrap();
+
+    assert_eq!(first.content, "one");
+    assert_eq!(first.conversation_id, conversation_id);
+    assert!(first.timestamp > 0);
+    assert!(second.id > first.id);
+}
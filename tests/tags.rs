@@ -0,0 +1,109 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use serde_json::{Value, json};
+
+use common::{Session, TestApp, spawn_app};
+
+async fn create_tag(app: &TestApp, session: &Session, name: &str) -> (StatusCode, Value) {
+    app.request(Method::POST, "/tags", Some(&session.access_token), Some(json!({ "name": name })))
+        .await
+}
+
+#[tokio::test]
+async fn tag_names_are_unique_per_user_ignoring_case() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+
+    let (status, body) = create_tag(&app, &alice, "  Work ").await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["name"], "Work");
+
+    let (status, body) = create_tag(&app, &alice, "work").await;
+    assert_eq!(status, StatusCode::CONFLICT, "{}", body);
+    assert_eq!(body["error"], "Tag already exists");
+
+    let (status, _) = create_tag(&app, &bob, "work").await;
+    assert_eq!(status, StatusCode::OK);
+
+    let (status, _) = create_tag(&app, &alice, "   ").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+
+    create_tag(&app, &alice, "Archive").await;
+    let (status, body) = app.request(Method::GET, "/tags", Some(&alice.access_token), None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body.as_array().unwrap().len(), 2);
+    assert_eq!(body[0]["name"], "Archive");
+}
+
+#[tokio::test]
+async fn tagged_conversations_can_be_filtered() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let token = Some(alice.access_token.as_str());
+    let tagged = app.create_conversation(&alice).await;
+    app.create_conversation(&alice).await;
+    let (_, tag) = create_tag(&app, &alice, "Work").await;
+    let tag_id = tag["id"].as_i64().unwrap();
+    let uri = format!("/conversations/{}/tags/{}", tagged, tag_id);
+
+    let (status, body) = app.request(Method::PUT, &uri, token, None).await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["tags"][0]["name"], "Work");
+    // Attaching again changes nothing
+    let (status, body) = app.request(Method::PUT, &uri, token, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["tags"].as_array().unwrap().len(), 1);
+
+    let (_, body) = app.request(Method::GET, "/conversations?tag=work", token, None).await;
+    assert_eq!(body["total"], 1);
+    assert_eq!(body["items"][0]["id"], tagged);
+    assert_eq!(body["items"][0]["tags"][0]["id"], tag_id);
+
+    let (_, body) = app.request(Method::GET, &format!("/conversations/{}", tagged), token, None).await;
+    assert_eq!(body["tags"][0]["name"], "Work");
+
+    let (status, body) = app.request(Method::DELETE, &uri, token, None).await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["tags"], json!([]));
+    let (_, body) = app.request(Method::GET, "/conversations?tag=Work", token, None).await;
+    assert_eq!(body["total"], 0);
+}
+
+#[tokio::test]
+async fn tags_and_conversations_of_others_cannot_be_combined() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let alices = app.create_conversation(&alice).await;
+    let bobs = app.create_conversation(&bob).await;
+    let (_, alices_tag) = create_tag(&app, &alice, "Work").await;
+    let (_, bobs_tag) = create_tag(&app, &bob, "Work").await;
+
+    let (status, body) = app
+        .request(
+            Method::PUT,
+            &format!("/conversations/{}/tags/{}", alices, bobs_tag["id"]),
+            Some(&alice.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    assert_eq!(body["error"], "Tag not found");
+
+    let (status, _) = app
+        .request(
+            Method::PUT,
+            &format!("/conversations/{}/tags/{}", bobs, alices_tag["id"]),
+            Some(&alice.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+
+    let (status, _) = app
+        .request(Method::DELETE, &format!("/tags/{}", bobs_tag["id"]), Some(&alice.access_token), None)
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}
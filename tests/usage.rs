@@ -0,0 +1,123 @@
+mod common;
+
+use axum::http::{Method, StatusCode};
+use chrono::Utc;
+use serde_json::json;
+
+use common::{TestApp, spawn_app, spawn_app_with, test_settings};
+
+async fn charge(app: &TestApp, user_id: i64, tokens: i64, recorded_at: i64) {
+    sqlx::query("INSERT INTO token_usage (user_id, tokens, recorded_at) VALUES (?1, ?2, ?3)")
+        .bind(user_id)
+        .bind(tokens)
+        .bind(recorded_at)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+}
+
+#[tokio::test]
+async fn usage_sums_own_messages_per_day() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let mine = app.create_conversation(&alice).await;
+    let theirs = app.create_conversation(&bob).await;
+
+    // 2024-01-01 and 2024-01-02, UTC
+    for (conversation_id, timestamp, tokens) in [
+        (mine, 1_704_067_200, 10),
+        (mine, 1_704_070_000, 5),
+        (mine, 1_704_153_600, 7),
+        (theirs, 1_704_067_200, 100),
+    ] {
+        sqlx::query(
+            "INSERT INTO messages (conversation_id, role, content, timestamp, token_count) VALUES (?1, 'user', 'hi', ?2, ?3)",
+        )
+        .bind(conversation_id)
+        .bind(timestamp)
+        .bind(tokens)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+    }
+
+    let (status, body) = app
+        .request(
+            Method::GET,
+            "/usage?from=1704067200&to=1704239999",
+            Some(&alice.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::OK, "{}", body);
+    assert_eq!(body["total_messages"], 3);
+    assert_eq!(body["total_tokens"], 22);
+    assert_eq!(body["days"][0]["day"], "2024-01-01");
+    assert_eq!(body["days"][0]["tokens"], 15);
+    assert_eq!(body["days"][1]["day"], "2024-01-02");
+    assert_eq!(body["days"][1]["messages"], 1);
+}
+
+#[tokio::test]
+async fn usage_rejects_inverted_or_oversized_windows() {
+    let app = spawn_app().await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+
+    for uri in ["/usage?from=200&to=100", "/usage?from=0&to=100000000", "/usage?from=-5&to=10"] {
+        let (status, body) = app.request(Method::GET, uri, Some(&session.access_token), None).await;
+        assert_eq!(status, StatusCode::BAD_REQUEST, "{}", uri);
+        assert_eq!(body["error"], "Invalid usage window");
+    }
+}
+
+#[tokio::test]
+async fn spent_budget_blocks_model_calls() {
+    let mut settings = test_settings();
+    settings.monthly_token_budget = 100;
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    charge(&app, session.user_id, 100, Utc::now().timestamp()).await;
+
+    let (status, body) = app
+        .request(Method::GET, "/text", Some(&session.access_token), Some(json!({ "msg": "hi" })))
+        .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{}", body);
+    assert_eq!(body["error"], "Token budget exhausted");
+
+    let (status, body) = app
+        .request(
+            Method::POST,
+            &format!("/conversations/{}/regenerate", id),
+            Some(&session.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS, "{}", body);
+}
+
+#[tokio::test]
+async fn budget_resets_with_the_month_and_can_be_set_per_user() {
+    let mut settings = test_settings();
+    settings.monthly_token_budget = 100;
+    let app = spawn_app_with(settings).await;
+    let session = app.signed_in("alice", "alice@example.com").await;
+    let id = app.create_conversation(&session).await;
+    let uri = format!("/conversations/{}/regenerate", id);
+
+    // Spent long before this month, so it no longer counts
+    charge(&app, session.user_id, 500, 1_704_067_200).await;
+    let (status, body) = app.request(Method::POST, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST, "{}", body);
+    assert_eq!(body["error"], "Nothing to regenerate");
+
+    sqlx::query("UPDATE users SET monthly_token_budget = 5 WHERE id = ?1")
+        .bind(session.user_id)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+    charge(&app, session.user_id, 5, Utc::now().timestamp()).await;
+    let (status, _) = app.request(Method::POST, &uri, Some(&session.access_token), None).await;
+    assert_eq!(status, StatusCode::TOO_MANY_REQUESTS);
+}
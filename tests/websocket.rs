@@ -0,0 +1,546 @@
+mod common;
+
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use axum::http::StatusCode;
+use futures::StreamExt;
+use rback::models::app::GenerationEnd;
+use tokio::net::TcpStream;
+use tokio_tungstenite::{
+    MaybeTlsStream, WebSocketStream, connect_async,
+    tungstenite::{Error, Message},
+};
+
+use common::{SlowProvider, spawn_app, spawn_app_with, spawn_app_with_provider, test_settings};
+
+type Socket = WebSocketStream<MaybeTlsStream<TcpStream>>;
+
+async fn connect(addr: SocketAddr, conversation_id: i64, token: Option<&str>) -> Result<Socket, Error> {
+    let mut url = format!("ws://{}/conversations_ws?conversation_id={}", addr, conversation_id);
+    if let Some(token) = token {
+        url.push_str(&format!("&token={}", token));
+    }
+
+    connect_async(url).await.map(|(socket, _)| socket)
+}
+
+fn rejected_with(result: Result<Socket, Error>) -> StatusCode {
+    match result {
+        Err(Error::Http(response)) => StatusCode::from_u16(response.status().as_u16()).unwrap(),
+        Err(e) => panic!("unexpected handshake error: {}", e),
+        Ok(_) => panic!("upgrade was accepted"),
+    }
+}
+
+#[tokio::test]
+async fn upgrade_requires_a_token() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    assert_eq!(rejected_with(connect(addr, conversation_id, None).await), StatusCode::UNAUTHORIZED);
+    assert_eq!(
+        rejected_with(connect(addr, conversation_id, Some("not-a-jwt")).await),
+        StatusCode::UNAUTHORIZED
+    );
+}
+
+#[tokio::test]
+async fn upgrade_into_someone_elses_conversation_is_rejected() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let bob = app.signed_in("bob", "bob@example.com").await;
+    let bobs_conversation = app.create_conversation(&bob).await;
+    let addr = app.spawn_server().await;
+
+    let result = connect(addr, bobs_conversation, Some(&alice.access_token)).await;
+    assert_eq!(rejected_with(result), StatusCode::NOT_FOUND);
+
+    let socket = connect(addr, bobs_conversation, Some(&bob.access_token)).await;
+    assert!(socket.is_ok());
+}
+
+
+async fn next_text(socket: &mut Socket) -> String {
+    match socket.next().await {
+        Some(Ok(Message::Text(text))) => text.to_string(),
+        other => panic!("expected a text frame, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn socket_joining_mid_generation_gets_partial_and_remaining_chunks() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let generation = app.state.start_generation(conversation_id).unwrap();
+    generation.push("Hello, ");
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    assert_eq!(next_text(&mut socket).await, "Hello, ");
+
+    generation.push("world");
+    app.state.finish_generation(conversation_id, GenerationEnd::Done);
+
+    assert_eq!(next_text(&mut socket).await, "world");
+    assert_eq!(next_text(&mut socket).await, "{\"done\":true}");
+}
+
+#[tokio::test]
+async fn joined_socket_receives_the_failure_frame() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let generation = app.state.start_generation(conversation_id).unwrap();
+    generation.push("partial");
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    assert_eq!(next_text(&mut socket).await, "partial");
+
+    let frame = "{\"error\":{\"code\":504,\"message\":\"Gemini stopped responding mid-reply\"}}";
+    app.state
+        .finish_generation(conversation_id, GenerationEnd::Failed(frame.to_string()));
+
+    assert_eq!(next_text(&mut socket).await, frame);
+}
+
+#[tokio::test]
+async fn upgrade_with_an_unknown_model_is_rejected() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let url = format!(
+        "ws://{}/conversations_ws?conversation_id={}&token={}&model=gemini-ultra",
+        addr, conversation_id, alice.access_token
+    );
+    let result = connect_async(url).await.map(|(socket, _)| socket);
+    assert_eq!(rejected_with(result), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn upgrade_with_out_of_range_generation_params_is_rejected() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let url = format!(
+        "ws://{}/conversations_ws?conversation_id={}&token={}&top_p=1.5",
+        addr, conversation_id, alice.access_token
+    );
+    let result = connect_async(url).await.map(|(socket, _)| socket);
+    assert_eq!(rejected_with(result), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn joined_socket_is_told_the_reply_was_stopped() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let generation = app.state.start_generation(conversation_id).unwrap();
+    generation.push("partial");
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    assert_eq!(next_text(&mut socket).await, "partial");
+
+    app.state.finish_generation(conversation_id, GenerationEnd::Stopped);
+    assert_eq!(next_text(&mut socket).await, "{\"stopped\":true}");
+}
+
+#[tokio::test]
+async fn stop_without_a_reply_in_progress_is_not_a_prompt() {
+    use futures::SinkExt;
+
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    socket.send(Message::text("{\"type\":\"stop\"}")).await.unwrap();
+
+    // Frames are handled in order, so the busy answer to this prompt means the stop was read
+    let _generation = app.state.start_generation(conversation_id).unwrap();
+    socket.send(Message::text("hello")).await.unwrap();
+    assert!(next_text(&mut socket).await.contains("Reply in progress"));
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE conversation_id = ?1")
+        .bind(conversation_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn message_into_a_conversation_deleted_mid_session_closes_the_socket() {
+    use futures::SinkExt;
+
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    sqlx::query("DELETE FROM conversations WHERE id = ?1")
+        .bind(conversation_id)
+        .execute(&app.state.db)
+        .await
+        .unwrap();
+
+    socket.send(Message::text("hello")).await.unwrap();
+    assert!(next_text(&mut socket).await.contains("Conversation not found"));
+    assert!(matches!(socket.next().await, Some(Ok(Message::Close(_)))));
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn stored_prompt_is_echoed_with_its_id() {
+    use futures::SinkExt;
+
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    socket.send(Message::text("hello")).await.unwrap();
+
+    let frame: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+    let id: i64 = sqlx::query_scalar("SELECT id FROM messages WHERE conversation_id = ?1")
+        .bind(conversation_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(frame["message"]["id"], id);
+    assert_eq!(frame["message"]["role"], "user");
+    assert_eq!(frame["message"]["content"], "hello");
+}
+
+#[tokio::test]
+async fn nothing_follows_the_final_reply_once_the_turn_is_done() {
+    use futures::SinkExt;
+
+    // Slow enough that the old one-second typing tick would have fired during the turn
+    let provider = SlowProvider { reply: "Slow canned reply", delay: Duration::from_millis(400) };
+    let app = spawn_app_with_provider(test_settings(), Arc::new(provider)).await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    socket.send(Message::text("hello")).await.unwrap();
+
+    let mut frames = Vec::new();
+    loop {
+        let frame = next_text(&mut socket).await;
+        assert_ne!(frame, "typing");
+        if frame == "{\"done\":true}" {
+            break;
+        }
+        frames.push(frame);
+    }
+    let last: serde_json::Value = serde_json::from_str(frames.last().unwrap()).unwrap();
+    assert_eq!(last["message"]["content"], "Slow canned reply");
+
+    // Read until the socket goes idle for longer than a typing tick
+    let mut after = Vec::new();
+    while let Ok(Some(Ok(frame))) = tokio::time::timeout(Duration::from_millis(1500), socket.next()).await {
+        after.push(frame);
+    }
+    assert!(
+        after.iter().all(|frame| !matches!(frame, Message::Text(text) if text.as_str() == "typing")),
+        "{:?}",
+        after
+    );
+}
+
+#[tokio::test]
+async fn prompt_that_cannot_be_stored_gets_no_reply() {
+    use futures::SinkExt;
+
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+    sqlx::query(
+        "CREATE TRIGGER refuse_prompts BEFORE INSERT ON messages WHEN NEW.role = 'user'
+BEGIN SELECT RAISE(ABORT, 'disk full'); END",
+    )
+    .execute(&app.state.db)
+    .await
+    .unwrap();
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    socket.send(Message::text("hello")).await.unwrap();
+
+    let frame: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+    assert_eq!(frame["details"][0]["field"], "database");
+    assert!(
+        tokio::time::timeout(Duration::from_millis(500), socket.next()).await.is_err(),
+        "the turn went on after the prompt failed"
+    );
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+    // The slot was given back, so the conversation isn't stuck as busy
+    assert!(app.state.start_generation(conversation_id).is_some());
+}
+
+async fn spawn_with_heartbeat() -> (common::TestApp, i64, String, SocketAddr) {
+    let mut settings = test_settings();
+    settings.ws_ping_interval_secs = 1;
+    settings.ws_pong_timeout_secs = 1;
+    let app = spawn_app_with(settings).await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+    (app, conversation_id, alice.access_token, addr)
+}
+
+#[tokio::test]
+async fn socket_that_answers_pings_stays_open() {
+    let (_app, conversation_id, token, addr) = spawn_with_heartbeat().await;
+    let mut socket = connect(addr, conversation_id, Some(&token)).await.unwrap();
+
+    // Reading is what lets the client send its pongs
+    let mut pings = 0;
+    let listening = tokio::time::sleep(std::time::Duration::from_millis(3500));
+    tokio::pin!(listening);
+    loop {
+        tokio::select! {
+            frame = socket.next() => match frame {
+                Some(Ok(Message::Ping(_))) => pings += 1,
+                other => panic!("expected only pings, got {:?}", other),
+            },
+            _ = &mut listening => break,
+        }
+    }
+    assert!(pings >= 2);
+}
+
+#[tokio::test]
+async fn socket_that_stops_answering_pings_is_closed() {
+    let (_app, conversation_id, token, addr) = spawn_with_heartbeat().await;
+    let mut socket = connect(addr, conversation_id, Some(&token)).await.unwrap();
+
+    // Not reading means the ping goes unanswered
+    tokio::time::sleep(std::time::Duration::from_millis(2500)).await;
+
+    assert!(matches!(socket.next().await, Some(Ok(Message::Ping(_)))));
+    match socket.next().await {
+        Some(Ok(Message::Close(Some(frame)))) => assert_eq!(frame.reason, "ping timeout"),
+        other => panic!("expected a close frame, got {:?}", other),
+    }
+}
+
+#[tokio::test]
+async fn close_frame_ends_the_session() {
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    socket.close(None).await.unwrap();
+
+    // The server answers the close instead of storing it as a prompt
+    assert!(matches!(socket.next().await, Some(Ok(Message::Close(_)))));
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn binary_frame_gets_an_error_and_the_socket_stays_usable() {
+    use futures::SinkExt;
+
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    socket.send(Message::binary(vec![0xff, 0xfe, 0x00])).await.unwrap();
+    assert!(next_text(&mut socket).await.contains("Unsupported frame"));
+
+    socket.send(Message::text("hello")).await.unwrap();
+    let frame: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+    assert_eq!(frame["message"]["content"], "hello");
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE role = 'user'")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 1);
+}
+
+#[tokio::test]
+async fn oversized_chat_message_gets_an_error_frame() {
+    use futures::SinkExt;
+
+    let mut settings = test_settings();
+    settings.ws_max_message_bytes = 16;
+    let app = spawn_app_with(settings).await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    socket.send(Message::text("x".repeat(17))).await.unwrap();
+    assert!(next_text(&mut socket).await.contains("Message too large"));
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 0);
+}
+
+#[tokio::test]
+async fn keyed_prompt_sent_twice_is_answered_once() {
+    use futures::SinkExt;
+
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    let prompt = r#"{"msg": "hello", "idempotency_key": "k-1"}"#;
+
+    let mut replies = Vec::new();
+    for _ in 0..2 {
+        socket.send(Message::text(prompt)).await.unwrap();
+        let mut frames = Vec::new();
+        loop {
+            let frame = next_text(&mut socket).await;
+            if frame == "{\"done\":true}" {
+                break;
+            }
+            frames.push(frame);
+        }
+
+        let first: serde_json::Value = serde_json::from_str(&frames[0]).unwrap();
+        assert_eq!(first["message"]["content"], "hello");
+        let last: serde_json::Value = serde_json::from_str(frames.last().unwrap()).unwrap();
+        assert_eq!(last["message"]["content"], "Canned reply");
+        replies.push((first["message"]["id"].clone(), last["message"]["id"].clone()));
+    }
+    assert_eq!(replies[0], replies[1]);
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE conversation_id = ?1")
+        .bind(conversation_id)
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 2);
+}
+
+#[tokio::test]
+async fn moderated_prompt_gets_an_error_frame_and_is_not_stored() {
+    use futures::SinkExt;
+
+    let mut settings = test_settings();
+    settings.moderation_enabled = true;
+    settings.moderation_patterns = vec!["forbidden".to_string()];
+    let app = spawn_app_with(settings).await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    socket.send(Message::text("something forbidden")).await.unwrap();
+    assert!(next_text(&mut socket).await.contains("Message rejected"));
+
+    socket.send(Message::text("hello")).await.unwrap();
+    let frame: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+    assert_eq!(frame["message"]["content"], "hello");
+
+    let stored: i64 = sqlx::query_scalar("SELECT COUNT(*) FROM messages WHERE role = 'user'")
+        .fetch_one(&app.state.db)
+        .await
+        .unwrap();
+    assert_eq!(stored, 1);
+}
+
+#[tokio::test]
+async fn overlong_prompt_gets_an_error_frame_and_the_socket_stays_open() {
+    use futures::SinkExt;
+
+    let mut settings = test_settings();
+    settings.max_message_chars = 10;
+    let app = spawn_app_with(settings).await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    socket.send(Message::text("far too long a prompt")).await.unwrap();
+    assert!(next_text(&mut socket).await.contains("Message too long"));
+
+    socket.send(Message::text("hello")).await.unwrap();
+    let frame: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+    assert_eq!(frame["message"]["content"], "hello");
+}
+
+#[tokio::test]
+async fn reply_to_a_dropped_socket_is_stored_and_listed_after_the_prompt() {
+    use axum::http::Method;
+    use futures::SinkExt;
+
+    let app = spawn_app().await;
+    let alice = app.signed_in("alice", "alice@example.com").await;
+    let conversation_id = app.create_conversation(&alice).await;
+    let addr = app.spawn_server().await;
+
+    let mut socket = connect(addr, conversation_id, Some(&alice.access_token)).await.unwrap();
+    socket.send(Message::text("hello")).await.unwrap();
+    let frame: serde_json::Value = serde_json::from_str(&next_text(&mut socket).await).unwrap();
+    let prompt_id = frame["message"]["id"].as_i64().unwrap();
+    drop(socket);
+
+    let uri = format!("/conversations/{}/messages?after={}", conversation_id, prompt_id);
+    let mut missed = serde_json::Value::Null;
+    for _ in 0..50 {
+        let (status, body) = app.request(Method::GET, &uri, Some(&alice.access_token), None).await;
+        assert_eq!(status, StatusCode::OK, "{}", body);
+        if body["total"] == 1 {
+            missed = body;
+            break;
+        }
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+    assert_eq!(missed["items"][0]["role"], "assistant");
+    assert_eq!(missed["items"][0]["content"], "Canned reply");
+
+    let (status, _) = app
+        .request(
+            Method::GET,
+            &format!("/conversations/{}/messages?after=999", conversation_id),
+            Some(&alice.access_token),
+            None,
+        )
+        .await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+}